@@ -0,0 +1,381 @@
+//! Off-chain helpers for building transactions against a stake pool.
+//! Only compiled with the `client` feature so none of it lands in the program binary.
+
+use borsh::BorshSerialize;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    stake, system_program, sysvar,
+};
+use crate::{
+    instruction::StakePoolInstruction,
+    state::{
+        MINT_SEED, POOL_SEED, SPLIT_STAKE_SEED, STAKE_ACCOUNT_SEED, STAKE_AUTHORITY_SEED,
+        STAKE_RECORD_SEED, VALIDATOR_LIST_SEED, WITHDRAW_AUTHORITY_SEED,
+    },
+};
+
+/// Every PDA belonging to a stake pool, derived with the same seeds the processor uses.
+///
+/// There is no nonce: the processor seeds the pool with its authority alone, so each
+/// authority has exactly one pool. There is no reserve either: deposits stay in the
+/// users' own stake accounts and `StakePool.reserve` is left unset, so no reserve
+/// address exists to derive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolAddresses {
+    /// Stake pool state account
+    pub stake_pool: Pubkey,
+    pub stake_pool_bump: u8,
+
+    /// Pool token (obeSOL) mint
+    pub mint: Pubkey,
+    pub mint_bump: u8,
+
+    /// Stake authority PDA (staker of user stake accounts, mint authority)
+    pub stake_authority: Pubkey,
+    pub stake_authority_bump: u8,
+
+    /// Withdraw authority PDA
+    pub withdraw_authority: Pubkey,
+    pub withdraw_authority_bump: u8,
+}
+
+impl PoolAddresses {
+    /// Derives all pool PDAs for the pool created by `authority`
+    pub fn derive(authority: &Pubkey, program_id: &Pubkey) -> Self {
+        let (stake_pool, stake_pool_bump) = find_stake_pool_address(authority, program_id);
+        let (mint, mint_bump) = find_mint_address(&stake_pool, program_id);
+        let (stake_authority, stake_authority_bump) = find_stake_authority_address(&stake_pool, program_id);
+        let (withdraw_authority, withdraw_authority_bump) =
+            find_withdraw_authority_address(&stake_pool, program_id);
+
+        PoolAddresses {
+            stake_pool,
+            stake_pool_bump,
+            mint,
+            mint_bump,
+            stake_authority,
+            stake_authority_bump,
+            withdraw_authority,
+            withdraw_authority_bump,
+        }
+    }
+
+    /// Derives the stake account PDA the pool uses for `user`
+    pub fn user_stake_account(&self, user: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+        find_user_stake_account_address(&self.stake_pool, user, program_id)
+    }
+}
+
+/// Derives the stake pool PDA created by `authority`
+pub fn find_stake_pool_address(authority: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[authority.as_ref(), POOL_SEED], program_id)
+}
+
+/// Derives the pool token mint PDA of `stake_pool`
+pub fn find_mint_address(stake_pool: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[stake_pool.as_ref(), MINT_SEED], program_id)
+}
+
+/// Derives the stake authority PDA of `stake_pool`
+pub fn find_stake_authority_address(stake_pool: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[STAKE_AUTHORITY_SEED, stake_pool.as_ref()], program_id)
+}
+
+/// Derives the withdraw authority PDA of `stake_pool`
+pub fn find_withdraw_authority_address(stake_pool: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[WITHDRAW_AUTHORITY_SEED, stake_pool.as_ref()], program_id)
+}
+
+/// Derives `user`'s stake account PDA in `stake_pool`
+pub fn find_user_stake_account_address(stake_pool: &Pubkey, user: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    let (stake_authority, _) = find_stake_authority_address(stake_pool, program_id);
+    Pubkey::find_program_address(
+        &[STAKE_ACCOUNT_SEED, stake_pool.as_ref(), user.as_ref(), stake_authority.as_ref()],
+        program_id,
+    )
+}
+
+/// Derives the split stake PDA a partial unstake by `user` in `epoch` creates
+pub fn find_split_stake_address(stake_pool: &Pubkey, user: &Pubkey, epoch: u64, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[SPLIT_STAKE_SEED, stake_pool.as_ref(), user.as_ref(), &epoch.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// Derives the stake record PDA of a pool stake account
+pub fn find_stake_record_address(stake_account: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[STAKE_RECORD_SEED, stake_account.as_ref()], program_id)
+}
+
+/// Derives the validator list PDA of `stake_pool`
+pub fn find_validator_list_address(stake_pool: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VALIDATOR_LIST_SEED, stake_pool.as_ref()], program_id)
+}
+
+fn build(program_id: &Pubkey, instruction: &StakePoolInstruction, accounts: Vec<AccountMeta>) -> Instruction {
+    // Writing into a Vec cannot fail
+    let data = instruction.try_to_vec().unwrap();
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Builds `Initialize` for the pool owned by `authority`, who signs and pays
+pub fn initialize(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    manager_fee_account: &Pubkey,
+    treasury_fee_account: &Pubkey,
+    name: String,
+    fee_percentage: u8,
+    helius_validator_vote: &Pubkey,
+) -> Instruction {
+    let pool = PoolAddresses::derive(authority, program_id);
+    build(
+        program_id,
+        &StakePoolInstruction::Initialize { name, fee_percentage, helius_validator_vote: *helius_validator_vote },
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(pool.stake_pool, false),
+            AccountMeta::new(pool.mint, false),
+            AccountMeta::new(*manager_fee_account, false),
+            AccountMeta::new(*treasury_fee_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(*helius_validator_vote, false),
+        ],
+    )
+}
+
+/// Builds `Stake` of `amount` lamports, minting to `user`'s associated token account, with
+/// the stake account's record appended. `helius_validator_vote` must be the pool's
+/// validator. For a pool with a validator list, pass any listed validator instead and
+/// append the list as a writable account. A separate rent payer is appended as a writable
+/// signer; the appended accounts may come in any order.
+pub fn stake(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    stake_pool: &Pubkey,
+    helius_validator_vote: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (mint, _) = find_mint_address(stake_pool, program_id);
+    let (stake_authority, _) = find_stake_authority_address(stake_pool, program_id);
+    let (user_stake_account, _) = find_user_stake_account_address(stake_pool, user, program_id);
+    #[allow(deprecated)] // delegate_stake still takes the stake config account
+    let stake_config = stake::config::id();
+    build(
+        program_id,
+        &StakePoolInstruction::Stake { amount },
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(*stake_pool, false),
+            AccountMeta::new(spl_associated_token_account::get_associated_token_address(user, &mint), false),
+            AccountMeta::new(mint, false),
+            AccountMeta::new(user_stake_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(stake::program::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+            AccountMeta::new_readonly(stake_config, false),
+            AccountMeta::new_readonly(*helius_validator_vote, false),
+            AccountMeta::new_readonly(stake_authority, false),
+            AccountMeta::new(find_stake_record_address(&user_stake_account, program_id).0, false),
+        ],
+    )
+}
+
+/// Builds `Unstake` of `pool_token_amount`, burning from `user`'s associated token account,
+/// with the stake account's record appended. Burning every token minted against the stake
+/// account deactivates all of it. For a pool with a validator list, append the list as a
+/// writable account. For a partial unstake, also append the split stake PDA of the current
+/// epoch and its stake record (both writable), the system program and the rent sysvar.
+pub fn unstake(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    stake_pool: &Pubkey,
+    pool_token_amount: u64,
+) -> Instruction {
+    let (mint, _) = find_mint_address(stake_pool, program_id);
+    let (stake_authority, _) = find_stake_authority_address(stake_pool, program_id);
+    let (user_stake_account, _) = find_user_stake_account_address(stake_pool, user, program_id);
+    build(
+        program_id,
+        &StakePoolInstruction::Unstake { amount: pool_token_amount },
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(*stake_pool, false),
+            AccountMeta::new(spl_associated_token_account::get_associated_token_address(user, &mint), false),
+            AccountMeta::new(mint, false),
+            AccountMeta::new(user_stake_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(stake::program::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(stake_authority, false),
+            AccountMeta::new(find_stake_record_address(&user_stake_account, program_id).0, false),
+        ],
+    )
+}
+
+/// Builds `WithdrawStake`, paying `user`'s cooled-down stake account out to `user`.
+/// For a split stake account, use `withdraw_split_stake` instead.
+pub fn withdraw_stake(program_id: &Pubkey, user: &Pubkey, stake_pool: &Pubkey) -> Instruction {
+    let (user_stake_account, _) = find_user_stake_account_address(stake_pool, user, program_id);
+    withdraw(program_id, user, stake_pool, &user_stake_account)
+}
+
+/// Builds `WithdrawStake` for the split stake account a partial unstake by `user` in
+/// `epoch` created
+pub fn withdraw_split_stake(program_id: &Pubkey, user: &Pubkey, stake_pool: &Pubkey, epoch: u64) -> Instruction {
+    let (split_stake, _) = find_split_stake_address(stake_pool, user, epoch, program_id);
+    withdraw(program_id, user, stake_pool, &split_stake)
+}
+
+fn withdraw(program_id: &Pubkey, user: &Pubkey, stake_pool: &Pubkey, stake_account: &Pubkey) -> Instruction {
+    let (withdraw_authority, _) = find_withdraw_authority_address(stake_pool, program_id);
+    build(
+        program_id,
+        &StakePoolInstruction::WithdrawStake,
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(*stake_pool, false),
+            AccountMeta::new(*stake_account, false),
+            AccountMeta::new_readonly(withdraw_authority, false),
+            AccountMeta::new_readonly(stake::program::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+            AccountMeta::new(find_stake_record_address(stake_account, program_id).0, false),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::StakeAccounts;
+
+    /// `(key, is_signer, is_writable)` of each account, in order
+    fn layout(instruction: &Instruction) -> Vec<(Pubkey, bool, bool)> {
+        instruction.accounts.iter().map(|meta| (meta.pubkey, meta.is_signer, meta.is_writable)).collect()
+    }
+
+    #[test]
+    fn initialize_account_order() {
+        let program_id = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let manager = Pubkey::new_unique();
+        let treasury = Pubkey::new_unique();
+        let vote = Pubkey::new_unique();
+        let pool = PoolAddresses::derive(&authority, &program_id);
+        let instruction = initialize(&program_id, &authority, &manager, &treasury, "Pool".to_string(), 1, &vote);
+        assert_eq!(
+            layout(&instruction),
+            vec![
+                (authority, true, true),
+                (pool.stake_pool, false, true),
+                (pool.mint, false, true),
+                (manager, false, true),
+                (treasury, false, true),
+                (spl_token::id(), false, false),
+                (system_program::id(), false, false),
+                (sysvar::rent::id(), false, false),
+                (vote, false, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn stake_account_order() {
+        let program_id = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let vote = Pubkey::new_unique();
+        let pool = PoolAddresses::derive(&Pubkey::new_unique(), &program_id);
+        let instruction = stake(&program_id, &user, &pool.stake_pool, &vote, 1);
+        let (user_stake_account, _) = pool.user_stake_account(&user, &program_id);
+        let accounts = layout(&instruction);
+        #[allow(deprecated)]
+        let stake_config = stake::config::id();
+        let expected = [
+            (StakeAccounts::User, (user, true, true)),
+            (StakeAccounts::StakePool, (pool.stake_pool, false, true)),
+            (
+                StakeAccounts::UserTokenAccount,
+                (spl_associated_token_account::get_associated_token_address(&user, &pool.mint), false, true),
+            ),
+            (StakeAccounts::PoolMint, (pool.mint, false, true)),
+            (StakeAccounts::UserStakeAccount, (user_stake_account, false, true)),
+            (StakeAccounts::TokenProgram, (spl_token::id(), false, false)),
+            (StakeAccounts::StakeProgram, (stake::program::id(), false, false)),
+            (StakeAccounts::SystemProgram, (system_program::id(), false, false)),
+            (StakeAccounts::RentSysvar, (sysvar::rent::id(), false, false)),
+            (StakeAccounts::ClockSysvar, (sysvar::clock::id(), false, false)),
+            (StakeAccounts::StakeHistorySysvar, (sysvar::stake_history::id(), false, false)),
+            (StakeAccounts::StakeConfig, (stake_config, false, false)),
+            (StakeAccounts::ValidatorVote, (vote, false, false)),
+            (StakeAccounts::StakeAuthority, (pool.stake_authority, false, false)),
+        ];
+        assert_eq!(expected.len(), StakeAccounts::COUNT);
+        for (position, account) in expected {
+            assert_eq!(accounts[position as usize], account, "{:?}", position);
+        }
+        // The stake record is the only optional account appended
+        assert_eq!(
+            accounts[StakeAccounts::COUNT..],
+            [(find_stake_record_address(&user_stake_account, &program_id).0, false, true)],
+        );
+    }
+
+    #[test]
+    fn unstake_account_order() {
+        let program_id = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let pool = PoolAddresses::derive(&Pubkey::new_unique(), &program_id);
+        let (user_stake_account, _) = pool.user_stake_account(&user, &program_id);
+        let instruction = unstake(&program_id, &user, &pool.stake_pool, 1);
+        assert_eq!(
+            layout(&instruction),
+            vec![
+                (user, true, true),
+                (pool.stake_pool, false, true),
+                (spl_associated_token_account::get_associated_token_address(&user, &pool.mint), false, true),
+                (pool.mint, false, true),
+                (user_stake_account, false, true),
+                (spl_token::id(), false, false),
+                (stake::program::id(), false, false),
+                (sysvar::clock::id(), false, false),
+                (pool.stake_authority, false, false),
+                (find_stake_record_address(&user_stake_account, &program_id).0, false, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn withdraw_account_order() {
+        let program_id = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let pool = PoolAddresses::derive(&Pubkey::new_unique(), &program_id);
+        let (user_stake_account, _) = pool.user_stake_account(&user, &program_id);
+        let (split_stake, _) = find_split_stake_address(&pool.stake_pool, &user, 7, &program_id);
+        for (instruction, stake_account) in [
+            (withdraw_stake(&program_id, &user, &pool.stake_pool), user_stake_account),
+            (withdraw_split_stake(&program_id, &user, &pool.stake_pool, 7), split_stake),
+        ] {
+            assert_eq!(
+                layout(&instruction),
+                vec![
+                    (user, true, true),
+                    (pool.stake_pool, false, true),
+                    (stake_account, false, true),
+                    (pool.withdraw_authority, false, false),
+                    (stake::program::id(), false, false),
+                    (sysvar::clock::id(), false, false),
+                    (sysvar::stake_history::id(), false, false),
+                    (find_stake_record_address(&stake_account, &program_id).0, false, true),
+                ],
+            );
+        }
+    }
+}
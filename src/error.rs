@@ -8,6 +8,8 @@ use thiserror::Error;
 
 #[derive(Error, Debug, Copy, Clone, FromPrimitive)]
 pub enum StakePoolError {
+    /// Not returned: undecodable instruction data fails with the standard
+    /// `ProgramError::InvalidInstructionData`. Kept so later codes are unchanged.
     #[error("Invalid instruction")]
     InvalidInstruction,
     
@@ -38,12 +40,15 @@ pub enum StakePoolError {
     #[error("Insufficient balance")]
     InsufficientBalance,
     
+    /// Not returned: superseded by `CooldownNotPassed`. Kept so later codes are unchanged.
     #[error("Cooldown period not elapsed")]
     CooldownNotElapsed,
     
     #[error("Invalid owner")]
     InvalidOwner,
     
+    /// Not returned: handlers use `ProgramError::UninitializedAccount`. Kept so later
+    /// codes are unchanged.
     #[error("Account not initialized")]
     UninitializedAccount,
 
@@ -56,15 +61,20 @@ pub enum StakePoolError {
     #[error("Invalid account owner")]
     InvalidAccountOwner,
 
+    /// Not returned: superseded by `CooldownNotPassed`. Kept so later codes are unchanged.
     #[error("Unstake cooldown period not met")]
     UnstakeCooldownNotMet,
 
     #[error("Calculation failed")]
     CalculationFailure,
 
+    /// Not returned: a repeat `ClaimRewards` in the same epoch succeeds and reports
+    /// `already_processed` instead. Kept so later codes are unchanged.
     #[error("Already claimed rewards this epoch")]
     AlreadyClaimedThisEpoch,
 
+    /// Not returned: rewards accrue through the pool ratio and are never collected
+    /// explicitly. Kept so later codes are unchanged.
     #[error("No rewards to collect")]
     NoRewardsToCollect,
 
@@ -88,6 +98,75 @@ pub enum StakePoolError {
 
     #[error("Invalid stake authority")]
     InvalidStakeAuthority,
+
+    #[error("Pool mint has a freeze authority")]
+    MintHasFreezeAuthority,
+
+    #[error("Delegatable stake is below the deposited amount")]
+    DelegationAmountMismatch,
+
+    #[error("Unstake output is below the minimum; unstake a larger amount")]
+    OutputBelowMinimum,
+
+    #[error("Stake account was not fully drained by withdrawal")]
+    StakeAccountNotClosed,
+
+    #[error("Per-epoch unstake limit reached; try again next epoch")]
+    UnstakeLimitReached,
+
+    #[error("Pool mint already has a nonzero supply")]
+    MintSupplyNotZero,
+
+    #[error("Deposits are held until the pool authority acknowledges a commission increase")]
+    CommissionHold,
+
+    #[error("Invalid validator vote account")]
+    InvalidVoteAccount,
+
+    #[error("Account data has the wrong discriminator (run MigrateAccountLayout for legacy accounts)")]
+    InvalidAccountDiscriminator,
+
+    #[error("Token account is not the user's associated token account for the pool mint")]
+    NonCanonicalTokenAccount,
+
+    #[error("Stake account data length does not match the stake state size")]
+    StakeAccountSizeMismatch,
+
+    #[error("total_shares and mint supply diverge by more than ReconcileShares may correct")]
+    SharesDivergenceTooLarge,
+
+    #[error("Account passed as a sysvar is not that sysvar")]
+    InvalidSysvar,
+
+    #[error("Deposit would give the validator more than its share of pool stake")]
+    ValidatorStakeLimitExceeded,
+
+    #[error("Validator is not in the pool's validator list")]
+    ValidatorNotInList,
+
+    #[error("Validator is already in the pool's validator list")]
+    ValidatorAlreadyInList,
+
+    #[error("Validator list is full")]
+    ValidatorListFull,
+
+    #[error("Validator still has active stake and cannot be removed")]
+    ValidatorHasStake,
+
+    #[error("Stake pool account is below rent exemption; top it up with TopUpPool")]
+    PoolNotRentExempt,
+
+    #[error("Partial unstake would leave a stake account below the minimum delegation")]
+    PartialUnstakeBelowMinimum,
+
+    #[error("Stake account is already delegated and cannot take another deposit")]
+    StakeAccountAlreadyDelegated,
+
+    #[error("Unstake is worth more than the stake delegated from the user's stake account")]
+    UnstakeExceedsStake,
+
+    #[error("Stake account is being moved to another validator and cannot be withdrawn")]
+    StakeMigrating,
 }
 
 impl From<StakePoolError> for ProgramError {
@@ -17,10 +17,12 @@ pub enum StakePoolInstruction {
     /// 2. `[writable]` Pool token mint
     /// 3. `[writable]` Manager fee account
     /// 4. `[writable]` Treasury fee account
-    /// 5. `[]` Helius validator vote account pubkey (passed in instruction data, not as account)
-    /// 6. `[]` Token program id
-    /// 7. `[]` System program id
-    /// 8. `[]` Rent sysvar
+    /// 5. `[]` Token program id
+    /// 6. `[]` System program id
+    /// 7. `[]` Rent sysvar
+    /// 8. `[]` Helius validator vote account (must match `helius_validator_vote`)
+    /// 9. `[]` (Optional) Clock sysvar. Passing it makes the pool strict: the validator
+    ///    must have voted recently, here and in every later `AddValidator`.
     Initialize {
         /// Pool name
         name: String,
@@ -31,11 +33,16 @@ pub enum StakePoolInstruction {
     },
 
     /// Stake SOL in the pool
+    /// The user's stake account must be new or initialized but not yet delegated. A
+    /// delegated one cannot take another deposit; once unstaked, `WithdrawStake` closes
+    /// it and the next `Stake` creates it again. Lamports the stake account already holds
+    /// beyond its rent-exempt reserve are delegated and credited along with the deposit.
+    /// The pool tokens minted are recorded in the stake account's stake record.
     /// 
     /// Accounts expected:
     /// 0. `[signer, writable]` User account
     /// 1. `[writable]` Stake pool
-    /// 2. `[writable]` User token account
+    /// 2. `[writable]` User token account (must be the user's ATA if the pool sets `require_ata`)
     /// 3. `[writable]` Pool token mint
     /// 4. `[writable]` Stake account (derived from user & pool)
     /// 5. `[]` Token program id
@@ -44,16 +51,31 @@ pub enum StakePoolInstruction {
     /// 8. `[]` Rent sysvar
     /// 9. `[]` Clock sysvar
     /// 10. `[]` Stake history sysvar
-    /// 11. `[]` Helius validator vote account (read-only)
+    /// 11. `[]` Stake config
+    /// 12. `[]` Validator vote account: the Helius validator, or once the pool has a
+    ///     validator list, any validator in it
+    /// 13. `[]` Stake authority PDA (signs delegation and minting)
+    ///
+    /// Then, in any order after 13 (PDAs matched by their derived addresses):
+    /// - `[writable]` Stake record PDA of the stake account; created here if missing
+    /// - `[writable]` Validator list PDA; required once the pool has one
+    /// - `[signer, writable]` Rent payer (optional, the only other signer); funds the
+    ///   rent-exempt reserves of the accounts created here, instead of the user
     Stake {
         /// Amount of SOL to stake
         amount: u64,
     },
 
     /// Unstake SOL from the pool
+    /// Burning every pool token the stake record holds for the user's stake account
+    /// deactivates the whole account, rewards included. Fewer tokens split their SOL
+    /// value into a split stake PDA for the current epoch and deactivate only that; the
+    /// rest stays delegated. More are rejected. For an account without a record, tokens
+    /// worth all of its delegated stake deactivate it and tokens worth more are rejected.
+    /// One partial unstake per user and epoch.
     /// 
     /// Accounts expected:
-    /// 0. `[signer, writable]` User account
+    /// 0. `[signer, writable]` User account (burn authority; pays rent for a split stake account)
     /// 1. `[writable]` Stake pool
     /// 2. `[writable]` User token account
     /// 3. `[writable]` Pool token mint
@@ -61,38 +83,534 @@ pub enum StakePoolInstruction {
     /// 5. `[]` Token program id
     /// 6. `[]` Stake program id
     /// 7. `[]` Clock sysvar
+    /// 8. `[]` Stake authority PDA (signs the deactivation)
+    ///
+    /// Then, in any order after 8:
+    /// - `[writable]` Stake record PDA of the stake account
+    /// - `[writable]` Validator list PDA; required once the pool has one
+    /// - `[writable]` Split stake PDA for the current epoch, `[writable]` its stake record
+    ///   PDA, `[]` system program id and `[]` rent sysvar; required for a partial unstake
     Unstake {
         /// Amount of pool tokens to unstake
         amount: u64,
     },
 
-    /// Claim rewards
+    /// Claim rewards (advances the pool's processed epoch)
+    /// Returns a borsh-encoded `ClaimStatus` via return data; a repeat call in the
+    /// same epoch succeeds with `already_processed = true`.
     /// 
     /// Accounts expected:
-    /// 0. `[signer, writable]` User account (who is claiming)
+    /// 0. `[signer]` User account (who triggers the update)
     /// 1. `[writable]` Stake pool
-    /// 2. `[writable]` User token account (to receive rewards)
-    /// 3. `[writable]` Pool token mint
-    /// 4. `[writable]` Treasury fee account (to receive fees)
-    /// 5. `[writable]` Stake account (for the Helius validator - needs to be passed)
-    /// 6. `[]` Token program id
-    /// 7. `[]` Clock sysvar
+    /// 2. `[]` Clock sysvar
+    /// 3. `[writable]` (Optional) Pool history PDA, receives a snapshot when the epoch advances
+    /// 4. `[]` (Optional) Pool validator vote account, has its commission checked
+    ///
+    /// The optional accounts are matched by key, so they may be passed in either order.
     ClaimRewards,
 
     /// Withdraw SOL from a deactivated stake account
     /// Requires the stake account to be fully deactivated (cooldown passed).
+    /// The full balance, including the rent-exempt reserve, is paid out and the
+    /// stake account is closed, along with its stake record. The stake the record holds
+    /// as pending is taken off `total_pending_withdrawal`.
     /// 
     /// Accounts expected:
     /// 0. `[signer, writable]` User account (receives SOL)
-    /// 1. `[writable]` Stake pool (read-only, for withdraw authority derivation)
-    /// 2. `[writable]` Stake account (PDA derived from user & pool - withdraw from)
+    /// 1. `[writable]` Stake pool (withdraw authority, pending withdrawal total)
+    /// 2. `[writable]` Stake account (PDA derived from user & pool, or a split stake PDA
+    ///    from a partial `Unstake` - withdraw from)
     /// 3. `[]` Stake pool withdraw authority PDA (derived from pool)
     /// 4. `[]` Stake program id
     /// 5. `[]` Clock sysvar
     /// 6. `[]` Stake history sysvar
+    /// 7. `[writable]` Stake record PDA of the stake account
     WithdrawStake,
 
-    // Removed AddValidator, RemoveValidator, UpdateValidatorStatus
+    /// Unstake the user's entire obeSOL balance
+    /// Burns every token held in the user token account and unstakes their SOL value as
+    /// `Unstake` does.
+    ///
+    /// Accounts expected: same as `Unstake`
+    UnstakeAll {
+        /// Minimum balance the user expects to burn; guards against the balance
+        /// changing between building and executing the transaction
+        min_tokens: u64,
+    },
+
+    /// Create and initialize (but don't delegate) a user's stake account ahead of time
+    /// A later `Stake` detects the existing account and skips re-creation.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` User account (pays rent)
+    /// 1. `[]` Stake pool
+    /// 2. `[writable]` Stake account (derived from user & pool)
+    /// 3. `[]` Stake program id
+    /// 4. `[]` System program id
+    /// 5. `[]` Rent sysvar
+    PrepareStakeAccount,
+
+    /// Preview the SOL a `WithdrawStake` would return, without withdrawing
+    /// Returns a borsh-encoded `WithdrawPreview` via return data.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Stake pool
+    /// 1. `[]` Stake account (PDA derived from user & pool)
+    /// 2. `[]` Clock sysvar
+    PreviewWithdraw,
+
+    /// Repair a pool whose stored mint was never written during initialization
+    /// Only succeeds while `StakePool.mint` is still the default pubkey.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` Pool token mint (PDA derived from the pool)
+    RepairMint {
+        /// Mint address to record; must be the pool's derived mint PDA
+        mint: Pubkey,
+    },
+
+    /// Create the pool's history account, a ring buffer of per-epoch snapshots
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Stake pool authority (pays rent)
+    /// 1. `[]` Stake pool
+    /// 2. `[writable]` Pool history PDA (derived from the pool)
+    /// 3. `[]` System program id
+    /// 4. `[]` Rent sysvar
+    CreateHistory {
+        /// Number of epochs to retain (1 to MAX_HISTORY_ENTRIES)
+        capacity: u32,
+    },
+
+    /// Read pool history snapshots, oldest first
+    /// Returns a borsh-encoded `ReturnPage<HistoryEntry>` via return data.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Pool history PDA
+    GetHistory {
+        /// Index of the first snapshot to return
+        offset: u32,
+        /// Maximum number of snapshots to return
+        limit: u32,
+    },
+
+    /// Clear rounding dust from the pool totals
+    /// Reconciles `total_shares` to the mint supply when they differ by at most
+    /// `dust_threshold`, then zeroes `total_staked` if no shares remain and it is dust.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` Pool token mint
+    SweepDust {
+        /// Largest residue treated as dust (at most MAX_DUST_THRESHOLD)
+        dust_threshold: u64,
+    },
+
+    /// Set the per-epoch unstake circuit breaker
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    SetUnstakeLimit {
+        /// Maximum SOL value (lamports) unstakeable per epoch, 0 = unlimited
+        max_unstake_per_epoch: u64,
+    },
+
+    /// Advance the pool epoch (as `ClaimRewards`) and return the signer's position
+    /// Returns a borsh-encoded `ClaimQueryResult` via return data; in an
+    /// already-processed epoch only the current value is returned.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` User account
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` Clock sysvar
+    /// 3. `[]` User's pool token account
+    /// 4. `[writable]` (Optional) Pool history PDA, as for `ClaimRewards`
+    /// 5. `[]` (Optional) Pool validator vote account, as for `ClaimRewards`
+    ClaimAndQuery,
+
+    /// Move lamports above the rent-exempt minimum out of a pool-owned PDA
+    /// The account is never left below rent exemption.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    /// 2. `[writable]` Account to reclaim from (the stake pool itself or its history PDA)
+    /// 3. `[writable]` Treasury fee account (receives the surplus)
+    ReclaimExcessRent,
+
+    /// Report the program version, compiled features and a pool's enabled options
+    /// Returns a borsh-encoded `ProgramInfo` via return data.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` (Optional) Stake pool, to fill in `pool_flags`
+    GetProgramInfo,
+
+    /// Read the SOL value of several pool token accounts at the current pool ratio
+    /// Returns a borsh-encoded `ReturnPage<UserValue>` via return data; accounts that
+    /// are not pool token accounts are returned with `valid = false`.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Stake pool
+    /// 1.. `[]` Pool token accounts to value
+    GetUserValues {
+        /// Index (among the token accounts) of the first one to return
+        offset: u32,
+        /// Maximum number of entries to return
+        limit: u32,
+    },
+
+    /// Configure validator commission alerts
+    /// The vote account's current commission becomes the baseline. During
+    /// `ClaimRewards`, an increase of at least `alert_threshold` points is logged and,
+    /// with `pause_on_hike`, holds new deposits (unstaking is unaffected).
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` Pool validator vote account
+    SetCommissionPolicy {
+        /// Commission increase (percentage points) that raises an alert, 0 = off
+        alert_threshold: u8,
+        /// Whether an alert also holds new deposits
+        pause_on_hike: bool,
+    },
+
+    /// Acknowledge the validator's current commission, releasing a deposit hold
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` Pool validator vote account
+    AcknowledgeCommission,
+
+    /// Rewrite accounts created before account discriminators were introduced into the
+    /// current layout (8-byte type tag + data), growing them by 8 bytes. History entries
+    /// also gain their price, rewards and fees fields. Accounts that are already migrated
+    /// are left untouched.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Stake pool authority (pays rent for the added bytes)
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` System program id
+    /// 3. `[writable]` (Optional) Pool history PDA
+    MigrateAccountLayout,
+
+    /// Require (or stop requiring) deposits to mint to the user's associated token account
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    SetRequireAta {
+        /// Reject any user token account other than the canonical ATA
+        require_ata: bool,
+    },
+
+    /// Run every precondition check of `Stake` without transferring, creating or
+    /// minting anything, for frontends to simulate before the user signs
+    /// Returns a borsh-encoded `StakeValidation` via return data.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` User account (signer in the `Stake` it previews)
+    /// 1. `[]` Stake pool
+    /// 2. `[]` User's pool token account
+    /// 3. `[]` Pool token mint
+    /// 4. `[]` User's stake account PDA
+    /// 5. `[]` Validator vote account
+    /// 6. `[]` Stake authority PDA
+    /// 7. `[]` Stake program id (queried for the minimum delegation)
+    /// 8. Then, in any order: stake record PDA, validator list PDA and rent payer (marked
+    ///    as signer in the simulated transaction), as in `Stake`
+    ValidateStake {
+        /// Amount of SOL (lamports) the `Stake` would deposit
+        amount: u64,
+    },
+
+    /// Set `total_shares` to the pool mint's actual supply
+    /// Fails with `SharesDivergenceTooLarge` if they differ by more than
+    /// `MAX_SHARES_DIVERGENCE_BPS`.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` Pool token mint
+    ReconcileShares,
+
+    /// Report the pool's totals, including stake still cooling down after `Unstake`
+    /// Returns a borsh-encoded `PoolInfo` via return data.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Stake pool
+    GetPoolInfo,
+
+    /// Create the user's pool token ATA if it is missing, then stake as `Stake` does,
+    /// so a first-time user needs a single transaction
+    /// Uses more compute than `Stake`; callers should raise the compute unit limit.
+    ///
+    /// Accounts expected:
+    /// 0-13. As for `Stake`, with 2. `[writable]` the user's associated token account
+    /// 14. `[]` Associated token account program id
+    /// 15. The trailing accounts of `Stake` (stake record, validator list, rent payer), in
+    ///     any order; the rent payer does not pay for the ATA
+    StakeWithSetup {
+        /// Amount of SOL (lamports) to stake
+        amount: u64,
+    },
+
+    /// Record the pool's totals, mint supply and time in a new write-once snapshot
+    /// account that includes the previous snapshot's hash. Anyone may take one.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Payer (pays rent for the snapshot)
+    /// 1. `[]` Stake pool
+    /// 2. `[]` Pool token mint
+    /// 3. `[writable]` Snapshot PDA for `index` (derived from the pool and index)
+    /// 4. `[]` System program id
+    /// 5. `[]` Rent sysvar
+    /// 6. `[]` Clock sysvar
+    /// 7. `[]` Snapshot PDA for `index - 1` (omitted when `index` is 0)
+    Snapshot {
+        /// Position in the chain; must be one past the latest snapshot
+        index: u64,
+    },
+
+    /// Report the configured and effective deposit limits
+    /// Returns a borsh-encoded `StakeLimits` via return data.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Stake pool
+    /// 1. `[]` Stake program id (queried for the minimum delegation)
+    GetStakeLimits,
+
+    /// Change the pool's fee, stake limits or pause state
+    /// Only the fields passed as `Some` are changed; all `None` is a no-op.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    UpdatePoolConfig {
+        /// New fee percentage (0-100)
+        fee_percentage: Option<u8>,
+        /// New minimum stake, in lamports
+        min_stake: Option<u64>,
+        /// New maximum stake, in lamports
+        max_stake: Option<u64>,
+        /// Pause or unpause the pool
+        paused: Option<bool>,
+    },
+
+    /// Add a validator to the pool's validator list, creating the list on first use
+    /// A new list starts with the Helius validator, holding all stake delegated so far.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Stake pool authority (pays rent for a new list)
+    /// 1. `[writable]` Stake pool
+    /// 2. `[writable]` Validator list PDA (derived from the pool)
+    /// 3. `[]` Vote account of the validator to add
+    /// 4. `[]` System program id
+    /// 5. `[]` Rent sysvar
+    /// 6. `[]` Clock sysvar, required when the pool was initialized strict; the
+    ///    validator must then have voted recently
+    AddValidator {
+        /// Number of validators the list has room for (2 to MAX_VALIDATORS); only used
+        /// when this call creates the list
+        max_validators: u32,
+    },
+
+    /// Remove a validator that has no stake left from the pool's validator list
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[]` Stake pool
+    /// 2. `[writable]` Validator list PDA
+    RemoveValidator {
+        /// Vote account of the validator to remove
+        vote_account: Pubkey,
+    },
+
+    /// Restore the stake pool account to rent exemption, which deposits and withdrawals
+    /// require. Anyone may pay; an already rent-exempt pool is left unchanged.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Payer (funds the shortfall)
+    /// 1. `[writable]` Stake pool
+    /// 2. `[]` System program id
+    TopUpPool,
+
+    /// Compare the stake behind the pool with what it owes: the value of the obeSOL
+    /// supply plus pending withdrawals
+    /// Returns a borsh-encoded `BackingReport` via return data. Stake accounts left out
+    /// only lower the backing, so a fully backed report is proof on its own. An
+    /// under-backed report marked `partial` counted less stake than the pool has booked;
+    /// pass any pool stake accounts left out to tell missing accounts from a real deficit.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Stake pool
+    /// 1. `[]` Pool token mint
+    /// 2. `[]` Users' stake account and split stake PDAs, as many as follow, each at most once
+    VerifyBacking,
+
+    /// Raise the capacity of the pool's validator list, reallocating its account
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Stake pool authority (pays rent for the added space)
+    /// 1. `[]` Stake pool
+    /// 2. `[writable]` Validator list PDA
+    /// 3. `[]` System program id
+    GrowValidatorList {
+        /// New number of validators the list has room for; above the current one and
+        /// at most MAX_VALIDATORS
+        max_validators: u32,
+    },
+
+    /// Set the share of the list's stake one validator may hold. Only new deposits are
+    /// checked against it: stake already above a lowered cap stays delegated, but its
+    /// validator takes no more until it is back under the cap. An even split of the
+    /// list still applies while it is larger than the cap.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    UpdateValidatorCap {
+        /// Cap in basis points, MIN_VALIDATOR_STAKE_BPS to 10000
+        max_validator_stake_bps: u16,
+    },
+
+    /// Turn auto-claim on or off. With it on, the first `Stake` or `Unstake` of an epoch
+    /// advances the pool epoch as `ClaimRewards` does before pricing the deposit or
+    /// burn, so no separate crank is needed. It checks the pool validator's commission
+    /// when `Stake` delegates to it, and snapshots the pool history when the history
+    /// PDA is appended (writable) to either instruction. Off by default, as it adds to
+    /// the compute cost of that first call.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    SetAutoClaim {
+        /// Whether `Stake`/`Unstake` advance the pool epoch
+        enabled: bool,
+    },
+
+    /// Move one pool stake account from a listed validator to another, in two calls
+    /// with the same arguments. The first deactivates the stake while it is delegated
+    /// to validator `from_index` and takes it off that entry. Once the stake has cooled
+    /// down, the second delegates it to validator `to_index` and adds it to that entry;
+    /// by then the source may already have been removed. The account's obeSOL stays
+    /// outstanding throughout, so the owner cannot withdraw it in between. The
+    /// destination's stake cap is not checked, as no new stake enters the pool.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[]` Stake pool
+    /// 2. `[writable]` Validator list PDA
+    /// 3. `[writable]` Stake account (a user's stake PDA)
+    /// 4. `[]` Stake record PDA of the stake account
+    /// 5. `[]` Vote account of validator `to_index`
+    /// 6. `[]` Clock sysvar
+    /// 7. `[]` Stake history sysvar
+    /// 8. `[]` Stake config account
+    /// 9. `[]` Stake authority PDA
+    /// 10. `[]` Stake program id
+    MigrateValidatorStake {
+        /// List position of the validator the stake leaves
+        from_index: u32,
+        /// List position of the validator the stake moves to
+        to_index: u32,
+    },
+
+    // Removed UpdateValidatorStatus
+
+    /// (Test only) Overwrite the pool's epoch bookkeeping to simulate epoch progression.
+    /// Built with `test-utils` and in this crate's own unit tests.
+    /// Must remain the last variant: new variants go above it so their discriminants
+    /// are identical with and without `test-utils`.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Stake pool authority
+    /// 1. `[writable]` Stake pool
+    #[cfg(any(test, feature = "test-utils"))]
+    WarpPoolEpoch {
+        /// New `last_update_epoch`
+        last_update_epoch: u64,
+        /// New `unstake_tracking_epoch`
+        unstake_tracking_epoch: u64,
+    },
+}
+
+/// Positions of `Stake`'s required accounts, for code that indexes them instead of
+/// reading them in order (`StakeWithSetup`)
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)] // Every position is named; only some are indexed
+pub enum StakeAccounts {
+    User,
+    StakePool,
+    UserTokenAccount,
+    PoolMint,
+    UserStakeAccount,
+    TokenProgram,
+    StakeProgram,
+    SystemProgram,
+    RentSysvar,
+    ClockSysvar,
+    StakeHistorySysvar,
+    StakeConfig,
+    ValidatorVote,
+    StakeAuthority,
+}
+
+impl StakeAccounts {
+    /// Number of required `Stake` accounts; the optional ones follow them
+    pub const COUNT: usize = StakeAccounts::StakeAuthority as usize + 1;
+}
+
+impl StakePoolInstruction {
+    /// Human-readable instruction name, logged on dispatch
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Initialize { .. } => "Initialize",
+            Self::Stake { .. } => "Stake",
+            Self::Unstake { .. } => "Unstake",
+            Self::ClaimRewards => "Claim Rewards",
+            Self::WithdrawStake => "Withdraw Stake",
+            Self::UnstakeAll { .. } => "Unstake All",
+            Self::PrepareStakeAccount => "Prepare Stake Account",
+            Self::PreviewWithdraw => "Preview Withdraw",
+            Self::RepairMint { .. } => "Repair Mint",
+            Self::CreateHistory { .. } => "Create History",
+            Self::GetHistory { .. } => "Get History",
+            Self::SweepDust { .. } => "Sweep Dust",
+            Self::SetUnstakeLimit { .. } => "Set Unstake Limit",
+            Self::ClaimAndQuery => "Claim And Query",
+            Self::ReclaimExcessRent => "Reclaim Excess Rent",
+            Self::GetProgramInfo => "Get Program Info",
+            Self::GetUserValues { .. } => "Get User Values",
+            Self::SetCommissionPolicy { .. } => "Set Commission Policy",
+            Self::AcknowledgeCommission => "Acknowledge Commission",
+            Self::MigrateAccountLayout => "Migrate Account Layout",
+            Self::SetRequireAta { .. } => "Set Require ATA",
+            Self::ValidateStake { .. } => "Validate Stake",
+            Self::ReconcileShares => "Reconcile Shares",
+            Self::GetPoolInfo => "Get Pool Info",
+            Self::StakeWithSetup { .. } => "Stake With Setup",
+            Self::Snapshot { .. } => "Snapshot",
+            Self::GetStakeLimits => "Get Stake Limits",
+            Self::UpdatePoolConfig { .. } => "Update Pool Config",
+            Self::AddValidator { .. } => "Add Validator",
+            Self::RemoveValidator { .. } => "Remove Validator",
+            Self::TopUpPool => "Top Up Pool",
+            Self::VerifyBacking => "Verify Backing",
+            Self::GrowValidatorList { .. } => "Grow Validator List",
+            Self::UpdateValidatorCap { .. } => "Update Validator Cap",
+            Self::SetAutoClaim { .. } => "Set Auto Claim",
+            Self::MigrateValidatorStake { .. } => "Migrate Validator Stake",
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::WarpPoolEpoch { .. } => "Warp Pool Epoch (test only)",
+        }
+    }
 }
 
 // REMOVED ENTIRE MANUAL IMPLEMENTATION OF UNPACK
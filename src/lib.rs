@@ -11,9 +11,19 @@ use solana_program::{
 mod error;
 mod instruction;
 mod processor;
+mod safe_math;
+mod security;
 mod state;
 mod utils;
 
+// Also built for the processor tests, which use its instruction builders
+#[cfg(any(test, feature = "client"))]
+pub mod client;
+
+// Test-only instructions must never reach a deployed program
+#[cfg(all(feature = "test-utils", target_os = "solana"))]
+compile_error!("the `test-utils` feature is for native tests only and cannot be built for on-chain deployment");
+
 use crate::{processor::Processor};
 
 // Program entrypoint
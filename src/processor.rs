@@ -6,25 +6,55 @@ use solana_program::{
     pubkey::Pubkey,
     sysvar::{clock::Clock, rent::Rent, Sysvar},
     msg,
-    program::{invoke, invoke_signed},
+    program::{invoke, invoke_signed, set_return_data},
     stake::{
         instruction as stake_instruction,
         state::{Authorized, Lockup, StakeStateV2},
     },
-    system_instruction,
+    system_instruction, system_program,
 };
 use borsh::{BorshSerialize, BorshDeserialize};
 use crate::{
     error::StakePoolError,
-    instruction::StakePoolInstruction,
-    state::{StakePool},
-    utils::{assert_owned_by, create_or_allocate_account_raw},
+    instruction::{StakeAccounts, StakePoolInstruction},
+    safe_math,
+    security::SecurityManager,
+    state::{
+        BackingReport, ClaimQueryResult, ClaimStatus, DiscriminatedAccount, HistoryEntry, LegacyPoolHistory, PoolHistory,
+        PoolInfo, PoolSnapshot, ProgramInfo, StakeLimits, StakePool, StakeRecord, StakeValidation, UserValue,
+        ValidatorEntry, ValidatorList, ValidatorStatus, WithdrawPreview,
+        ACCOUNT_DISCRIMINATOR_LEN, DEFAULT_VALIDATOR_STAKE_BPS, FEATURE_DEBUG_LOGGING, FEATURE_TEST_UTILS, HISTORY_SEED,
+        MAX_DUST_THRESHOLD, MAX_HISTORY_ENTRIES, MAX_SHARES_DIVERGENCE_BPS, MAX_VALIDATORS, MINT_SEED,
+        MIN_VALIDATOR_STAKE_BPS, POOL_SEED, POOL_STATE_VERSION, PROGRAM_VERSION, SNAPSHOT_SEED, SPLIT_STAKE_SEED,
+        STAKE_ACCOUNT_SEED, STAKE_ACCOUNT_SIZE, STAKE_AUTHORITY_SEED, STAKE_RECORD_SEED, VALIDATOR_LIST_SEED,
+        WITHDRAW_AUTHORITY_SEED,
+    },
+    utils::{
+        assert_associated_token_account, assert_authority_bumps, assert_distinct_authorities, assert_rent_exempt, assert_mint_authority, assert_mint_not_freezable, assert_owned_by,
+        assert_program_id, assert_sysvar, close_program_account, cooldown_complete, cpi_step, create_or_allocate_account_raw, effective_min_stake,
+        set_paged_return_data,
+        assert_vote_account_live, vote_account_commission,
+    },
 };
 
 pub struct Processor {}
 
 impl Processor {
     /// Processes instructions according to the instruction data provided.
+    ///
+    /// Invoke depth: every handler only issues direct CPIs into the system, stake
+    /// and token programs, none of which invoke further programs. The deepest point
+    /// reached is therefore one level below this program (stack height 2 when
+    /// called from a top-level instruction), well under the runtime's limit of 4
+    /// nested CPIs. New features must keep CPIs direct rather than chaining through
+    /// intermediate programs.
+    ///
+    /// Authority accounts in CPIs: `invoke_signed` seeds only provide a PDA's
+    /// signature; the PDA's `AccountInfo` must still be passed whenever it appears
+    /// as an account meta of the inner instruction. That applies to the stake
+    /// authority for `delegate_stake`, `deactivate_stake` and `mint_to`, and to the
+    /// withdraw authority for `withdraw`. `create_account` for a PDA needs its own
+    /// seeds, while stake `initialize` takes no signer at all.
     pub fn process(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
@@ -38,27 +68,49 @@ impl Processor {
             })?;
 
         // Route to the specific instruction processor based on the unpacked instruction.
+        msg!("Instruction: {}", instruction.name());
         match instruction {
-            StakePoolInstruction::Initialize { name, fee_percentage, helius_validator_vote } => {
-                msg!("Instruction: Initialize");
-                Self::process_initialize(program_id, accounts, name, fee_percentage, helius_validator_vote)
-            }
-            StakePoolInstruction::Stake { amount } => {
-                msg!("Instruction: Stake");
-                Self::process_stake(program_id, accounts, amount)
-            }
-            StakePoolInstruction::Unstake { amount } => {
-                msg!("Instruction: Unstake");
-                Self::process_unstake(program_id, accounts, amount)
-            }
-            StakePoolInstruction::ClaimRewards => {
-                msg!("Instruction: Claim Rewards");
-                Self::process_claim_rewards(program_id, accounts)
-            }
-            StakePoolInstruction::WithdrawStake => {
-                msg!("Instruction: Withdraw Stake");
-                Self::process_withdraw_stake(program_id, accounts)
-            }
+            StakePoolInstruction::Initialize { name, fee_percentage, helius_validator_vote } => Self::process_initialize(program_id, accounts, name, fee_percentage, helius_validator_vote),
+            StakePoolInstruction::Stake { amount } => Self::process_stake(program_id, accounts, amount),
+            StakePoolInstruction::Unstake { amount } => Self::process_unstake(program_id, accounts, amount),
+            StakePoolInstruction::ClaimRewards => Self::process_claim_rewards(program_id, accounts),
+            StakePoolInstruction::WithdrawStake => Self::process_withdraw_stake(program_id, accounts),
+            StakePoolInstruction::UnstakeAll { min_tokens } => Self::process_unstake_all(program_id, accounts, min_tokens),
+            StakePoolInstruction::PrepareStakeAccount => Self::process_prepare_stake_account(program_id, accounts),
+            StakePoolInstruction::PreviewWithdraw => Self::process_preview_withdraw(program_id, accounts),
+            StakePoolInstruction::RepairMint { mint } => Self::process_repair_mint(program_id, accounts, mint),
+            StakePoolInstruction::CreateHistory { capacity } => Self::process_create_history(program_id, accounts, capacity),
+            StakePoolInstruction::GetHistory { offset, limit } => Self::process_get_history(program_id, accounts, offset, limit),
+            StakePoolInstruction::SweepDust { dust_threshold } => Self::process_sweep_dust(program_id, accounts, dust_threshold),
+            StakePoolInstruction::SetUnstakeLimit { max_unstake_per_epoch } => Self::process_set_unstake_limit(program_id, accounts, max_unstake_per_epoch),
+            StakePoolInstruction::ClaimAndQuery => Self::process_claim_and_query(program_id, accounts),
+            StakePoolInstruction::ReclaimExcessRent => Self::process_reclaim_excess_rent(program_id, accounts),
+            StakePoolInstruction::GetProgramInfo => Self::process_get_program_info(program_id, accounts),
+            StakePoolInstruction::GetUserValues { offset, limit } => Self::process_get_user_values(program_id, accounts, offset, limit),
+            StakePoolInstruction::SetCommissionPolicy { alert_threshold, pause_on_hike } => Self::process_set_commission_policy(program_id, accounts, alert_threshold, pause_on_hike),
+            StakePoolInstruction::AcknowledgeCommission => Self::process_acknowledge_commission(program_id, accounts),
+            StakePoolInstruction::MigrateAccountLayout => Self::process_migrate_account_layout(program_id, accounts),
+            StakePoolInstruction::SetRequireAta { require_ata } => Self::process_set_require_ata(program_id, accounts, require_ata),
+            StakePoolInstruction::ValidateStake { amount } => Self::process_validate_stake(program_id, accounts, amount),
+            StakePoolInstruction::ReconcileShares => Self::process_reconcile_shares(program_id, accounts),
+            StakePoolInstruction::GetPoolInfo => Self::process_get_pool_info(program_id, accounts),
+            StakePoolInstruction::StakeWithSetup { amount } => Self::process_stake_with_setup(program_id, accounts, amount),
+            StakePoolInstruction::Snapshot { index } => Self::process_snapshot(program_id, accounts, index),
+            StakePoolInstruction::GetStakeLimits => Self::process_get_stake_limits(program_id, accounts),
+            StakePoolInstruction::UpdatePoolConfig { fee_percentage, min_stake, max_stake, paused } =>
+                Self::process_update_config(program_id, accounts, fee_percentage, min_stake, max_stake, paused),
+            StakePoolInstruction::AddValidator { max_validators } => Self::process_add_validator(program_id, accounts, max_validators),
+            StakePoolInstruction::RemoveValidator { vote_account } => Self::process_remove_validator(program_id, accounts, &vote_account),
+            StakePoolInstruction::TopUpPool => Self::process_top_up_pool(program_id, accounts),
+            StakePoolInstruction::VerifyBacking => Self::process_verify_backing(program_id, accounts),
+            StakePoolInstruction::GrowValidatorList { max_validators } => Self::process_grow_validator_list(program_id, accounts, max_validators),
+            StakePoolInstruction::UpdateValidatorCap { max_validator_stake_bps } =>
+                Self::process_update_validator_cap(program_id, accounts, max_validator_stake_bps),
+            StakePoolInstruction::SetAutoClaim { enabled } => Self::process_set_auto_claim(program_id, accounts, enabled),
+            StakePoolInstruction::MigrateValidatorStake { from_index, to_index } =>
+                Self::process_migrate_validator_stake(program_id, accounts, from_index, to_index),
+            #[cfg(any(test, feature = "test-utils"))]
+            StakePoolInstruction::WarpPoolEpoch { last_update_epoch, unstake_tracking_epoch } => Self::process_warp_pool_epoch(program_id, accounts, last_update_epoch, unstake_tracking_epoch),
         }
     }
 
@@ -83,7 +135,8 @@ impl Processor {
         let token_program_info = next_account_info(account_info_iter)?; // SPL Token program ID
         let system_program_info = next_account_info(account_info_iter)?; // Needed for account creation
         let rent_info = next_account_info(account_info_iter)?; // Rent sysvar
-        let stake_authority_info = next_account_info(account_info_iter)?; // <-- ADDED Account #13
+        let vote_info = next_account_info(account_info_iter)?; // Validator vote account
+        let strict_clock_info = account_info_iter.next(); // Optional: checks the validator is voting
 
         // --- Validation --- 
         // Ensure the provided authority signed the transaction.
@@ -91,6 +144,8 @@ impl Processor {
             msg!("Authority signature missing");
             return Err(ProgramError::MissingRequiredSignature);
         }
+        assert_program_id(token_program_info, &spl_token::id())?;
+        assert_program_id(system_program_info, &system_program::id())?;
         // Validate business logic constraints.
         if fee_percentage > 100 {
             msg!("Fee percentage must be 0-100");
@@ -100,36 +155,127 @@ impl Processor {
             msg!("Pool name length invalid");
             return Err(StakePoolError::InvalidPoolName.into());
         }
+        // A pool whose validator isn't a real vote account could never delegate. Reading
+        // the commission checks the account is a vote account and records the baseline
+        // for commission tracking.
+        if helius_validator_vote == Pubkey::default() || *vote_info.key != helius_validator_vote {
+            msg!("Vote account {} does not match validator {}", vote_info.key, helius_validator_vote);
+            return Err(StakePoolError::InvalidVoteAccount.into());
+        }
+        let validator_commission = vote_account_commission(vote_info)?;
+        // A strict pool also refuses a validator that isn't voting, such as a fake or
+        // abandoned vote account set up to redirect rewards
+        if let Some(clock_info) = strict_clock_info {
+            assert_sysvar(clock_info, &solana_program::sysvar::clock::id())?;
+            assert_vote_account_live(vote_info, Clock::from_account_info(clock_info)?.slot)?;
+        }
+        // Fee recipients must be distinct from each other and from the authority wallet
+        // so manager and treasury fees can be told apart.
+        if manager_fee_info.key == treasury_fee_info.key {
+            msg!("Manager and treasury fee accounts must differ");
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
+        if manager_fee_info.key == authority_info.key || treasury_fee_info.key == authority_info.key {
+            msg!("Fee accounts must not be the pool authority");
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
 
         // --- Stake Pool PDA Derivation & Validation ---
-        const poolSeedString: &str = "obelisk_pool_04"; // Use NEW seed for clean initialization
         let (expected_stake_pool_pda, bump_seed) = Pubkey::find_program_address(
-            &[authority_info.key.as_ref(), poolSeedString.as_bytes()],
+            &[authority_info.key.as_ref(), POOL_SEED],
             program_id
         );
         if expected_stake_pool_pda != *stake_pool_info.key {
             msg!("Provided stake pool account {} does not match derived PDA {}", *stake_pool_info.key, expected_stake_pool_pda);
             return Err(ProgramError::InvalidSeeds);
         }
+        // Refuse to initialize over a pool that already exists at this address.
+        if stake_pool_info.owner == program_id && !stake_pool_info.data_is_empty() {
+            msg!("Stake pool {} is already initialized", stake_pool_info.key);
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
         let stake_pool_signer_seeds = &[
             authority_info.key.as_ref(),
-            poolSeedString.as_bytes(),
+            POOL_SEED,
             &[bump_seed]
         ];
 
+        // --- Mint PDA Derivation & Validation ---
+        // Derived up front so the pool state is complete when it is first written
+        let (expected_mint_pda, mint_bump_seed) = Pubkey::find_program_address(
+            &[stake_pool_info.key.as_ref(), MINT_SEED],
+            program_id
+        );
+        if expected_mint_pda != *pool_mint_info.key {
+            msg!("Provided pool mint account {} does not match derived PDA {}", *pool_mint_info.key, expected_mint_pda);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let mint_signer_seeds = &[
+            stake_pool_info.key.as_ref(),
+            MINT_SEED,
+            &[mint_bump_seed]
+        ];
+
         // --- Pre-calculate Authorities and Create Initial State Object ---
         // Derive authorities FIRST, as they are needed in the StakePool struct
         let (stake_authority, stake_authority_bump) = Pubkey::find_program_address(
-            &[b"stake_authority", expected_stake_pool_pda.as_ref()], // Use expected_pda key
+            &[STAKE_AUTHORITY_SEED, expected_stake_pool_pda.as_ref()], // Use expected_pda key
             program_id,
         );
         let (withdraw_authority, withdraw_authority_bump) = Pubkey::find_program_address(
-            &[b"withdraw_authority", expected_stake_pool_pda.as_ref()], // Use expected_pda key
+            &[WITHDRAW_AUTHORITY_SEED, expected_stake_pool_pda.as_ref()], // Use expected_pda key
             program_id,
         );
+        // Distinct seeds should always give distinct PDAs; if they ever collided,
+        // staking and withdrawal permissions would be held by the same address.
+        if let Err(error) = assert_distinct_authorities(&stake_authority, &withdraw_authority) {
+            msg!("Stake and withdraw authority PDAs collide: {}", stake_authority);
+            return Err(error);
+        }
+
+        // Fee accounts must not be any of the pool's own accounts. The mint account was
+        // checked against its derived PDA above, so comparing to its key is sufficient.
+        let pool_accounts = [expected_stake_pool_pda, *pool_mint_info.key, stake_authority, withdraw_authority];
+        if pool_accounts.contains(manager_fee_info.key) || pool_accounts.contains(treasury_fee_info.key) {
+            msg!("Fee accounts must not be the pool, mint, or authority PDAs");
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
+        // The pool authority must be able to sign admin instructions later. None of the
+        // pool's own PDAs can, and the zero key and the incinerator are unspendable.
+        // Other programs' PDAs (e.g. a governance multisig signing via CPI) are allowed.
+        if pool_accounts.contains(authority_info.key)
+            || *authority_info.key == Pubkey::default()
+            || *authority_info.key == solana_program::incinerator::id()
+        {
+            msg!("Pool authority {} cannot be a pool PDA or an unspendable address", authority_info.key);
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+
+        // --- Existing Mint Check ---
+        // The mint PDA may already exist (e.g. created ahead of a retried init). Accept it
+        // only if nothing has been minted and the pool controls it; otherwise the supply
+        // would not match total_shares == 0.
+        let mint_exists = !pool_mint_info.data_is_empty();
+        if mint_exists {
+            if *pool_mint_info.owner != spl_token::id() {
+                msg!("Pool mint account {} exists but is not a token mint", pool_mint_info.key);
+                return Err(StakePoolError::InvalidAccountOwner.into());
+            }
+            let existing_mint = spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())?;
+            if existing_mint.supply != 0 {
+                msg!("Pool mint already has supply {}", existing_mint.supply);
+                return Err(StakePoolError::MintSupplyNotZero.into());
+            }
+            assert_mint_authority(&existing_mint, &stake_authority)?;
+            if existing_mint.freeze_authority.is_some() {
+                msg!("Existing pool mint has a freeze authority");
+                return Err(StakePoolError::MintHasFreezeAuthority.into());
+            }
+            msg!("Using existing pool mint {}", pool_mint_info.key);
+        }
 
         let initial_stake_pool = StakePool {
-            version: 1,
+            version: POOL_STATE_VERSION,
             authority: *authority_info.key,
             stake_authority: stake_authority,
             withdraw_authority: withdraw_authority,
@@ -137,7 +283,7 @@ impl Processor {
             fee_percentage: fee_percentage,
             total_staked: 0,
             total_shares: 0,
-            mint: Pubkey::default(), // Placeholder, set after mint is created
+            mint: *pool_mint_info.key,
             reserve: Pubkey::default(),
             helius_validator_vote: helius_validator_vote,
             manager_fee_account: *manager_fee_info.key,
@@ -148,11 +294,24 @@ impl Processor {
             max_stake: 1_000_000 * 1_000_000_000,
             stake_authority_bump_seed: stake_authority_bump,
             withdraw_authority_bump_seed: withdraw_authority_bump,
-            reserved: [0u8; 62],
+            max_unstake_per_epoch: 0,
+            unstaked_this_epoch: 0,
+            unstake_tracking_epoch: 0,
+            last_known_commission: validator_commission,
+            commission_alert_threshold: 0,
+            pause_on_commission_hike: false,
+            commission_hold: false,
+            require_ata: false,
+            total_pending_withdrawal: 0,
+            has_validator_list: false,
+            max_validator_stake_bps: DEFAULT_VALIDATOR_STAKE_BPS,
+            require_voting_validator: strict_clock_info.is_some(),
+            auto_claim: false,
+            reserved: [0u8; 20],
         };
 
         // --- Serialize the state to get the exact required size --- 
-        let serialized_data = initial_stake_pool.try_to_vec()?;
+        let serialized_data = initial_stake_pool.to_account_data()?;
         let required_size = serialized_data.len();
         msg!("Serialized initial StakePool data size: {}", required_size);
 
@@ -183,80 +342,45 @@ impl Processor {
         }
         msg!("Initialized StakePool data written to account.");
 
-        // --- Mint PDA Derivation & Validation (Depends on stake_pool_info.key) ---
-        let (expected_mint_pda, mint_bump_seed) = Pubkey::find_program_address(
-            &[stake_pool_info.key.as_ref(), b"mint"], // Use the NOW CREATED stake pool's key
-            program_id
-        );
-        if expected_mint_pda != *pool_mint_info.key {
-            msg!("Provided pool mint account {} does not match derived PDA {}", *pool_mint_info.key, expected_mint_pda);
-            return Err(ProgramError::InvalidSeeds);
-        }
-        let mint_signer_seeds = &[
-            stake_pool_info.key.as_ref(),
-            b"mint",
-            &[mint_bump_seed]
-        ];
-
         // --- Create Mint Account --- 
-        msg!("Creating or allocating pool mint account PDA");
-        create_or_allocate_account_raw(
-            &spl_token::id(),
-            pool_mint_info,
-            rent_info,
-            system_program_info,
-            authority_info,
-            spl_token::state::Mint::LEN,
-            mint_signer_seeds,
-        )?;
-
-        // --- Update StakePool Data In-Place with Mint Address --- 
-        // Calculate the offset of the 'mint' field
-        // version(1) + authority(32) + stake_authority(32) + withdraw_authority(32) +
-        // name_len(4) + name_bytes(name.len()) + fee(1) + total_staked(8) + total_shares(8) = offset
-        let name_len = name.len(); // Use the actual name length used in initial serialization
-        let mint_offset = 1 + (3 * 32) + 4 + name_len + 1 + (2 * 8);
-        msg!("Calculated mint offset: {}", mint_offset);
-
-        // Borrow mutably AGAIN (should now be safe after previous scope ended)
-        // Revert back to direct borrow_mut() as it panics, not returns Result
-        let mut account_data_for_mint = stake_pool_info.data.borrow_mut(); // BORROW 2 
-        
-        // Ensure slice is long enough (should be)
-        if account_data_for_mint.len() >= mint_offset + 32 {
-             account_data_for_mint[mint_offset..mint_offset + 32].copy_from_slice(&pool_mint_info.key.to_bytes());
-             msg!("Updated mint address directly in account data.");
-        } else {
-            msg!("Error: Account data too small to write mint address at offset {}", mint_offset);
-            return Err(ProgramError::AccountDataTooSmall);
+        if !mint_exists {
+            msg!("Creating or allocating pool mint account PDA");
+            create_or_allocate_account_raw(
+                &spl_token::id(),
+                pool_mint_info,
+                rent_info,
+                system_program_info,
+                authority_info,
+                spl_token::state::Mint::LEN,
+                mint_signer_seeds,
+            )?;
         }
-        // Drop the mutable borrow explicitly (or let it go out of scope)
-        drop(account_data_for_mint);
-        // -------------------------------------------------------
-        
+
         // --- Initialize Mint --- 
-        msg!("Initializing pool token mint");
-        invoke(
-            &spl_token::instruction::initialize_mint(
-                &spl_token::id(),
-                pool_mint_info.key,
-                &stake_authority, // Use the derived stake_authority PDA
-                None, // No freeze authority
-                0,    // Decimals
-            )?,
-            &[
-                token_program_info.clone(),
-                pool_mint_info.clone(),
-                rent_info.clone(),
-            ],
-        )?;
+        if !mint_exists {
+            msg!("Initializing pool token mint");
+            invoke(
+                &spl_token::instruction::initialize_mint(
+                    &spl_token::id(),
+                    pool_mint_info.key,
+                    &stake_authority, // Use the derived stake_authority PDA
+                    None, // No freeze authority
+                    0,    // Decimals
+                )?,
+                &[
+                    token_program_info.clone(),
+                    pool_mint_info.clone(),
+                    rent_info.clone(),
+                ],
+            ).map_err(cpi_step("initialize_mint"))?;
+        }
 
         // --- Remove Old Size/Serialization Logs --- 
         // match initial_stake_pool.try_to_vec() { // This was based on the state BEFORE mint was added
         //     Ok(data) => msg!("Calculated serialized StakePool size: {}", data.len()),
         //     Err(e) => msg!("Failed to calculate serialized size: {}", e),
         // };
-        // stake_pool.serialize(&mut *stake_pool_info.data.borrow_mut())?; // Done above
+        // stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?; // Done above
 
         Ok(())
     }
@@ -296,10 +420,16 @@ impl Processor {
         let stake_history_info = next_account_info(account_info_iter)?;
         // 11. `[]` Stake Config ID (Constant address, needed for delegate_stake CPI)
         let stake_config_info = next_account_info(account_info_iter)?;
-        // 12. `[]` Helius validator vote account (read-only)
-        let helius_validator_vote_info = next_account_info(account_info_iter)?;
+        // 12. `[]` Validator vote account (read-only)
+        let validator_vote_info = next_account_info(account_info_iter)?;
         // 13. `[]` Stake Authority account (read-only)
-        let stake_authority_info = next_account_info(account_info_iter)?; // <-- ADDED Account #13
+        let stake_authority_info = next_account_info(account_info_iter)?;
+        // Then, in any order: `[writable]` stake record PDA of the stake account (matched
+        // by key), `[writable]` validator list PDA (matched by key, required once the pool
+        // has one) and optionally `[signer, writable]` rent payer for the accounts created
+        // here (the first other signer; the user pays when there is none)
+        let optional_infos = account_info_iter.as_slice();
+        let rent_payer_info = Self::optional_rent_payer(optional_infos).unwrap_or(user_info);
         
         // --- Validation --- 
         // Verify signer
@@ -307,74 +437,102 @@ impl Processor {
             msg!("User signature missing");
             return Err(ProgramError::MissingRequiredSignature);
         }
+        if !rent_payer_info.is_signer {
+            msg!("Rent payer signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_sysvar(stake_history_info, &solana_program::sysvar::stake_history::id())?;
         // Verify account ownerships
-        msg!("Checking ownership of stake_pool_info ({}) -> Expected Owner: {}", stake_pool_info.key, program_id);
-        msg!(" -> Actual Owner: {}", stake_pool_info.owner);
+        // The expected-vs-actual owner logs each base58-encode two pubkeys per line, which
+        // is costly on the stake hot path, so they only build with `debug-logging`.
+        #[cfg(feature = "debug-logging")]
+        {
+            msg!("Checking ownership of stake_pool_info ({}) -> Expected Owner: {}", stake_pool_info.key, program_id);
+            msg!(" -> Actual Owner: {}", stake_pool_info.owner);
+        }
         assert_owned_by(stake_pool_info, program_id)?;
+        assert_rent_exempt(&Rent::get()?, stake_pool_info)?;
 
-        msg!("Checking ownership of pool_mint_info ({}) -> Expected Owner: {}", pool_mint_info.key, spl_token::id());
-        msg!(" -> Actual Owner: {}", pool_mint_info.owner);
+        #[cfg(feature = "debug-logging")]
+        {
+            msg!("Checking ownership of pool_mint_info ({}) -> Expected Owner: {}", pool_mint_info.key, spl_token::id());
+            msg!(" -> Actual Owner: {}", pool_mint_info.owner);
+        }
         assert_owned_by(pool_mint_info, &spl_token::id())?;
+        assert_mint_not_freezable(pool_mint_info)?;
 
-        msg!("Checking ownership of user_token_account_info ({}) -> Expected Owner: {}", user_token_account_info.key, spl_token::id());
-        msg!(" -> Actual Owner: {}", user_token_account_info.owner);
+        #[cfg(feature = "debug-logging")]
+        {
+            msg!("Checking ownership of user_token_account_info ({}) -> Expected Owner: {}", user_token_account_info.key, spl_token::id());
+            msg!(" -> Actual Owner: {}", user_token_account_info.owner);
+        }
         assert_owned_by(user_token_account_info, &spl_token::id())?;
+        // Both receive the pool's signatures via CPI
+        assert_program_id(token_program_info, &spl_token::id())?;
+        assert_program_id(stake_program_info, &solana_program::stake::program::id())?;
 
         // Stake account PDA will be checked/created below
 
         // Load stake pool state
         msg!("Attempting to deserialize StakePool state from account: {}", stake_pool_info.key);
         msg!(" -> Account data length: {}", stake_pool_info.data.borrow().len());
-        let mut stake_pool = StakePool::try_from_slice(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             msg!("Stake pool not initialized");
             return Err(ProgramError::UninitializedAccount);
         }
-        if stake_pool.paused {
-            msg!("Stake pool is paused");
-            return Err(StakePoolError::PoolPaused.into());
+        assert_authority_bumps(program_id, stake_pool_info.key, &stake_pool)?;
+        // Advancing first lets a commission hike it detects hold this deposit too
+        if stake_pool.auto_claim && stake_pool.needs_epoch_update(Clock::from_account_info(clock_info)?.epoch) {
+            stake_pool = Self::advance_pool_epoch(program_id, user_info, stake_pool_info, clock_info, accounts)?.0;
+        }
+        SecurityManager::verify_not_paused(&stake_pool)?;
+        if stake_pool.commission_hold {
+            msg!("Deposits held: validator commission rose to {}%", stake_pool.last_known_commission);
+            return Err(StakePoolError::CommissionHold.into());
         }
-        // Check stake amount against limits
-        if amount < stake_pool.min_stake {
-            msg!("Stake amount below minimum");
+        // Only mint obeSOL through a mint the pool's stake authority controls
+        let pool_mint = spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())?;
+        assert_mint_authority(&pool_mint, &stake_pool.stake_authority)?;
+        if stake_pool.require_ata {
+            assert_associated_token_account(user_info.key, &stake_pool.mint, user_token_account_info)?;
+        }
+        // Check stake amount against limits. The minimum also honours the stake
+        // program's minimum delegation, which can change via feature activation.
+        let min_stake = effective_min_stake(&stake_pool)?;
+        if amount < min_stake {
+            msg!("Stake amount below minimum of {}", min_stake);
             return Err(StakePoolError::StakeTooSmall.into());
         }
         if amount > stake_pool.max_stake {
             msg!("Stake amount above maximum");
             return Err(StakePoolError::StakeTooLarge.into());
         }
-        // Verify the passed validator vote account matches the one in the pool state
-        if *helius_validator_vote_info.key != stake_pool.helius_validator_vote {
-            msg!("Incorrect Helius validator vote account passed");
-            return Err(StakePoolError::InvalidStakeAccountDelegation.into());
-        }
-
-        // --- Calculate Pool Token Amount --- 
-        // Based on current pool ratio (total_staked / total_shares)
-        // Using u128 for intermediate calculations to prevent overflow.
-        let pool_tokens_to_mint = if stake_pool.total_shares == 0 || stake_pool.total_staked == 0 {
-            amount // If pool is empty, 1 SOL = 1 obeSOL (lamport basis)
-        } else {
-            (amount as u128)
-                .checked_mul(stake_pool.total_shares as u128)
-                .ok_or(StakePoolError::MathOverflow)?
-                .checked_div(stake_pool.total_staked as u128)
-                .ok_or(StakePoolError::MathOverflow)?
-                .try_into()
-                .map_err(|_| StakePoolError::MathOverflow)?
+        // Without a validator list the vote account must be the pool's Helius validator;
+        // with one, it may be any listed validator
+        let mut validator_list = match Self::optional_validator_list(program_id, stake_pool_info.key, &stake_pool, optional_infos)? {
+            Some((validator_list_info, validator_list)) => {
+                let validator_index = validator_list.find(validator_vote_info.key).ok_or_else(|| {
+                    msg!("Validator {} is not in the pool's validator list", validator_vote_info.key);
+                    StakePoolError::ValidatorNotInList
+                })?;
+                Some((validator_list_info, validator_list, validator_index))
+            }
+            None => {
+                if *validator_vote_info.key != stake_pool.helius_validator_vote {
+                    msg!("Incorrect Helius validator vote account passed");
+                    return Err(StakePoolError::InvalidStakeAccountDelegation.into());
+                }
+                None
+            }
         };
 
-        if pool_tokens_to_mint == 0 {
-            msg!("Calculated pool tokens to mint is zero");
-            return Err(StakePoolError::CalculationFailure.into());
-        }
-
         // --- Derive Stake Authority PDA --- 
         // This PDA signs for minting tokens and delegating stake.
-        let stake_authority_seeds = &[b"stake_authority", stake_pool_info.key.as_ref(), &[stake_pool.stake_authority_bump_seed]];
+        let stake_authority_seeds = &[STAKE_AUTHORITY_SEED, stake_pool_info.key.as_ref(), &[stake_pool.stake_authority_bump_seed]];
         // Verify derived stake authority PDA matches the one stored in the pool state
         let (expected_stake_authority_pda, _stake_auth_bump) = Pubkey::find_program_address(
-            &[b"stake_authority", stake_pool_info.key.as_ref()],
+            &[STAKE_AUTHORITY_SEED, stake_pool_info.key.as_ref()],
             program_id,
         );
         if expected_stake_authority_pda != stake_pool.stake_authority || expected_stake_authority_pda != *stake_authority_info.key {
@@ -382,15 +540,15 @@ impl Processor {
                  expected_stake_authority_pda, stake_pool.stake_authority, *stake_authority_info.key);
             return Err(StakePoolError::InvalidStakeAuthority.into());
         }
-        // Need the AccountInfo for the PDA to pass to CPIs if it's used as a signer *and* account
-        // However, for invoke_signed, only the seeds are needed if it's just a signer.
-        // We'll derive it again below for the stake account seeds if needed.
+        // The stake authority is a signer meta of delegate_stake and mint_to, so its
+        // AccountInfo must be in those CPIs' account slices; the seeds alone only
+        // supply the signature.
 
         // --- Derive User's Stake Account PDA --- 
         // Seeds: "stake_account", pool_pubkey, user_pubkey, stake_authority_pubkey
         let (stake_account_pda, stake_account_bump) = Pubkey::find_program_address(
             &[
-                b"stake_account",
+                STAKE_ACCOUNT_SEED,
                 stake_pool_info.key.as_ref(),
                 user_info.key.as_ref(),
                 &stake_pool.stake_authority.to_bytes(), // Ensure authority bytes are part of seed
@@ -405,63 +563,63 @@ impl Processor {
             return Err(ProgramError::InvalidSeeds);
         }
         let stake_account_pda_seeds = &[
-            b"stake_account",
+            STAKE_ACCOUNT_SEED,
             stake_pool_info.key.as_ref(),
             user_info.key.as_ref(),
             &stake_pool.stake_authority.to_bytes(),
             &[stake_account_bump]
         ];
+        let (stake_record_info, stake_record_bump) =
+            Self::optional_stake_record(program_id, stake_account_info.key, optional_infos)?;
+
+        // --- Check User Balance ---
+        // Fail up front with the shortfall rather than midway through the CPIs. A
+        // first-time stake also pays the stake account's and the stake record's
+        // rent-exempt reserves, from the rent payer when one is passed, less any lamports
+        // the addresses already hold.
+        let stake_account_created = *stake_account_info.owner != solana_program::system_program::id();
+        let stake_account_rent = if stake_account_created {
+            0
+        } else {
+            Rent::get()?.minimum_balance(STAKE_ACCOUNT_SIZE).saturating_sub(stake_account_info.lamports())
+        };
+        let stake_record_rent = Self::stake_record_rent(program_id, stake_record_info)?;
+        let rent_due = safe_math::add(stake_account_rent, stake_record_rent)?;
+        let separate_rent_payer = rent_payer_info.key != user_info.key;
+        let required_lamports = if separate_rent_payer {
+            amount
+        } else {
+            safe_math::add(amount, rent_due)?
+        };
+        if user_info.lamports() < required_lamports {
+            msg!("Insufficient SOL: need {} lamports ({} stake + {} rent), have {}, short {}",
+                 required_lamports, amount, required_lamports - amount, user_info.lamports(),
+                 required_lamports - user_info.lamports());
+            return Err(StakePoolError::InsufficientBalance.into());
+        }
+        if separate_rent_payer && rent_payer_info.lamports() < rent_due {
+            msg!("Rent payer has {} lamports, needs {} for stake account and stake record rent",
+                 rent_payer_info.lamports(), rent_due);
+            return Err(StakePoolError::InsufficientBalance.into());
+        }
 
         // --- Create or Load Stake Account PDA --- 
-        let rent = Rent::get()?;
-        let stake_account_size = std::mem::size_of::<StakeStateV2>();
-        let required_lamports = rent.minimum_balance(stake_account_size);
-        
-        msg!("Checking if stake account PDA needs creation (lamports == 0)... Stake Account Lamports: {}", stake_account_info.lamports());
-        let stake_account_state = if stake_account_info.lamports() == 0 {
+        // Lamports sent to the address before the account exists leave it system-owned;
+        // it is still created here and they end up delegated with the deposit
+        msg!("Checking if stake account PDA needs creation... Stake Account Lamports: {}", stake_account_info.lamports());
+        let stake_account_state = if !stake_account_created {
             msg!("-> Entering block to CREATE and INITIALIZE stake account PDA.");
-            // PDA doesn't exist, create it using system_instruction::create_account directly.
-            msg!("   Derived Stake Account PDA: {}", stake_account_pda);
-            msg!("   Stake Program ID: {}", stake_program_info.key);
-            msg!("Attempting to create Stake Account PDA via CPI...");
-            invoke_signed(
-                &system_instruction::create_account(
-                    user_info.key,             // Payer
-                    stake_account_info.key,    // Account to create
-                    required_lamports,         // Lamports
-                    stake_account_size as u64, // Space
-                    stake_program_info.key,    // Owner MUST be Stake Program
-                ),
-                &[
-                    user_info.clone(),
-                    stake_account_info.clone(),
-                    system_program_info.clone(),
-                ],
-                &[stake_account_pda_seeds], // Seeds for the PDA account being created
-            )?;
-            msg!("Stake Account PDA created via CPI successfully.");
-
-            // Initialize the stake account using Stake Program CPI (this should now work).
-            msg!("Attempting to initialize Stake Account PDA via CPI...");
-            msg!(" -> Rent Sysvar: {}", rent_info.key);
-            msg!(" -> Stake Config Authority: {}", stake_config_info.key);
-            invoke_signed(
-                &stake_instruction::initialize(
-                    stake_account_info.key, // The PDA we just created
-                    &Authorized {              // Use Authorized struct
-                        staker: stake_pool.stake_authority, // <-- Set Staker to Pool's Authority PDA
-                        withdrawer: *user_info.key,        // <-- Set Withdrawer to User
-                    },
-                    &Lockup::default(),    // No lockup
-                ),
-                &[
-                    stake_account_info.clone(), // The account to initialize
-                    rent_info.clone(),          // Rent sysvar
-                ],
-                &[stake_account_pda_seeds], // Seeds for the PDA account being initialized
+            Self::create_user_stake_account(
+                rent_payer_info,
+                stake_account_info,
+                stake_program_info,
+                system_program_info,
+                rent_info,
+                &stake_pool,
+                stake_account_pda_seeds,
             )?;
-            // Return Default state as it's newly initialized but not delegated
-            StakeStateV2::default() 
+            // Read back the freshly initialized (not yet delegated) state
+            StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..])?
         } else {
             msg!("-> Entering block to LOAD existing stake account PDA.");
             // PDA exists, load its state.
@@ -471,11 +629,55 @@ impl Processor {
             // Check ownership
             assert_owned_by(stake_account_info, stake_program_info.key)?;
             msg!("   Stake account ownership check passed.");
-            // Deserialize state - CORRECTED: Use try_borrow_mut_data and pass &mut slice
-            let mut account_data = stake_account_info.try_borrow_mut_data()?;
-            StakeStateV2::deserialize(&mut &account_data[..])? // Create slice and pass mut ref
+            // An account created with another size (e.g. by an older program
+            // version) must not be deserialized as if it had this layout.
+            if stake_account_info.data_len() != STAKE_ACCOUNT_SIZE {
+                msg!("Stake account data length {} does not match expected {}",
+                     stake_account_info.data_len(), STAKE_ACCOUNT_SIZE);
+                return Err(StakePoolError::StakeAccountSizeMismatch.into());
+            }
+            let account_data = stake_account_info.try_borrow_data()?;
+            StakeStateV2::deserialize(&mut &account_data[..])?
+        };
+
+        // An existing account may be Initialized but never delegated (created by
+        // PrepareStakeAccount); creation is skipped and it is delegated below. Tokens are
+        // only minted after delegation succeeds, so nothing is credited for an
+        // undelegated account.
+        // A delegated account can't take a deposit. The stake program refuses to
+        // delegate active stake again (TooSoonToRedelegate); for deactivating stake,
+        // delegating only cancels the deactivation without delegating the new lamports;
+        // and deactivated stake belongs to an unstake awaiting WithdrawStake.
+        let rent_exempt_reserve = match stake_account_state {
+            StakeStateV2::Initialized(meta) => {
+                msg!("Stake account is initialized but not delegated; delegating deposit");
+                meta.rent_exempt_reserve
+            }
+            StakeStateV2::Stake(_, stake, _) if stake.delegation.deactivation_epoch == u64::MAX => {
+                msg!("Stake account already has {} lamports delegated to {}; it cannot take another deposit",
+                     stake.delegation.stake, stake.delegation.voter_pubkey);
+                return Err(StakePoolError::StakeAccountAlreadyDelegated.into());
+            }
+            StakeStateV2::Stake(_, stake, _) => {
+                msg!("Stake account was unstaked in epoch {}; withdraw it with WithdrawStake before staking again",
+                     stake.delegation.deactivation_epoch);
+                return Err(StakePoolError::StakeAccountAlreadyDelegated.into());
+            }
+            _ => {
+                msg!("Stake account is not initialized");
+                return Err(StakePoolError::WrongStakeState.into());
+            }
         };
 
+        if let Some((_, validator_list, validator_index)) = &validator_list {
+            SecurityManager::verify_validator_stake_limit(
+                validator_list,
+                *validator_index,
+                amount,
+                stake_pool.validator_stake_cap_bps(),
+            )?;
+        }
+
         // --- CPI: Transfer SOL --- 
         // Transfer user's SOL to the derived stake account PDA.
         msg!("Transferring {} lamports from user to stake account PDA", amount);
@@ -490,29 +692,56 @@ impl Processor {
                 stake_account_info.clone(),
                 system_program_info.clone(),
             ]
-        )?;
+        ).map_err(cpi_step("transfer"))?;
+
+        // --- Validate Delegatable Balance ---
+        // delegate_stake delegates everything above the rent-exempt reserve. That must
+        // cover the deposit; anything more was sent to the stake account by someone else,
+        // and is delegated and credited along with the deposit so the pool's accounting
+        // matches what is actually staked.
+        let delegatable_lamports = safe_math::sub(stake_account_info.lamports(), rent_exempt_reserve)
+            .map_err(|_| StakePoolError::DelegationAmountMismatch)?;
+        if delegatable_lamports < amount {
+            msg!("Delegatable lamports {} are below stake amount {}", delegatable_lamports, amount);
+            return Err(StakePoolError::DelegationAmountMismatch.into());
+        }
+        if delegatable_lamports > amount {
+            msg!("Stake account already held {} lamports; delegating and crediting them with the deposit",
+                 delegatable_lamports - amount);
+        }
+
+        // --- Calculate Pool Token Amount --- 
+        // Based on current pool ratio (total_staked / total_shares)
+        // Using u128 for intermediate calculations to prevent overflow.
+        // If pool is empty, 1 SOL = 1 obeSOL (lamport basis)
+        let pool_tokens_to_mint = stake_pool.lamports_to_tokens(delegatable_lamports)?;
+
+        if pool_tokens_to_mint == 0 {
+            msg!("Calculated pool tokens to mint is zero");
+            return Err(StakePoolError::CalculationFailure.into());
+        }
 
         // --- CPI: Delegate Stake --- 
-        // Delegate the stake account to the Helius validator.
+        // Delegate the stake account to the validator checked above.
         // Requires the stake_authority PDA to sign.
-        msg!("Delegating stake account PDA to validator {}", stake_pool.helius_validator_vote);
+        msg!("Delegating stake account PDA to validator {}", validator_vote_info.key);
         invoke_signed(
             &stake_instruction::delegate_stake(
                 stake_account_info.key, 
                 &stake_pool.stake_authority, // Authority PDA pubkey for instruction data
-                &stake_pool.helius_validator_vote, 
+                validator_vote_info.key, 
             ),
             &[
                 stake_program_info.clone(),         // Stake Program
                 stake_account_info.clone(),         // Stake Account to delegate
-                helius_validator_vote_info.clone(), // Validator Vote Acc
+                validator_vote_info.clone(),        // Validator Vote Acc
                 clock_info.clone(),                 // Clock Sysvar
                 stake_history_info.clone(),         // Stake History Sysvar
                 stake_config_info.clone(),          // Stake Config Acc
                 stake_authority_info.clone(),       // Stake Authority Acc <-- ADDED
             ],
             &[stake_authority_seeds] // Sign with stake_authority PDA seeds
-        )?;
+        ).map_err(cpi_step("delegate_stake"))?;
 
         // --- CPI: Mint Pool Tokens --- 
         msg!("Minting {} obeSOL tokens to user {}", pool_tokens_to_mint, user_token_account_info.key);
@@ -532,24 +761,170 @@ impl Processor {
                 stake_authority_info.clone(),   // Mint Authority Account <-- ADDED
             ],
             &[stake_authority_seeds] // Sign with stake_authority PDA seeds
+        ).map_err(cpi_step("mint_to"))?;
+
+        // --- Record The Minted Shares ---
+        // The account was undelegated until now, so this deposit's tokens are all of them
+        let stake_record = StakeRecord {
+            version: 1,
+            stake_pool: *stake_pool_info.key,
+            stake_account: *stake_account_info.key,
+            pool_tokens: pool_tokens_to_mint,
+            pending_withdrawal: 0,
+        };
+        Self::write_stake_record(
+            program_id,
+            stake_record_info,
+            stake_record_bump,
+            rent_info,
+            system_program_info,
+            rent_payer_info,
+            &stake_record,
         )?;
 
         // --- Update Stake Pool State --- 
-        stake_pool.total_staked = stake_pool.total_staked
-            .checked_add(amount)
-            .ok_or(StakePoolError::MathOverflow)?;
-        stake_pool.total_shares = stake_pool.total_shares
-            .checked_add(pool_tokens_to_mint)
-            .ok_or(StakePoolError::MathOverflow)?;
+        stake_pool.total_staked = safe_math::add(stake_pool.total_staked, delegatable_lamports)?;
+        stake_pool.total_shares = safe_math::add(stake_pool.total_shares, pool_tokens_to_mint)?;
 
         msg!("Updating stake pool state: total_staked={}, total_shares={}", 
             stake_pool.total_staked, stake_pool.total_shares);
-        stake_pool.serialize(&mut *stake_pool_info.data.borrow_mut())?;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        if let Some((validator_list_info, validator_list, validator_index)) = validator_list.as_mut() {
+            let validator = &mut validator_list.validators[*validator_index];
+            validator.active_stake_lamports = safe_math::add(validator.active_stake_lamports, delegatable_lamports)?;
+            validator_list.pack_account(&mut validator_list_info.data.borrow_mut())?;
+        }
 
         msg!("Stake processing complete.");
         Ok(())
     }
 
+    /// Creates the user's stake account PDA and initializes it with the pool's
+    /// stake authority as staker and its withdraw authority as withdrawer, so the
+    /// stake can only leave through `Unstake` and `WithdrawStake`. `payer_info` funds
+    /// the rent-exempt reserve. The account is left undelegated.
+    fn create_user_stake_account<'a>(
+        payer_info: &AccountInfo<'a>,
+        stake_account_info: &AccountInfo<'a>,
+        stake_program_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        rent_info: &AccountInfo<'a>,
+        stake_pool: &StakePool,
+        stake_account_pda_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        // Also handles an address that already holds lamports, which create_account
+        // would refuse, by topping it up, allocating and assigning it instead
+        msg!("   Derived Stake Account PDA: {}", stake_account_info.key);
+        msg!("   Stake Program ID: {}", stake_program_info.key);
+        msg!("Attempting to create Stake Account PDA via CPI...");
+        create_or_allocate_account_raw(
+            stake_program_info.key, // Owner MUST be Stake Program
+            stake_account_info,
+            rent_info,
+            system_program_info,
+            payer_info,
+            STAKE_ACCOUNT_SIZE,
+            stake_account_pda_seeds,
+        )?;
+        msg!("Stake Account PDA created via CPI successfully.");
+
+        // Initialize the stake account using Stake Program CPI. Initialize has no
+        // signer metas, so no seeds are needed.
+        msg!("Attempting to initialize Stake Account PDA via CPI...");
+        msg!(" -> Rent Sysvar: {}", rent_info.key);
+        invoke(
+            &stake_instruction::initialize(
+                stake_account_info.key, // The PDA we just created
+                &Authorized {              // Use Authorized struct
+                    staker: stake_pool.stake_authority, // <-- Set Staker to Pool's Authority PDA
+                    withdrawer: stake_pool.withdraw_authority, // <-- Set Withdrawer to Pool's Withdraw Authority PDA
+                },
+                &Lockup::default(),    // No lockup
+            ),
+            &[
+                stake_account_info.clone(), // The account to initialize
+                rent_info.clone(),          // Rent sysvar
+            ],
+        ).map_err(cpi_step("stake_initialize"))?;
+        Ok(())
+    }
+
+    /// Creates and initializes a user's stake account PDA ahead of their first stake,
+    /// so the subsequent `Stake` only has to transfer, delegate and mint.
+    fn process_prepare_stake_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        msg!("Processing Prepare Stake Account");
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer, writable]` User account (pays rent)
+        let user_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[writable]` Stake account (derived from user & pool)
+        let stake_account_info = next_account_info(account_info_iter)?;
+        // 3. `[]` Stake program id
+        let stake_program_info = next_account_info(account_info_iter)?;
+        // 4. `[]` System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+        // 5. `[]` Rent sysvar
+        let rent_info = next_account_info(account_info_iter)?;
+
+        if !user_info.is_signer {
+            msg!("User signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_not_paused(&stake_pool)?;
+        assert_program_id(stake_program_info, &solana_program::stake::program::id())?;
+
+        let (stake_account_pda, stake_account_bump) = Pubkey::find_program_address(
+            &[
+                STAKE_ACCOUNT_SEED,
+                stake_pool_info.key.as_ref(),
+                user_info.key.as_ref(),
+                &stake_pool.stake_authority.to_bytes(),
+            ],
+            program_id
+        );
+        if stake_account_pda != *stake_account_info.key {
+            msg!("Provided stake account {} does not match derived PDA {}", stake_account_info.key, stake_account_pda);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if *stake_account_info.owner != solana_program::system_program::id() {
+            msg!("Stake account {} already exists", stake_account_info.key);
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        let stake_account_pda_seeds = &[
+            STAKE_ACCOUNT_SEED,
+            stake_pool_info.key.as_ref(),
+            user_info.key.as_ref(),
+            &stake_pool.stake_authority.to_bytes(),
+            &[stake_account_bump]
+        ];
+
+        Self::create_user_stake_account(
+            user_info,
+            stake_account_info,
+            stake_program_info,
+            system_program_info,
+            rent_info,
+            &stake_pool,
+            stake_account_pda_seeds,
+        )?;
+
+        msg!("Stake account prepared.");
+        Ok(())
+    }
+
     /// Processes a user's request to unstake (burn obeSOL tokens).
     /// This is the first step of a two-step process due to stake deactivation cooldown.
     fn process_unstake(
@@ -560,7 +935,7 @@ impl Processor {
         msg!("Processing Unstake: Burning {} pool tokens", pool_token_amount);
         let account_info_iter = &mut accounts.iter();
 
-        // 0. `[signer]` User account (signer, authority for token burn)
+        // 0. `[signer, writable]` User account (authority for token burn, pays split account rent)
         let user_info = next_account_info(account_info_iter)?;
         // 1. `[writable]` Stake pool
         let stake_pool_info = next_account_info(account_info_iter)?;
@@ -576,7 +951,13 @@ impl Processor {
         let stake_program_info = next_account_info(account_info_iter)?;
         // 7. `[]` Clock sysvar
         let clock_info = next_account_info(account_info_iter)?;
-        // (Implicit) Stake pool withdraw authority PDA (used for signing burn/deactivate)
+        // 8. `[]` Stake authority PDA (signs the deactivation)
+        let stake_authority_info = next_account_info(account_info_iter)?;
+        // Then, in any order: `[writable]` stake record PDA of the stake account,
+        // `[writable]` validator list PDA (required once the pool has one), and for a
+        // partial unstake `[writable]` split stake PDA, `[writable]` its stake record PDA,
+        // `[]` system program id and `[]` rent sysvar
+        let optional_infos = account_info_iter.as_slice();
 
         // Basic checks
         if !user_info.is_signer {
@@ -584,20 +965,28 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
         assert_owned_by(stake_pool_info, program_id)?;
+        assert_rent_exempt(&Rent::get()?, stake_pool_info)?;
         assert_owned_by(pool_mint_info, &spl_token::id())?;
+        assert_mint_not_freezable(pool_mint_info)?;
         assert_owned_by(user_token_account_info, &spl_token::id())?;
-        // Stake account ownership is checked by stake program CPI
+        assert_owned_by(stake_account_info, &solana_program::stake::program::id())?;
+        assert_program_id(token_program_info, &spl_token::id())?;
+        assert_program_id(stake_program_info, &solana_program::stake::program::id())?;
 
         // Load stake pool state
-        let mut stake_pool = StakePool::try_from_slice(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             msg!("Stake pool not initialized");
             return Err(ProgramError::UninitializedAccount);
         }
-        if stake_pool.paused {
-            msg!("Stake pool is paused");
-            return Err(StakePoolError::PoolPaused.into());
+        assert_authority_bumps(program_id, stake_pool_info.key, &stake_pool)?;
+        if stake_pool.auto_claim && stake_pool.needs_epoch_update(Clock::from_account_info(clock_info)?.epoch) {
+            stake_pool = Self::advance_pool_epoch(program_id, user_info, stake_pool_info, clock_info, accounts)?.0;
         }
+        SecurityManager::verify_not_paused(&stake_pool)?;
+        let validator_list = Self::optional_validator_list(program_id, stake_pool_info.key, &stake_pool, optional_infos)?;
+        let (stake_record_info, _) = Self::optional_stake_record(program_id, stake_account_info.key, optional_infos)?;
+        let mut stake_record = Self::load_stake_record(program_id, stake_account_info.key, stake_record_info)?;
 
         // Check pool token amount
         if pool_token_amount == 0 {
@@ -607,20 +996,90 @@ impl Processor {
         // --- Share to SOL Calculation --- 
         // Calculate the proportional amount of SOL the user *should* receive back
         // based on the current pool ratio. This SOL is not transferred yet.
-        let sol_to_withdraw = if stake_pool.total_shares > 0 && stake_pool.total_staked > 0 {
-            (pool_token_amount as u128)
-                .checked_mul(stake_pool.total_staked as u128)
-                .ok_or(StakePoolError::MathOverflow)?
-                .checked_div(stake_pool.total_shares as u128)
-                .ok_or(StakePoolError::MathOverflow)?
-                .try_into()
-                .map_err(|_| StakePoolError::MathOverflow)?
-        } else {
-            // Should not happen if pool_token_amount > 0 and tokens exist, but handle defensively
-            0 
-        };
+        // An empty pool values tokens at 0, which is rejected just below
+        let sol_to_withdraw = stake_pool.tokens_to_lamports(pool_token_amount)?;
         msg!("Calculated SOL to withdraw (deferred): {}", sol_to_withdraw);
 
+        // Rounding can leave a dust unstake worth nothing; refuse rather than burn tokens for zero SOL
+        if sol_to_withdraw == 0 {
+            msg!("Unstake of {} tokens yields 0 lamports; unstake a larger amount", pool_token_amount);
+            return Err(StakePoolError::OutputBelowMinimum.into());
+        }
+
+        // --- Per-Epoch Unstake Limit --- 
+        let current_epoch = Clock::from_account_info(clock_info)?.epoch;
+        let unstaked_this_epoch = safe_math::add(stake_pool.unstaked_in_epoch(current_epoch), sol_to_withdraw)?;
+        if stake_pool.max_unstake_per_epoch != 0 && unstaked_this_epoch > stake_pool.max_unstake_per_epoch {
+            msg!("Unstake of {} lamports exceeds epoch {} limit ({} of {} used); try again next epoch",
+                 sol_to_withdraw, current_epoch, stake_pool.unstaked_in_epoch(current_epoch),
+                 stake_pool.max_unstake_per_epoch);
+            return Err(StakePoolError::UnstakeLimitReached.into());
+        }
+
+        // --- Check Stake Account State ---
+        // Only a delegated, still-active account can be deactivated. Anything else would
+        // only fail inside the stake program, with an opaque error.
+        let (delegated_stake, validator) = match StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..])? {
+            StakeStateV2::Stake(_, stake, _) if stake.delegation.deactivation_epoch == u64::MAX => {
+                (stake.delegation.stake, stake.delegation.voter_pubkey)
+            }
+            StakeStateV2::Stake(_, stake, _) => {
+                msg!("Stake account is already deactivating (since epoch {})", stake.delegation.deactivation_epoch);
+                return Err(StakePoolError::WrongStakeState.into());
+            }
+            StakeStateV2::Initialized(_) => {
+                // No tokens are minted until delegation succeeds, so there is nothing to unstake
+                msg!("Stake account was never delegated; nothing to unstake");
+                return Err(StakePoolError::WrongStakeState.into());
+            }
+            StakeStateV2::Uninitialized | StakeStateV2::RewardsPool => {
+                msg!("Stake account is not initialized");
+                return Err(StakePoolError::WrongStakeState.into());
+            }
+        };
+
+        // --- Full or Partial ---
+        // Burning every pool token minted against the account deactivates all of it. Its
+        // delegation has grown by the rewards it earned, which the pool ratio never books,
+        // so the tokens' value alone would split off less and strand those rewards. A
+        // smaller burn splits off just its SOL value, and both the split-off and the
+        // remaining stake must stay at or above the minimum delegation. An account staked
+        // before stake records existed is fully deactivated only when the burn is worth
+        // all of its stake. Either way `sol_to_withdraw` leaves the pool's stake, and the
+        // stake deactivated moves to the pending withdrawals.
+        let partial = match &stake_record {
+            Some(record) => {
+                if pool_token_amount > record.pool_tokens {
+                    msg!("Burning {} pool tokens exceeds the {} minted against this stake account",
+                         pool_token_amount, record.pool_tokens);
+                    return Err(StakePoolError::UnstakeExceedsStake.into());
+                }
+                pool_token_amount < record.pool_tokens
+            }
+            None => sol_to_withdraw < delegated_stake,
+        };
+        if (partial && sol_to_withdraw >= delegated_stake)
+            || (stake_record.is_none() && sol_to_withdraw > delegated_stake)
+        {
+            msg!("Unstaking {} lamports exceeds the {} lamports delegated from this stake account",
+                 sol_to_withdraw, delegated_stake);
+            return Err(StakePoolError::UnstakeExceedsStake.into());
+        }
+        if partial {
+            let minimum_delegation = solana_program::stake::tools::get_minimum_delegation()?;
+            if sol_to_withdraw < minimum_delegation {
+                msg!("Unstaking {} lamports would split off less than the minimum delegation of {}",
+                     sol_to_withdraw, minimum_delegation);
+                return Err(StakePoolError::PartialUnstakeBelowMinimum.into());
+            }
+            let remaining_stake = safe_math::sub(delegated_stake, sol_to_withdraw)?;
+            if remaining_stake < minimum_delegation {
+                msg!("Unstaking {} of {} lamports would leave less than the minimum delegation of {}",
+                     sol_to_withdraw, delegated_stake, minimum_delegation);
+                return Err(StakePoolError::PartialUnstakeBelowMinimum.into());
+            }
+        }
+
         // --- CPI: Burn Pool Tokens --- 
         // Burns the specified amount of obeSOL tokens from the user's token account.
         // The user signs as the authority to burn their own tokens.
@@ -640,27 +1099,29 @@ impl Processor {
                 pool_mint_info.clone(),
                 user_info.clone(),
             ]
-        )?;
+        ).map_err(cpi_step("burn"))?;
 
         // --- CPI: Deactivate Stake Account --- 
         // Initiates the deactivation of the user's stake account PDA via the Stake program.
         // The stake account must be fully deactivated before SOL can be withdrawn.
         // Requires the stake_authority PDA (derived from pool) to sign.
         // First, derive the stake_authority PDA and its seeds using the stored bump.
-        let stake_authority_seeds = &[b"stake_authority", stake_pool_info.key.as_ref(), &[stake_pool.stake_authority_bump_seed]]; // Use stored bump
+        let stake_authority_seeds = &[STAKE_AUTHORITY_SEED, stake_pool_info.key.as_ref(), &[stake_pool.stake_authority_bump_seed]]; // Use stored bump
         // Verify derived stake authority matches the one stored in the pool state.
         let (stake_authority_pda, _stake_auth_bump) = Pubkey::find_program_address(
-            &[b"stake_authority", stake_pool_info.key.as_ref()],
+            &[STAKE_AUTHORITY_SEED, stake_pool_info.key.as_ref()],
             program_id,
         );
-        if stake_authority_pda != stake_pool.stake_authority {
-             return Err(StakePoolError::InvalidStakeAuthority.into());
+        if stake_authority_pda != stake_pool.stake_authority || stake_authority_pda != *stake_authority_info.key {
+            msg!("Stake Authority PDA mismatch. Expected {}, Pool {}, Passed {}",
+                 stake_authority_pda, stake_pool.stake_authority, *stake_authority_info.key);
+            return Err(StakePoolError::InvalidStakeAuthority.into());
         }
         
         // Derive the expected user stake account PDA to confirm the correct account was passed.
         let (expected_stake_pda, _stake_pda_bump) = Pubkey::find_program_address(
             &[
-                b"stake_account",
+                STAKE_ACCOUNT_SEED,
                 stake_pool_info.key.as_ref(),
                 user_info.key.as_ref(),
                 &stake_pool.stake_authority.to_bytes(), // CORRECTED: Include authority in seeds
@@ -672,61 +1133,232 @@ impl Processor {
             return Err(ProgramError::InvalidSeeds);
         }
 
+        // --- Split Off The Unstaked SOL (Partial Unstake) ---
+        let deactivate_info = if partial {
+            Self::split_user_stake(
+                program_id,
+                stake_pool_info.key,
+                &stake_pool,
+                user_info,
+                stake_account_info,
+                stake_authority_info,
+                stake_program_info,
+                optional_infos,
+                current_epoch,
+                sol_to_withdraw,
+            )?
+        } else {
+            stake_account_info
+        };
+
         // Authority for deactivation is the stake_pool.stake_authority PDA
-        msg!("Deactivating stake account");
+        msg!("Deactivating stake account {}", deactivate_info.key);
         invoke_signed(
             &stake_instruction::deactivate_stake(
-                stake_account_info.key,
+                deactivate_info.key,
                 &stake_pool.stake_authority, // The PDA is the authority
             ),
             &[
                 stake_program_info.clone(),
-                stake_account_info.clone(),
+                deactivate_info.clone(),
                 clock_info.clone(),
-                // The authority account_info needs to be passed if it exists separate from pool
-                // If it's just a PDA derived from pool, it doesn't need separate AccountInfo
+                stake_authority_info.clone(), // Authority account must be present to sign
             ],
             &[stake_authority_seeds], // Sign with the PDA authority seeds
-        )?;
+        ).map_err(cpi_step("deactivate_stake"))?;
 
         // --- Update Stake Pool State --- 
-        stake_pool.total_staked = stake_pool.total_staked
-            .checked_sub(sol_to_withdraw)
-            .ok_or(StakePoolError::MathOverflow)?;
-        stake_pool.total_shares = stake_pool.total_shares
-            .checked_sub(pool_token_amount)
-            .ok_or(StakePoolError::MathOverflow)?;
-
-        // TODO: Potentially record unstake request info (e.g., epoch, amount)
-        // in a separate account or modify stake_pool state if needed for later withdrawal claim.
+        // A split deactivates exactly `sol_to_withdraw`; a full unstake the whole
+        // delegation, including rewards the pool ratio never booked. The stake record of
+        // the deactivated account keeps the amount for `WithdrawStake` to take back off.
+        let deactivated_lamports = if partial { sol_to_withdraw } else { delegated_stake };
+        stake_pool.total_staked = safe_math::sub(stake_pool.total_staked, sol_to_withdraw)?;
+        stake_pool.total_shares = safe_math::sub(stake_pool.total_shares, pool_token_amount)?;
+        stake_pool.unstaked_this_epoch = unstaked_this_epoch;
+        stake_pool.unstake_tracking_epoch = current_epoch;
+        stake_pool.total_pending_withdrawal =
+            safe_math::add(stake_pool.total_pending_withdrawal, deactivated_lamports)?;
 
         msg!("Updating stake pool state");
-        stake_pool.serialize(&mut *stake_pool_info.data.borrow_mut())?;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        if let Some(record) = stake_record.as_mut() {
+            record.pool_tokens = safe_math::sub(record.pool_tokens, pool_token_amount)?;
+            if !partial {
+                record.pending_withdrawal = deactivated_lamports;
+            }
+            record.pack_account(&mut stake_record_info.data.borrow_mut())?;
+        }
+
+        // The delegated stake includes rewards the list never counted, hence saturating
+        if let Some((validator_list_info, mut validator_list)) = validator_list {
+            match validator_list.find(&validator) {
+                Some(validator_index) => {
+                    let entry = &mut validator_list.validators[validator_index];
+                    entry.active_stake_lamports = entry.active_stake_lamports.saturating_sub(sol_to_withdraw);
+                    validator_list.pack_account(&mut validator_list_info.data.borrow_mut())?;
+                }
+                None => msg!("Validator {} is not in the validator list; nothing to update", validator),
+            }
+        }
 
         msg!("Unstake processing complete. User must wait for cooldown and call withdraw instruction.");
         Ok(())
     }
 
-    /// Processes reward epoch updates. (Simplified)
-    /// NOTE: In this simplified model, rewards are not actively calculated or distributed here.
-    /// Rewards accrue implicitly in the underlying stake accounts, increasing the value 
-    /// of each obeSOL pool token over time. This instruction only marks the epoch as processed.
+    /// Moves `lamports` of the user's delegated stake into a new split stake PDA for
+    /// `epoch`, which the caller then deactivates, and gives it a stake record holding
+    /// `lamports` as pending withdrawal. The user funds both accounts' rent-exempt
+    /// reserves, the split account's first, so exactly `lamports` of stake moves.
+    #[allow(clippy::too_many_arguments)]
+    fn split_user_stake<'a, 'b>(
+        program_id: &Pubkey,
+        stake_pool_key: &Pubkey,
+        stake_pool: &StakePool,
+        user_info: &'b AccountInfo<'a>,
+        stake_account_info: &'b AccountInfo<'a>,
+        stake_authority_info: &'b AccountInfo<'a>,
+        stake_program_info: &'b AccountInfo<'a>,
+        optional_infos: &'b [AccountInfo<'a>],
+        epoch: u64,
+        lamports: u64,
+    ) -> Result<&'b AccountInfo<'a>, ProgramError> {
+        let find_optional = |key: &Pubkey| {
+            optional_infos.iter().find(|info| info.key == key).ok_or_else(|| {
+                msg!("A partial unstake needs account {}", key);
+                ProgramError::NotEnoughAccountKeys
+            })
+        };
+        let epoch_bytes = epoch.to_le_bytes();
+        let (split_stake_pda, split_stake_bump) = Pubkey::find_program_address(
+            &[SPLIT_STAKE_SEED, stake_pool_key.as_ref(), user_info.key.as_ref(), &epoch_bytes],
+            program_id,
+        );
+        let split_stake_info = find_optional(&split_stake_pda)?;
+        let (split_record_info, split_record_bump) =
+            Self::optional_stake_record(program_id, &split_stake_pda, optional_infos)?;
+        let system_program_info = find_optional(&solana_program::system_program::id())?;
+        let rent_info = find_optional(&solana_program::sysvar::rent::id())?;
+
+        if *split_stake_info.owner == solana_program::stake::program::id() {
+            msg!("Already unstaked part of this stake account in epoch {}; try again next epoch", epoch);
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        create_or_allocate_account_raw(
+            &solana_program::stake::program::id(),
+            split_stake_info,
+            rent_info,
+            system_program_info,
+            user_info,
+            STAKE_ACCOUNT_SIZE,
+            &[SPLIT_STAKE_SEED, stake_pool_key.as_ref(), user_info.key.as_ref(), &epoch_bytes, &[split_stake_bump]],
+        )?;
+
+        // Only the split itself; the account was created above, so the allocate and
+        // assign that `split` also returns are not needed
+        let split_instructions = stake_instruction::split(
+            stake_account_info.key,
+            &stake_pool.stake_authority,
+            lamports,
+            split_stake_info.key,
+        );
+        let split_instruction = split_instructions.last().ok_or(StakePoolError::CalculationFailure)?;
+        msg!("Splitting {} lamports into {}", lamports, split_stake_info.key);
+        invoke_signed(
+            split_instruction,
+            &[
+                stake_program_info.clone(),
+                stake_account_info.clone(),
+                split_stake_info.clone(),
+                stake_authority_info.clone(),
+            ],
+            &[&[STAKE_AUTHORITY_SEED, stake_pool_key.as_ref(), &[stake_pool.stake_authority_bump_seed]]],
+        ).map_err(cpi_step("stake_split"))?;
+
+        let split_record = StakeRecord {
+            version: 1,
+            stake_pool: *stake_pool_key,
+            stake_account: split_stake_pda,
+            pool_tokens: 0,
+            pending_withdrawal: lamports,
+        };
+        Self::write_stake_record(
+            program_id,
+            split_record_info,
+            split_record_bump,
+            rent_info,
+            system_program_info,
+            user_info,
+            &split_record,
+        )?;
+        Ok(split_stake_info)
+    }
+
+    /// Processes a user's request to unstake their entire obeSOL balance.
+    /// Reads the balance from the user token account and delegates to `process_unstake`.
+    fn process_unstake_all(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_tokens: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let user_info = next_account_info(account_info_iter)?;
+        let _stake_pool_info = next_account_info(account_info_iter)?;
+        let user_token_account_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(user_token_account_info, &spl_token::id())?;
+        let user_token_account = spl_token::state::Account::unpack(&user_token_account_info.data.borrow())?;
+        if user_token_account.owner != *user_info.key {
+            msg!("User token account is not owned by the user");
+            return Err(StakePoolError::InvalidOwner.into());
+        }
+
+        let balance = user_token_account.amount;
+        if balance < min_tokens {
+            msg!("Token balance {} is below expected minimum {}", balance, min_tokens);
+            return Err(StakePoolError::InsufficientBalance.into());
+        }
+        msg!("Unstaking full balance of {} pool tokens", balance);
+        Self::process_unstake(program_id, accounts, balance)
+    }
+
+    /// Processes reward epoch updates. (Simplified)
+    /// NOTE: In this simplified model, rewards are not actively calculated or distributed here.
+    /// Rewards accrue implicitly in the underlying stake accounts, increasing the value 
+    /// of each obeSOL pool token over time. This instruction only marks the epoch as processed.
     fn process_claim_rewards(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         
-        // Accounts needed: Signer, Stake Pool, Clock
-        let user_info = next_account_info(account_info_iter)?; // Signer who triggers the epoch update
-        let stake_pool_info = next_account_info(account_info_iter)?;
-        // let user_token_account_info = next_account_info(account_info_iter)?;
-        // let pool_mint_info = next_account_info(account_info_iter)?;
-        // let treasury_fee_account = next_account_info(account_info_iter)?;
-        // let stake_account_info = next_account_info(account_info_iter)?;
-        // let token_program_info = next_account_info(account_info_iter)?;
+        // 0. `[signer]` User account (who triggers the update)
+        let user_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Clock sysvar
         let clock_info = next_account_info(account_info_iter)?;
+        // 3./4. (Optional) Pool history PDA and/or validator vote account, in any order
+        let optional_infos = account_info_iter.as_slice();
+
+        let (_stake_pool, status) =
+            Self::advance_pool_epoch(program_id, user_info, stake_pool_info, clock_info, optional_infos)?;
+        set_return_data(&status.try_to_vec()?);
+        Ok(())
+    }
 
+    /// Marks the current epoch as processed on the pool (at most once per epoch).
+    /// Among `optional_infos`, matched by key, the pool's validator vote account has
+    /// its commission checked and the pool history PDA receives a snapshot; any other
+    /// account is ignored. Shared by `ClaimRewards` and `ClaimAndQuery`; returns the
+    /// updated pool and what happened.
+    fn advance_pool_epoch(
+        program_id: &Pubkey,
+        user_info: &AccountInfo,
+        stake_pool_info: &AccountInfo,
+        clock_info: &AccountInfo,
+        optional_infos: &[AccountInfo],
+    ) -> Result<(StakePool, ClaimStatus), ProgramError> {
         // Verify signer
         if !user_info.is_signer {
             // Allow anyone to trigger epoch update? Or restrict to pool authority?
@@ -734,9 +1366,10 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
         assert_owned_by(stake_pool_info, program_id)?;
+        assert_rent_exempt(&Rent::get()?, stake_pool_info)?;
 
         // Load stake pool and validate
-        let mut stake_pool = StakePool::try_from_slice(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             msg!("Stake pool not initialized");
             return Err(ProgramError::UninitializedAccount);
@@ -750,10 +1383,11 @@ impl Processor {
         let clock = Clock::from_account_info(clock_info)?;
         let current_epoch = clock.epoch;
 
-        // Ensure we haven't already claimed rewards this epoch
-        if stake_pool.last_update_epoch >= current_epoch {
+        // At most one advance per epoch: a second call in the same epoch is a no-op
+        if !stake_pool.needs_epoch_update(current_epoch) {
             msg!("Pool epoch {} already processed.", current_epoch);
-            return Ok(()); // Not an error, just nothing to do
+            // Not an error, but tell crankers the call did no work
+            return Ok((stake_pool, ClaimStatus { already_processed: true, current_epoch }));
         }
 
         // --- Reward Calculation Removed --- 
@@ -761,13 +1395,75 @@ impl Processor {
         // This instruction now only serves to mark the epoch as processed.
         msg!("Updating pool last processed epoch.");
         
-        // Update only the epoch marker
+        let (history_address, _) = Pubkey::find_program_address(
+            &[HISTORY_SEED, stake_pool_info.key.as_ref()],
+            program_id,
+        );
+        let vote_info = optional_infos.iter().find(|info| *info.key == stake_pool.helius_validator_vote);
+        let history_info = optional_infos.iter().find(|info| *info.key == history_address);
+        if let Some(vote_info) = vote_info {
+            Self::check_commission(&mut stake_pool, vote_info)?;
+        }
+
+        // Update only the epoch marker. All epoch bookkeeping must be written together
+        // with this so a repeat call cannot observe a half-processed epoch.
         stake_pool.last_update_epoch = current_epoch;
 
         // Save state
-        stake_pool.serialize(&mut *stake_pool_info.data.borrow_mut())?;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        if let Some(history_info) = history_info {
+            Self::record_history(program_id, stake_pool_info.key, &stake_pool, history_info, current_epoch)?;
+        }
 
         msg!("Pool epoch updated to {}", current_epoch);
+        Ok((stake_pool, ClaimStatus { already_processed: false, current_epoch }))
+    }
+
+    /// Advances the pool epoch like `ClaimRewards`, then returns the signer's pool
+    /// token balance and its SOL value at the updated pool ratio.
+    fn process_claim_and_query(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` User account
+        let user_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Clock sysvar
+        let clock_info = next_account_info(account_info_iter)?;
+        // 3. `[]` User's pool token account
+        let user_token_account_info = next_account_info(account_info_iter)?;
+        // 4./5. (Optional) Pool history PDA and/or validator vote account, in any order
+        let optional_infos = account_info_iter.as_slice();
+
+        let (stake_pool, status) =
+            Self::advance_pool_epoch(program_id, user_info, stake_pool_info, clock_info, optional_infos)?;
+
+        assert_owned_by(user_token_account_info, &spl_token::id())?;
+        let user_token_account = spl_token::state::Account::unpack(&user_token_account_info.data.borrow())?;
+        if user_token_account.owner != *user_info.key {
+            msg!("Token account is not owned by the signer");
+            return Err(StakePoolError::InvalidOwner.into());
+        }
+        if user_token_account.mint != stake_pool.mint {
+            msg!("Token account is not for the pool mint");
+            return Err(StakePoolError::InvalidMintAuthority.into());
+        }
+
+        let pool_tokens = user_token_account.amount;
+        let sol_value = stake_pool.tokens_to_lamports(pool_tokens)?;
+
+        let result = ClaimQueryResult {
+            already_processed: status.already_processed,
+            current_epoch: status.current_epoch,
+            pool_tokens,
+            sol_value,
+        };
+        msg!("User {} holds {} pool tokens worth {} lamports", user_info.key, pool_tokens, sol_value);
+        set_return_data(&result.try_to_vec()?);
         Ok(())
     }
 
@@ -782,7 +1478,7 @@ impl Processor {
 
         // 0. `[signer, writable]` User account (receives SOL)
         let user_info = next_account_info(account_info_iter)?;
-        // 1. `[]` Stake pool (read-only, for withdraw authority derivation)
+        // 1. `[writable]` Stake pool (withdraw authority, pending withdrawal total)
         let stake_pool_info = next_account_info(account_info_iter)?;
         // 2. `[writable]` Stake account (PDA derived from user & pool - withdraw from)
         let stake_account_info = next_account_info(account_info_iter)?;
@@ -794,6 +1490,8 @@ impl Processor {
         let clock_info = next_account_info(account_info_iter)?;
         // 6. `[]` Stake history sysvar
         let stake_history_info = next_account_info(account_info_iter)?;
+        // 7. `[writable]` Stake record PDA of the stake account (closed, rent to the user)
+        let stake_record_info = next_account_info(account_info_iter)?;
 
         // Basic Checks
         if !user_info.is_signer {
@@ -801,14 +1499,18 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
         assert_owned_by(stake_pool_info, program_id)?;
+        assert_rent_exempt(&Rent::get()?, stake_pool_info)?;
         assert_owned_by(stake_account_info, &solana_program::stake::program::id())?;
+        assert_program_id(stake_program_info, &solana_program::stake::program::id())?;
+        assert_sysvar(stake_history_info, &solana_program::sysvar::stake_history::id())?;
 
         // Load stake pool state (needed for withdraw authority)
-        let stake_pool = StakePool::try_from_slice(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             msg!("Stake pool not initialized");
             return Err(ProgramError::UninitializedAccount);
         }
+        assert_authority_bumps(program_id, stake_pool_info.key, &stake_pool)?;
         // It's okay if pool is paused for withdrawals
 
         // Verify passed withdraw authority PDA matches the one in the pool state
@@ -818,8 +1520,8 @@ impl Processor {
         }
 
         // Load stake account state
-        let stake_state = StakeStateV2::try_from_slice(&stake_account_info.data.borrow())?;
-        let (deactivation_epoch, stake_lamports) = match stake_state {
+        let stake_state = StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..])?;
+        let (deactivation_epoch, stake_lamports, deactivated_stake) = match stake_state {
             StakeStateV2::Stake(meta, stake, _stake_flags) => {
                  // Verify the designated withdrawer matches the pool's withdraw authority PDA.
                  if meta.authorized.withdrawer != stake_pool.withdraw_authority {
@@ -827,11 +1529,11 @@ impl Processor {
                     return Err(StakePoolError::InvalidStakeAccountAuthority.into());
                  }
                  // Check if the stake account has actually been deactivated.
-                 if stake.delegation.deactivation_epoch == std::u64::MAX {
+                 if stake.delegation.deactivation_epoch == u64::MAX {
                     msg!("Stake account is not deactivated");
                     return Err(StakePoolError::StakeNotDeactivated.into());
                  }
-                 (stake.delegation.deactivation_epoch, stake_account_info.lamports())
+                 (stake.delegation.deactivation_epoch, stake_account_info.lamports(), stake.delegation.stake)
             },
             _ => {
                 msg!("Stake account not in correct Stake state for withdrawal");
@@ -839,18 +1541,55 @@ impl Processor {
             }
         };
 
+        // The pool is the withdrawer of every user stake account, so only the address
+        // ties an account to its user: the signer's stake PDA, or a split stake PDA
+        // derived from the epoch its partial unstake deactivated it in
+        let (expected_stake_pda, _stake_pda_bump) = Pubkey::find_program_address(
+            &[
+                STAKE_ACCOUNT_SEED,
+                stake_pool_info.key.as_ref(),
+                user_info.key.as_ref(),
+                &stake_pool.stake_authority.to_bytes(),
+            ],
+            program_id
+        );
+        let (expected_split_stake_pda, _split_stake_bump) = Pubkey::find_program_address(
+            &[SPLIT_STAKE_SEED, stake_pool_info.key.as_ref(), user_info.key.as_ref(), &deactivation_epoch.to_le_bytes()],
+            program_id,
+        );
+        if *stake_account_info.key != expected_stake_pda && *stake_account_info.key != expected_split_stake_pda {
+            msg!("Stake account {} is not a stake account of user {}", stake_account_info.key, user_info.key);
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let (stake_record_address, _) = Pubkey::find_program_address(
+            &[STAKE_RECORD_SEED, stake_account_info.key.as_ref()],
+            program_id,
+        );
+        if *stake_record_info.key != stake_record_address {
+            msg!("Stake record {} does not match derived PDA {}", stake_record_info.key, stake_record_address);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let stake_record = Self::load_stake_record(program_id, stake_account_info.key, stake_record_info)?;
+        // Tokens still minted against a deactivated account mean MigrateValidatorStake
+        // deactivated it to move it, not an unstake
+        if stake_record.as_ref().is_some_and(|record| record.pool_tokens != 0) {
+            msg!("Stake account {} is being moved to another validator", stake_account_info.key);
+            return Err(StakePoolError::StakeMigrating.into());
+        }
+
         // Check cooldown period
         let clock = Clock::from_account_info(clock_info)?;
-        if clock.epoch <= deactivation_epoch { // Should be strictly greater? Check stake program logic. Using <= for safety.
+        if !cooldown_complete(deactivation_epoch, clock.epoch) {
             msg!("Stake account cooldown period not yet passed (current: {}, deactivation: {})", clock.epoch, deactivation_epoch);
             return Err(StakePoolError::CooldownNotPassed.into());
         }
 
         // Derive withdraw authority PDA seeds for signing
-        let withdraw_authority_seeds = &[b"withdraw_authority", stake_pool_info.key.as_ref(), &[stake_pool.withdraw_authority_bump_seed]];
+        let withdraw_authority_seeds = &[WITHDRAW_AUTHORITY_SEED, stake_pool_info.key.as_ref(), &[stake_pool.withdraw_authority_bump_seed]];
         // Double check derivation matches stored authority
         let (_withdraw_pda, _withdraw_bump) = Pubkey::find_program_address(
-            &[b"withdraw_authority", stake_pool_info.key.as_ref()],
+            &[WITHDRAW_AUTHORITY_SEED, stake_pool_info.key.as_ref()],
             program_id,
         );
         if _withdraw_pda != stake_pool.withdraw_authority {
@@ -862,6 +1601,9 @@ impl Processor {
         // Withdraws the full SOL balance from a fully deactivated stake account PDA
         // to the user's main account. Requires cooldown period to have passed.
         // Requires the withdraw_authority PDA to sign.
+        // Policy: the rent-exempt reserve is withdrawn too, closing the account. A
+        // zero-lamport account is purged by the runtime at the end of the transaction,
+        // so it is never left open below rent exemption, and a later Stake recreates it.
         msg!("Withdrawing {} lamports from stake account {} to user {}", 
              stake_lamports, stake_account_info.key, user_info.key);
         invoke_signed(
@@ -881,13 +1623,5096 @@ impl Processor {
                 withdraw_authority_info.clone(), // Authority account
             ],
             &[withdraw_authority_seeds], // Sign with the PDA withdraw authority seeds
-        )?;
+        ).map_err(cpi_step("stake_withdraw"))?;
+
+        // The account must be fully drained so it is closed rather than left rent-deficient
+        if stake_account_info.lamports() != 0 {
+            msg!("Stake account still holds {} lamports after withdrawal", stake_account_info.lamports());
+            return Err(StakePoolError::StakeAccountNotClosed.into());
+        }
 
-        // Optional: Close the stake account PDA and return rent to user?
-        // This would require making user_info writable and passing system_program.
-        // For simplicity, leaving the account open for now.
+        // Take off exactly what Unstake added. The delegation may have grown since, as
+        // deactivating stake still earns rewards for its deactivation epoch. An account
+        // unstaked before stake records existed falls back to its delegation, saturating
+        // since stake deactivated before the counter existed was never added.
+        stake_pool.total_pending_withdrawal = match &stake_record {
+            Some(record) => safe_math::sub(stake_pool.total_pending_withdrawal, record.pending_withdrawal)?,
+            None => stake_pool.total_pending_withdrawal.saturating_sub(deactivated_stake),
+        };
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+        if stake_record.is_some() {
+            close_program_account(stake_record_info, user_info)?;
+        }
 
         msg!("Withdrawal successful.");
         Ok(())
     }
-} // <-- ADDED Closing brace for impl Processor
\ No newline at end of file
+
+    /// Reports how much SOL a `WithdrawStake` on the given stake account would return
+    /// and whether it is ready, without performing the withdrawal.
+    fn process_preview_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake account (PDA derived from user & pool)
+        let stake_account_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Clock sysvar
+        let clock_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        assert_owned_by(stake_account_info, &solana_program::stake::program::id())?;
+
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        let stake_state = StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..])?;
+        let deactivation_epoch = match stake_state {
+            StakeStateV2::Stake(meta, stake, _stake_flags) => {
+                if meta.authorized.withdrawer != stake_pool.withdraw_authority {
+                    msg!("Stake account withdraw authority mismatch");
+                    return Err(StakePoolError::InvalidStakeAccountAuthority.into());
+                }
+                stake.delegation.deactivation_epoch
+            },
+            _ => {
+                msg!("Stake account not in correct Stake state for withdrawal");
+                return Err(StakePoolError::WrongStakeState.into());
+            }
+        };
+
+        let clock = Clock::from_account_info(clock_info)?;
+        let preview = WithdrawPreview {
+            withdrawable_lamports: stake_account_info.lamports(),
+            deactivation_epoch,
+            ready: cooldown_complete(deactivation_epoch, clock.epoch),
+        };
+        msg!("Withdrawable lamports: {}, ready: {}", preview.withdrawable_lamports, preview.ready);
+        set_return_data(&preview.try_to_vec()?);
+        Ok(())
+    }
+
+    /// Writes the pool mint into a pool whose initialization left `mint` unset.
+    fn process_repair_mint(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        mint: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Pool token mint
+        let pool_mint_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+        assert_owned_by(pool_mint_info, &spl_token::id())?;
+
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+        if stake_pool.mint != Pubkey::default() {
+            msg!("Pool mint is already set to {}", stake_pool.mint);
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let (expected_mint_pda, _mint_bump) = Pubkey::find_program_address(
+            &[stake_pool_info.key.as_ref(), MINT_SEED],
+            program_id
+        );
+        if mint != expected_mint_pda || *pool_mint_info.key != expected_mint_pda {
+            msg!("Provided mint {} does not match derived PDA {}", mint, expected_mint_pda);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let pool_mint = spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())?;
+        assert_mint_authority(&pool_mint, &stake_pool.stake_authority)?;
+
+        stake_pool.mint = mint;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Pool mint repaired: {}", mint);
+        Ok(())
+    }
+
+    /// Loads the pool's history account after checking it is the pool's history PDA.
+    fn load_history(
+        program_id: &Pubkey,
+        history_info: &AccountInfo,
+    ) -> Result<PoolHistory, ProgramError> {
+        assert_owned_by(history_info, program_id)?;
+        let history = PoolHistory::unpack_account(&history_info.data.borrow())?;
+        if !history.is_initialized() {
+            msg!("Pool history not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        let (expected_history_pda, _history_bump) = Pubkey::find_program_address(
+            &[HISTORY_SEED, history.stake_pool.as_ref()],
+            program_id,
+        );
+        if expected_history_pda != *history_info.key {
+            msg!("Provided history account {} does not match derived PDA {}", history_info.key, expected_history_pda);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        Ok(history)
+    }
+
+    /// Appends a snapshot of the pool to its history account.
+    fn record_history(
+        program_id: &Pubkey,
+        stake_pool_key: &Pubkey,
+        stake_pool: &StakePool,
+        history_info: &AccountInfo,
+        epoch: u64,
+    ) -> ProgramResult {
+        let mut history = Self::load_history(program_id, history_info)?;
+        if history.stake_pool != *stake_pool_key {
+            msg!("History account belongs to pool {}", history.stake_pool);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // Rewards stay in the users' stake accounts rather than being booked into
+        // `total_staked`, and no fees are minted yet, so both are recorded as 0.
+        history.push(HistoryEntry {
+            epoch,
+            price: stake_pool.price()?,
+            total_staked: stake_pool.total_staked,
+            total_shares: stake_pool.total_shares,
+            rewards_booked: 0,
+            fees_taken: 0,
+        });
+        history.pack_account(&mut history_info.data.borrow_mut())?;
+        msg!("Recorded history snapshot for epoch {}", epoch);
+        Ok(())
+    }
+
+    /// Creates the pool's history ring buffer sized for `capacity` epochs.
+    fn process_create_history(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        capacity: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer, writable]` Stake pool authority (pays rent)
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[writable]` Pool history PDA
+        let history_info = next_account_info(account_info_iter)?;
+        // 3. `[]` System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+        // 4. `[]` Rent sysvar
+        let rent_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+        if capacity == 0 || capacity > MAX_HISTORY_ENTRIES {
+            msg!("History capacity must be 1-{}", MAX_HISTORY_ENTRIES);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let (expected_history_pda, history_bump) = Pubkey::find_program_address(
+            &[HISTORY_SEED, stake_pool_info.key.as_ref()],
+            program_id,
+        );
+        if expected_history_pda != *history_info.key {
+            msg!("Provided history account {} does not match derived PDA {}", history_info.key, expected_history_pda);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if !history_info.data_is_empty() {
+            msg!("Pool history already exists");
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let history = PoolHistory::new(*stake_pool_info.key, capacity);
+        create_or_allocate_account_raw(
+            program_id,
+            history_info,
+            rent_info,
+            system_program_info,
+            authority_info,
+            PoolHistory::account_size(capacity),
+            &[HISTORY_SEED, stake_pool_info.key.as_ref(), &[history_bump]],
+        )?;
+        history.pack_account(&mut history_info.data.borrow_mut())?;
+
+        msg!("Pool history created with capacity {}", capacity);
+        Ok(())
+    }
+
+    /// Returns a page of history snapshots, oldest first.
+    fn process_get_history(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        offset: u32,
+        limit: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` Pool history PDA
+        let history_info = next_account_info(account_info_iter)?;
+
+        let history = Self::load_history(program_id, history_info)?;
+        set_paged_return_data(&history.ordered(), offset as usize, limit as usize)
+    }
+
+    /// Clears rounding residue left in the pool totals after many stake/unstake cycles.
+    fn process_sweep_dust(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        dust_threshold: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Pool token mint
+        let pool_mint_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+        assert_owned_by(pool_mint_info, &spl_token::id())?;
+
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+        if stake_pool.mint != *pool_mint_info.key {
+            msg!("Invalid pool mint");
+            return Err(StakePoolError::InvalidMintAuthority.into());
+        }
+        if dust_threshold > MAX_DUST_THRESHOLD {
+            msg!("Dust threshold {} exceeds maximum {}", dust_threshold, MAX_DUST_THRESHOLD);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let supply = spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())?.supply;
+        let (old_shares, old_staked) = (stake_pool.total_shares, stake_pool.total_staked);
+
+        // Shares are backed one-to-one by pool tokens, so only drift from the real
+        // supply is dust; outstanding tokens are never written off.
+        if stake_pool.total_shares.abs_diff(supply) <= dust_threshold {
+            stake_pool.total_shares = supply;
+        }
+        // With no shares left, leftover stake belongs to nobody and only skews the
+        // next depositor's ratio.
+        if stake_pool.total_shares == 0 && stake_pool.total_staked <= dust_threshold {
+            stake_pool.total_staked = 0;
+        }
+
+        if stake_pool.total_shares == old_shares && stake_pool.total_staked == old_staked {
+            msg!("No dust to sweep: total_staked={}, total_shares={}, supply={}", old_staked, old_shares, supply);
+            return Ok(());
+        }
+
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+        msg!("Dust swept: total_staked {} -> {}, total_shares {} -> {}",
+             old_staked, stake_pool.total_staked, old_shares, stake_pool.total_shares);
+        Ok(())
+    }
+
+    /// Sets the maximum SOL value that may be unstaked per epoch (0 disables the limit).
+    fn process_set_unstake_limit(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_unstake_per_epoch: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+
+        stake_pool.max_unstake_per_epoch = max_unstake_per_epoch;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Unstake limit set to {} lamports per epoch", max_unstake_per_epoch);
+        Ok(())
+    }
+
+    /// Moves lamports above the rent-exempt minimum out of a pool-owned PDA (the pool
+    /// account or its history) into the treasury fee account.
+    fn process_reclaim_excess_rent(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[writable]` Account to reclaim from (the stake pool or its history PDA)
+        let target_info = next_account_info(account_info_iter)?;
+        // 3. `[writable]` Treasury fee account (receives the surplus)
+        let treasury_fee_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+        assert_owned_by(target_info, program_id)?;
+
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+        if stake_pool.treasury_fee_account != *treasury_fee_info.key {
+            msg!("Surplus must go to the pool treasury fee account");
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
+
+        let (history_pda, _history_bump) = Pubkey::find_program_address(
+            &[HISTORY_SEED, stake_pool_info.key.as_ref()],
+            program_id,
+        );
+        if target_info.key != stake_pool_info.key && *target_info.key != history_pda {
+            msg!("Account {} is not a PDA of this pool", target_info.key);
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        // Never leave the account below rent exemption for its current size
+        let rent_exempt_minimum = Rent::get()?.minimum_balance(target_info.data_len());
+        let surplus = target_info.lamports().saturating_sub(rent_exempt_minimum);
+        if surplus == 0 {
+            msg!("No excess rent in {}", target_info.key);
+            return Ok(());
+        }
+
+        // The program owns the target, so it can debit lamports directly
+        **target_info.try_borrow_mut_lamports()? -= surplus;
+        **treasury_fee_info.try_borrow_mut_lamports()? = safe_math::add(treasury_fee_info.lamports(), surplus)?;
+
+        msg!("Reclaimed {} lamports from {} to treasury", surplus, target_info.key);
+        Ok(())
+    }
+
+    /// Returns the program version and compiled features, plus the enabled options of
+    /// the stake pool if one is passed.
+    fn process_get_program_info(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` (Optional) Stake pool
+        let stake_pool_info = next_account_info(account_info_iter).ok();
+
+        let mut features = 0;
+        if cfg!(feature = "debug-logging") {
+            features |= FEATURE_DEBUG_LOGGING;
+        }
+        if cfg!(any(test, feature = "test-utils")) {
+            features |= FEATURE_TEST_UTILS;
+        }
+
+        let pool_flags = match stake_pool_info {
+            Some(stake_pool_info) => {
+                assert_owned_by(stake_pool_info, program_id)?;
+                let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+                if !stake_pool.is_initialized() {
+                    msg!("Stake pool not initialized");
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                stake_pool.flags()
+            }
+            None => 0,
+        };
+
+        let info = ProgramInfo {
+            program_version: PROGRAM_VERSION.to_string(),
+            pool_state_version: POOL_STATE_VERSION,
+            features,
+            pool_flags,
+        };
+        set_return_data(&info.try_to_vec()?);
+        Ok(())
+    }
+
+    /// Returns the SOL value of each passed pool token account, paged. Accounts that
+    /// are not token accounts of the pool mint are flagged rather than failing the call.
+    fn process_get_user_values(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        offset: u32,
+        limit: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 1.. `[]` Pool token accounts
+        let token_account_infos = account_info_iter.as_slice();
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        let mut values = Vec::with_capacity(token_account_infos.len());
+        for token_account_info in token_account_infos {
+            let token_account = if *token_account_info.owner == spl_token::id() {
+                spl_token::state::Account::unpack(&token_account_info.data.borrow()).ok()
+            } else {
+                None
+            };
+            let value = match token_account {
+                Some(token_account) if token_account.mint == stake_pool.mint => UserValue {
+                    token_account: *token_account_info.key,
+                    valid: true,
+                    pool_tokens: token_account.amount,
+                    sol_value: stake_pool.tokens_to_lamports(token_account.amount)?,
+                },
+                _ => {
+                    msg!("Skipping {}: not a pool token account", token_account_info.key);
+                    UserValue { token_account: *token_account_info.key, ..UserValue::default() }
+                }
+            };
+            values.push(value);
+        }
+
+        set_paged_return_data(&values, offset as usize, limit as usize)
+    }
+
+    /// Compares the validator's current commission with the last one seen. An
+    /// increase of at least `commission_alert_threshold` points is logged as a
+    /// `CommissionChanged` event and, if configured, holds new deposits. Unstaking is
+    /// never blocked, so holders can always exit.
+    fn check_commission(stake_pool: &mut StakePool, vote_info: &AccountInfo) -> ProgramResult {
+        let commission = vote_account_commission(vote_info)?;
+        let previous = stake_pool.last_known_commission;
+        let increase = commission.saturating_sub(previous);
+        if stake_pool.commission_alert_threshold != 0 && increase >= stake_pool.commission_alert_threshold {
+            msg!("CommissionChanged: validator {} commission {}% -> {}%", vote_info.key, previous, commission);
+            if stake_pool.pause_on_commission_hike {
+                stake_pool.commission_hold = true;
+                msg!("New deposits held until the pool authority acknowledges");
+            }
+        }
+        stake_pool.last_known_commission = commission;
+        Ok(())
+    }
+
+    /// Loads the pool for a commission admin instruction: checks the authority and the
+    /// vote account, and returns the pool with the validator's current commission.
+    fn load_pool_for_commission_admin(
+        program_id: &Pubkey,
+        authority_info: &AccountInfo,
+        stake_pool_info: &AccountInfo,
+        vote_info: &AccountInfo,
+    ) -> Result<(StakePool, u8), ProgramError> {
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+        if stake_pool.helius_validator_vote != *vote_info.key {
+            msg!("Vote account {} is not the pool validator", vote_info.key);
+            return Err(StakePoolError::InvalidVoteAccount.into());
+        }
+        let commission = vote_account_commission(vote_info)?;
+        Ok((stake_pool, commission))
+    }
+
+    /// Configures commission alerts and records the validator's current commission
+    /// as the baseline increases are measured against.
+    fn process_set_commission_policy(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        alert_threshold: u8,
+        pause_on_hike: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Pool validator vote account
+        let vote_info = next_account_info(account_info_iter)?;
+
+        if alert_threshold > 100 {
+            msg!("Commission alert threshold must be 0-100");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let (mut stake_pool, commission) =
+            Self::load_pool_for_commission_admin(program_id, authority_info, stake_pool_info, vote_info)?;
+
+        stake_pool.commission_alert_threshold = alert_threshold;
+        stake_pool.pause_on_commission_hike = pause_on_hike;
+        stake_pool.last_known_commission = commission;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Commission policy set: threshold {} points, hold deposits {}, baseline {}%",
+             alert_threshold, pause_on_hike, commission);
+        Ok(())
+    }
+
+    /// Acknowledges the validator's current commission: releases the deposit hold and
+    /// makes the current commission the new baseline.
+    fn process_acknowledge_commission(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Pool validator vote account
+        let vote_info = next_account_info(account_info_iter)?;
+
+        let (mut stake_pool, commission) =
+            Self::load_pool_for_commission_admin(program_id, authority_info, stake_pool_info, vote_info)?;
+
+        stake_pool.commission_hold = false;
+        stake_pool.last_known_commission = commission;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Commission {}% acknowledged; deposits released", commission);
+        Ok(())
+    }
+
+    /// Whether account data already starts with the type tag of `T`.
+    fn has_discriminator<T: DiscriminatedAccount>(data: &[u8]) -> bool {
+        data.len() >= ACCOUNT_DISCRIMINATOR_LEN && data[..ACCOUNT_DISCRIMINATOR_LEN] == T::DISCRIMINATOR
+    }
+
+    /// Rewrites a legacy (untagged) account in the tagged layout: resizes it to the
+    /// tagged encoding of `value`, tops up rent from `payer_info`, and writes it back.
+    fn write_migrated_account<'a, T: DiscriminatedAccount>(
+        value: &T,
+        account_info: &AccountInfo<'a>,
+        payer_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let data = value.to_account_data()?;
+        let new_len = data.len();
+        let required_lamports = Rent::get()?.minimum_balance(new_len);
+        if account_info.lamports() < required_lamports {
+            invoke(
+                &system_instruction::transfer(payer_info.key, account_info.key, required_lamports - account_info.lamports()),
+                &[payer_info.clone(), account_info.clone(), system_program_info.clone()],
+            ).map_err(cpi_step("transfer"))?;
+        }
+        account_info.realloc(new_len, false)?;
+        account_info.data.borrow_mut().copy_from_slice(&data);
+        msg!("Migrated {} to the tagged account layout", account_info.key);
+        Ok(())
+    }
+
+    /// Migrates the pool account, and optionally its history, from the untagged layout.
+    fn process_migrate_account_layout(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer, writable]` Stake pool authority (pays rent)
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+        // 3. `[writable]` (Optional) Pool history PDA
+        let history_info = next_account_info(account_info_iter).ok();
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+
+        let pool_migrated = Self::has_discriminator::<StakePool>(&stake_pool_info.data.borrow());
+        let stake_pool = if pool_migrated {
+            StakePool::unpack_account(&stake_pool_info.data.borrow())?
+        } else {
+            StakePool::try_from_slice(&stake_pool_info.data.borrow())?
+        };
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+        if pool_migrated {
+            msg!("Stake pool already uses the tagged layout");
+        } else {
+            Self::write_migrated_account(&stake_pool, stake_pool_info, authority_info, system_program_info)?;
+        }
+
+        if let Some(history_info) = history_info {
+            assert_owned_by(history_info, program_id)?;
+            let (expected_history_pda, _history_bump) = Pubkey::find_program_address(
+                &[HISTORY_SEED, stake_pool_info.key.as_ref()],
+                program_id,
+            );
+            if expected_history_pda != *history_info.key {
+                msg!("Provided history account {} does not match derived PDA {}", history_info.key, expected_history_pda);
+                return Err(ProgramError::InvalidSeeds);
+            }
+            if Self::has_discriminator::<PoolHistory>(&history_info.data.borrow()) {
+                msg!("Pool history already uses the tagged layout");
+            } else {
+                let history = LegacyPoolHistory::try_from_slice(&history_info.data.borrow())?.upgrade()?;
+                Self::write_migrated_account(&history, history_info, authority_info, system_program_info)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets whether deposits must mint to the user's associated token account.
+    fn process_set_require_ata(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        require_ata: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+
+        stake_pool.require_ata = require_ata;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Require associated token account: {}", require_ata);
+        Ok(())
+    }
+
+    /// Reports which preconditions of `Stake` a deposit of `amount` would pass, without
+    /// changing any account. Frontends simulate it to explain a rejection up front.
+    fn process_validate_stake(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` User account
+        let user_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` User's pool token account
+        let user_token_account_info = next_account_info(account_info_iter)?;
+        // 3. `[]` Pool token mint
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // 4. `[]` User's stake account PDA
+        let stake_account_info = next_account_info(account_info_iter)?;
+        // 5. `[]` Validator vote account
+        let validator_vote_info = next_account_info(account_info_iter)?;
+        // 6. `[]` Stake authority PDA
+        let stake_authority_info = next_account_info(account_info_iter)?;
+        // 7. `[]` Stake program id
+        let stake_program_info = next_account_info(account_info_iter)?;
+        // Then, in any order: stake record PDA, validator list PDA and rent payer (marked as
+        // signer), as in Stake
+        let optional_infos = account_info_iter.as_slice();
+        let rent_payer_info = Self::optional_rent_payer(optional_infos).unwrap_or(user_info);
+
+        // Without a valid pool there is nothing to check against
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        assert_authority_bumps(program_id, stake_pool_info.key, &stake_pool)?;
+        if *stake_program_info.key != solana_program::stake::program::id() {
+            msg!("Incorrect stake program: {}", stake_program_info.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mint_valid = *pool_mint_info.key == stake_pool.mint
+            && *pool_mint_info.owner == spl_token::id()
+            && assert_mint_not_freezable(pool_mint_info).is_ok()
+            && spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())
+                .is_ok_and(|mint| assert_mint_authority(&mint, &stake_pool.stake_authority).is_ok());
+
+        let token_account_valid = *user_token_account_info.owner == spl_token::id()
+            && spl_token::state::Account::unpack(&user_token_account_info.data.borrow())
+                .is_ok_and(|account| account.mint == stake_pool.mint)
+            && (!stake_pool.require_ata
+                || assert_associated_token_account(user_info.key, &stake_pool.mint, user_token_account_info).is_ok());
+
+        let min_stake = effective_min_stake(&stake_pool)?;
+
+        let (expected_stake_authority, _) = Pubkey::find_program_address(
+            &[STAKE_AUTHORITY_SEED, stake_pool_info.key.as_ref()],
+            program_id,
+        );
+        let stake_authority_matches = expected_stake_authority == stake_pool.stake_authority
+            && expected_stake_authority == *stake_authority_info.key;
+
+        let (expected_stake_account, _) = Pubkey::find_program_address(
+            &[
+                STAKE_ACCOUNT_SEED,
+                stake_pool_info.key.as_ref(),
+                user_info.key.as_ref(),
+                &stake_pool.stake_authority.to_bytes(),
+            ],
+            program_id,
+        );
+
+        // Same rules as Stake: a missing account is created, an existing one must
+        // be a correctly sized stake account that is initialized but not delegated.
+        let stake_account_created = *stake_account_info.owner != solana_program::system_program::id();
+        let stake_account_usable = (!stake_account_created && stake_account_info.data_is_empty())
+            || (*stake_account_info.owner == solana_program::stake::program::id()
+                && stake_account_info.data_len() == STAKE_ACCOUNT_SIZE
+                && matches!(
+                    StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..]),
+                    Ok(StakeStateV2::Initialized(_))
+                ));
+
+        let (listed_validator, within_validator_limit) =
+            match Self::optional_validator_list(program_id, stake_pool_info.key, &stake_pool, optional_infos) {
+                Ok(Some((_, validator_list))) => match validator_list.find(validator_vote_info.key) {
+                    Some(validator_index) => (
+                        true,
+                        SecurityManager::verify_validator_stake_limit(
+                            &validator_list,
+                            validator_index,
+                            amount,
+                            stake_pool.validator_stake_cap_bps(),
+                        ).is_ok(),
+                    ),
+                    None => (false, false),
+                },
+                Ok(None) => (*validator_vote_info.key == stake_pool.helius_validator_vote, true),
+                Err(_) => (false, false),
+            };
+
+        let stake_account_rent = if stake_account_created {
+            0
+        } else {
+            Rent::get()?.minimum_balance(STAKE_ACCOUNT_SIZE).saturating_sub(stake_account_info.lamports())
+        };
+        // Without the stake record, Stake would fail; count its full rent here
+        let stake_record_rent = match Self::optional_stake_record(program_id, &expected_stake_account, optional_infos) {
+            Ok((stake_record_info, _)) => Self::stake_record_rent(program_id, stake_record_info)?,
+            Err(_) => Rent::get()?.minimum_balance(StakeRecord::default().to_account_data()?.len()),
+        };
+        let rent_due = safe_math::add(stake_account_rent, stake_record_rent)?;
+        let separate_rent_payer = rent_payer_info.key != user_info.key;
+        let required_lamports = if separate_rent_payer {
+            amount
+        } else {
+            safe_math::add(amount, rent_due)?
+        };
+        let sufficient_balance = user_info.lamports() >= required_lamports
+            && (!separate_rent_payer || rent_payer_info.lamports() >= rent_due);
+        let pool_tokens = stake_pool.lamports_to_tokens(amount).unwrap_or(0);
+
+        let mut report = StakeValidation {
+            passed: false,
+            user_signed: user_info.is_signer && rent_payer_info.is_signer,
+            not_paused: !stake_pool.paused,
+            no_commission_hold: !stake_pool.commission_hold,
+            mint_valid,
+            token_account_valid,
+            above_minimum: amount >= min_stake,
+            below_maximum: amount <= stake_pool.max_stake,
+            validator_matches: listed_validator,
+            within_validator_limit,
+            stake_authority_matches,
+            stake_account_matches: expected_stake_account == *stake_account_info.key,
+            stake_account_usable,
+            sufficient_balance,
+            mints_tokens: pool_tokens > 0,
+            min_stake,
+            max_stake: stake_pool.max_stake,
+            required_lamports,
+            pool_tokens,
+        };
+        report.passed = report.user_signed
+            && report.not_paused
+            && report.no_commission_hold
+            && report.mint_valid
+            && report.token_account_valid
+            && report.above_minimum
+            && report.below_maximum
+            && report.validator_matches
+            && report.within_validator_limit
+            && report.stake_authority_matches
+            && report.stake_account_matches
+            && report.stake_account_usable
+            && report.sufficient_balance
+            && report.mints_tokens;
+
+        msg!("Stake of {} lamports would {}", amount, if report.passed { "succeed" } else { "fail" });
+        set_return_data(&report.try_to_vec()?);
+        Ok(())
+    }
+
+    /// Resets `total_shares` to the pool mint's supply after a small divergence.
+    fn process_reconcile_shares(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Pool token mint
+        let pool_mint_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+        assert_owned_by(pool_mint_info, &spl_token::id())?;
+
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+        if stake_pool.mint != *pool_mint_info.key {
+            msg!("Provided mint {} is not the pool mint {}", pool_mint_info.key, stake_pool.mint);
+            return Err(StakePoolError::InvalidMintAuthority.into());
+        }
+
+        let supply = spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())?.supply;
+        let divergence = supply.abs_diff(stake_pool.total_shares);
+        if divergence == 0 {
+            msg!("total_shares already matches mint supply {}", supply);
+            return Ok(());
+        }
+        let max_divergence = safe_math::mul_div(
+            supply.max(stake_pool.total_shares),
+            MAX_SHARES_DIVERGENCE_BPS,
+            10_000,
+        )?;
+        if divergence > max_divergence {
+            msg!("total_shares {} and mint supply {} differ by {}, more than the {} allowed",
+                 stake_pool.total_shares, supply, divergence, max_divergence);
+            return Err(StakePoolError::SharesDivergenceTooLarge.into());
+        }
+
+        let previous_shares = stake_pool.total_shares;
+        stake_pool.total_shares = supply;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Reconciled total_shares: {} -> {} (mint supply)", previous_shares, supply);
+        Ok(())
+    }
+
+    /// Returns the pool's staked, share and pending-withdrawal totals.
+    fn process_get_pool_info(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        let info = PoolInfo {
+            total_staked: stake_pool.total_staked,
+            total_shares: stake_pool.total_shares,
+            total_pending_withdrawal: stake_pool.total_pending_withdrawal,
+        };
+        msg!("Pending withdrawal: {} lamports", info.total_pending_withdrawal);
+        set_return_data(&info.try_to_vec()?);
+        Ok(())
+    }
+
+    /// Creates the user's pool token ATA if needed, then runs `process_stake` with the
+    /// remaining accounts. Any failure reverts the ATA creation too.
+    fn process_stake_with_setup(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        // 0-13. Accounts of `Stake`, then 14. `[]` Associated token account program id
+        let associated_token_program_index = StakeAccounts::COUNT;
+        if accounts.len() <= associated_token_program_index {
+            msg!("StakeWithSetup expects at least {} accounts, got {}",
+                 associated_token_program_index + 1, accounts.len());
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let user_info = &accounts[StakeAccounts::User as usize];
+        let user_token_account_info = &accounts[StakeAccounts::UserTokenAccount as usize];
+        let pool_mint_info = &accounts[StakeAccounts::PoolMint as usize];
+        let token_program_info = &accounts[StakeAccounts::TokenProgram as usize];
+        let system_program_info = &accounts[StakeAccounts::SystemProgram as usize];
+        let associated_token_program_info = &accounts[associated_token_program_index];
+
+        if *associated_token_program_info.key != spl_associated_token_account::id() {
+            msg!("Incorrect associated token account program: {}", associated_token_program_info.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        assert_program_id(token_program_info, &spl_token::id())?;
+        assert_associated_token_account(user_info.key, pool_mint_info.key, user_token_account_info)?;
+
+        // Idempotent: succeeds without changes when the ATA already exists
+        msg!("Ensuring associated token account {}", user_token_account_info.key);
+        invoke(
+            &spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                user_info.key,
+                user_info.key,
+                pool_mint_info.key,
+                token_program_info.key,
+            ),
+            &[
+                user_info.clone(),
+                user_token_account_info.clone(),
+                pool_mint_info.clone(),
+                system_program_info.clone(),
+                token_program_info.clone(),
+                associated_token_program_info.clone(),
+            ],
+        ).map_err(cpi_step("create_associated_token_account"))?;
+
+        // 15. Stake's optional accounts (validator list, rent payer), which it expects right
+        //     after its own accounts
+        let stake_accounts: Vec<AccountInfo> = accounts[..associated_token_program_index]
+            .iter()
+            .chain(&accounts[associated_token_program_index + 1..])
+            .cloned()
+            .collect();
+        Self::process_stake(program_id, &stake_accounts, amount)
+    }
+
+    /// Appends snapshot `index` to the pool's hash-linked snapshot chain.
+    fn process_snapshot(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        index: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer, writable]` Payer
+        let payer_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Pool token mint
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // 3. `[writable]` Snapshot PDA for `index`
+        let snapshot_info = next_account_info(account_info_iter)?;
+        // 4. `[]` System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+        // 5. `[]` Rent sysvar
+        let rent_info = next_account_info(account_info_iter)?;
+        // 6. `[]` Clock sysvar
+        let clock_info = next_account_info(account_info_iter)?;
+
+        if !payer_info.is_signer {
+            msg!("Payer signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+        assert_owned_by(pool_mint_info, &spl_token::id())?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.mint != *pool_mint_info.key {
+            msg!("Invalid pool mint");
+            return Err(StakePoolError::InvalidMintAuthority.into());
+        }
+
+        // Each index has one PDA, so the chain cannot fork: a snapshot can only be
+        // taken on top of an existing one, and only once per index.
+        let prev_hash = if index == 0 {
+            [0u8; 32]
+        } else {
+            // 7. `[]` Snapshot PDA for `index - 1`
+            let prev_snapshot_info = next_account_info(account_info_iter)?;
+            assert_owned_by(prev_snapshot_info, program_id)?;
+            let prev_index = index - 1;
+            let (expected_prev_pda, _prev_bump) = Pubkey::find_program_address(
+                &[SNAPSHOT_SEED, stake_pool_info.key.as_ref(), &prev_index.to_le_bytes()],
+                program_id,
+            );
+            if expected_prev_pda != *prev_snapshot_info.key {
+                msg!("Provided previous snapshot {} does not match derived PDA {}", prev_snapshot_info.key, expected_prev_pda);
+                return Err(ProgramError::InvalidSeeds);
+            }
+            let prev_snapshot = PoolSnapshot::unpack_account(&prev_snapshot_info.data.borrow())?;
+            if !prev_snapshot.is_initialized() {
+                msg!("Previous snapshot not initialized");
+                return Err(ProgramError::UninitializedAccount);
+            }
+            prev_snapshot.hash
+        };
+
+        let index_bytes = index.to_le_bytes();
+        let (expected_snapshot_pda, snapshot_bump) = Pubkey::find_program_address(
+            &[SNAPSHOT_SEED, stake_pool_info.key.as_ref(), &index_bytes],
+            program_id,
+        );
+        if expected_snapshot_pda != *snapshot_info.key {
+            msg!("Provided snapshot account {} does not match derived PDA {}", snapshot_info.key, expected_snapshot_pda);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if !snapshot_info.data_is_empty() {
+            msg!("Snapshot {} already exists", index);
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let clock = Clock::from_account_info(clock_info)?;
+        let mint_supply = spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())?.supply;
+        let mut snapshot = PoolSnapshot {
+            version: 1,
+            stake_pool: *stake_pool_info.key,
+            index,
+            slot: clock.slot,
+            unix_timestamp: clock.unix_timestamp,
+            epoch: clock.epoch,
+            total_staked: stake_pool.total_staked,
+            total_shares: stake_pool.total_shares,
+            mint_supply,
+            total_pending_withdrawal: stake_pool.total_pending_withdrawal,
+            prev_hash,
+            hash: [0u8; 32],
+        };
+        snapshot.hash = snapshot.compute_hash()?;
+
+        let serialized_data = snapshot.to_account_data()?;
+        create_or_allocate_account_raw(
+            program_id,
+            snapshot_info,
+            rent_info,
+            system_program_info,
+            payer_info,
+            serialized_data.len(),
+            &[SNAPSHOT_SEED, stake_pool_info.key.as_ref(), &index_bytes, &[snapshot_bump]],
+        )?;
+        snapshot_info.data.borrow_mut().copy_from_slice(&serialized_data);
+
+        msg!("Snapshot {} taken at slot {}: hash {}", index, clock.slot,
+             solana_program::hash::Hash::new_from_array(snapshot.hash));
+        Ok(())
+    }
+
+    /// Returns the pool's configured stake limits and the effective minimum.
+    fn process_get_stake_limits(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake program id
+        let stake_program_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        if *stake_program_info.key != solana_program::stake::program::id() {
+            msg!("Incorrect stake program: {}", stake_program_info.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        // Same rule as effective_min_stake, without querying the stake program twice
+        let minimum_delegation = solana_program::stake::tools::get_minimum_delegation()?;
+        let limits = StakeLimits {
+            min_stake: stake_pool.min_stake,
+            max_stake: stake_pool.max_stake,
+            minimum_delegation,
+            effective_min_stake: stake_pool.min_stake.max(minimum_delegation),
+        };
+        msg!("Stake limits: {}-{} lamports", limits.effective_min_stake, limits.max_stake);
+        set_return_data(&limits.try_to_vec()?);
+        Ok(())
+    }
+
+    /// Applies the authority's changes to the pool's fee, stake limits and pause state.
+    fn process_update_config(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        fee_percentage: Option<u8>,
+        min_stake: Option<u64>,
+        max_stake: Option<u64>,
+        paused: Option<bool>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_admin(authority_info, &stake_pool)?;
+
+        if fee_percentage.is_none() && min_stake.is_none() && max_stake.is_none() && paused.is_none() {
+            msg!("No pool config changes requested");
+            return Ok(());
+        }
+
+        if let Some(fee_percentage) = fee_percentage {
+            if fee_percentage > 100 {
+                msg!("Fee percentage must be 0-100");
+                return Err(StakePoolError::InvalidFeePercentage.into());
+            }
+            stake_pool.fee_percentage = fee_percentage;
+        }
+        stake_pool.min_stake = min_stake.unwrap_or(stake_pool.min_stake);
+        stake_pool.max_stake = max_stake.unwrap_or(stake_pool.max_stake);
+        // Checked on the resulting pair, so either bound can move on its own
+        if stake_pool.min_stake > stake_pool.max_stake {
+            msg!("Minimum stake {} exceeds maximum stake {}", stake_pool.min_stake, stake_pool.max_stake);
+            return Err(ProgramError::InvalidArgument);
+        }
+        stake_pool.paused = paused.unwrap_or(stake_pool.paused);
+
+        // The name is unchanged, so the encoding has the same length as the account
+        // data it was read from and the fields after it stay in place.
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Pool config: fee {}%, stake {}-{} lamports, paused {}",
+             stake_pool.fee_percentage, stake_pool.min_stake, stake_pool.max_stake, stake_pool.paused);
+        Ok(())
+    }
+
+    /// Loads the pool's validator list. Only `AddValidator` creates accounts with the
+    /// list's discriminator, always at the pool's PDA, so owner, tag and pool together
+    /// identify it without re-deriving the address on every deposit.
+    fn load_validator_list(
+        program_id: &Pubkey,
+        stake_pool_key: &Pubkey,
+        validator_list_info: &AccountInfo,
+    ) -> Result<ValidatorList, ProgramError> {
+        assert_owned_by(validator_list_info, program_id)?;
+        let validator_list = ValidatorList::unpack_account(&validator_list_info.data.borrow())?;
+        if !validator_list.is_initialized() {
+            msg!("Validator list not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if validator_list.stake_pool != *stake_pool_key {
+            msg!("Validator list belongs to pool {}", validator_list.stake_pool);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(validator_list)
+    }
+
+    /// Finds the pool's validator list among a handler's optional accounts by its derived
+    /// address. `None` for a pool without a list.
+    fn optional_validator_list<'a, 'b>(
+        program_id: &Pubkey,
+        stake_pool_key: &Pubkey,
+        stake_pool: &StakePool,
+        optional_infos: &'b [AccountInfo<'a>],
+    ) -> Result<Option<(&'b AccountInfo<'a>, ValidatorList)>, ProgramError> {
+        if !stake_pool.has_validator_list {
+            return Ok(None);
+        }
+        let (validator_list_address, _) = Pubkey::find_program_address(
+            &[VALIDATOR_LIST_SEED, stake_pool_key.as_ref()],
+            program_id,
+        );
+        let validator_list_info = optional_infos
+            .iter()
+            .find(|info| *info.key == validator_list_address)
+            .ok_or_else(|| {
+                msg!("Pool has a validator list; pass {} so validator stake stays up to date", validator_list_address);
+                ProgramError::NotEnoughAccountKeys
+            })?;
+        let validator_list = Self::load_validator_list(program_id, stake_pool_key, validator_list_info)?;
+        Ok(Some((validator_list_info, validator_list)))
+    }
+
+    /// The rent payer among a deposit's optional accounts: the first one marked as a
+    /// signer. The other optional accounts are PDAs, which never sign a transaction.
+    fn optional_rent_payer<'a, 'b>(optional_infos: &'b [AccountInfo<'a>]) -> Option<&'b AccountInfo<'a>> {
+        optional_infos.iter().find(|info| info.is_signer)
+    }
+
+    /// Finds the stake record PDA of `stake_account_key` among a handler's optional
+    /// accounts by its derived address, with its bump
+    fn optional_stake_record<'a, 'b>(
+        program_id: &Pubkey,
+        stake_account_key: &Pubkey,
+        optional_infos: &'b [AccountInfo<'a>],
+    ) -> Result<(&'b AccountInfo<'a>, u8), ProgramError> {
+        let (stake_record_address, stake_record_bump) =
+            Pubkey::find_program_address(&[STAKE_RECORD_SEED, stake_account_key.as_ref()], program_id);
+        let stake_record_info = optional_infos
+            .iter()
+            .find(|info| *info.key == stake_record_address)
+            .ok_or_else(|| {
+                msg!("Pass the stake record {} of stake account {}", stake_record_address, stake_account_key);
+                ProgramError::NotEnoughAccountKeys
+            })?;
+        Ok((stake_record_info, stake_record_bump))
+    }
+
+    /// Loads a stake record. `None` while it was never created, as for stake accounts
+    /// delegated before stake records existed.
+    fn load_stake_record(
+        program_id: &Pubkey,
+        stake_account_key: &Pubkey,
+        stake_record_info: &AccountInfo,
+    ) -> Result<Option<StakeRecord>, ProgramError> {
+        if *stake_record_info.owner == solana_program::system_program::id() && stake_record_info.data_is_empty() {
+            return Ok(None);
+        }
+        assert_owned_by(stake_record_info, program_id)?;
+        let stake_record = StakeRecord::unpack_account(&stake_record_info.data.borrow())?;
+        if !stake_record.is_initialized() {
+            msg!("Stake record not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_record.stake_account != *stake_account_key {
+            msg!("Stake record belongs to stake account {}", stake_record.stake_account);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(Some(stake_record))
+    }
+
+    /// Lamports still needed to make a stake record rent-exempt, 0 once it exists
+    fn stake_record_rent(program_id: &Pubkey, stake_record_info: &AccountInfo) -> Result<u64, ProgramError> {
+        if *stake_record_info.owner == *program_id {
+            return Ok(0);
+        }
+        let size = StakeRecord::default().to_account_data()?.len();
+        Ok(Rent::get()?.minimum_balance(size).saturating_sub(stake_record_info.lamports()))
+    }
+
+    /// Writes a stake record, first creating its PDA with rent from `payer_info` if needed
+    fn write_stake_record<'a>(
+        program_id: &Pubkey,
+        stake_record_info: &AccountInfo<'a>,
+        stake_record_bump: u8,
+        rent_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        payer_info: &AccountInfo<'a>,
+        stake_record: &StakeRecord,
+    ) -> ProgramResult {
+        if *stake_record_info.owner != *program_id {
+            create_or_allocate_account_raw(
+                program_id,
+                stake_record_info,
+                rent_info,
+                system_program_info,
+                payer_info,
+                stake_record.to_account_data()?.len(),
+                &[STAKE_RECORD_SEED, stake_record.stake_account.as_ref(), &[stake_record_bump]],
+            )?;
+        }
+        stake_record.pack_account(&mut stake_record_info.data.borrow_mut())
+    }
+
+    /// Adds a validator to the pool's validator list. The first call creates the list
+    /// with the Helius validator in it, credited with all stake delegated so far.
+    fn process_add_validator(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_validators: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer, writable]` Stake pool authority (pays rent for a new list)
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[writable]` Validator list PDA
+        let validator_list_info = next_account_info(account_info_iter)?;
+        // 3. `[]` Vote account of the validator to add
+        let validator_vote_info = next_account_info(account_info_iter)?;
+        // 4. `[]` System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+        // 5. `[]` Rent sysvar
+        let rent_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_admin(authority_info, &stake_pool)?;
+        // Fails unless it is a vote account
+        vote_account_commission(validator_vote_info)?;
+        if stake_pool.require_voting_validator {
+            // 6. `[]` Clock sysvar
+            let clock_info = next_account_info(account_info_iter)?;
+            assert_sysvar(clock_info, &solana_program::sysvar::clock::id())?;
+            assert_vote_account_live(validator_vote_info, Clock::from_account_info(clock_info)?.slot)?;
+        }
+
+        let (expected_validator_list_pda, validator_list_bump) = Pubkey::find_program_address(
+            &[VALIDATOR_LIST_SEED, stake_pool_info.key.as_ref()],
+            program_id,
+        );
+        if expected_validator_list_pda != *validator_list_info.key {
+            msg!("Provided validator list {} does not match derived PDA {}",
+                 validator_list_info.key, expected_validator_list_pda);
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let mut validator_list = if validator_list_info.data_is_empty() {
+            // Room for at least the Helius validator and the one being added
+            if !(2..=MAX_VALIDATORS).contains(&max_validators) {
+                msg!("Validator list size must be 2-{}", MAX_VALIDATORS);
+                return Err(ProgramError::InvalidArgument);
+            }
+            create_or_allocate_account_raw(
+                program_id,
+                validator_list_info,
+                rent_info,
+                system_program_info,
+                authority_info,
+                ValidatorList::account_size(max_validators),
+                &[VALIDATOR_LIST_SEED, stake_pool_info.key.as_ref(), &[validator_list_bump]],
+            )?;
+            stake_pool.has_validator_list = true;
+            stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+            msg!("Validator list created");
+            ValidatorList {
+                version: 1,
+                stake_pool: *stake_pool_info.key,
+                max_validators,
+                validators: vec![ValidatorEntry {
+                    vote_account: stake_pool.helius_validator_vote,
+                    active_stake_lamports: stake_pool.total_staked,
+                    status: ValidatorStatus::Active,
+                }],
+            }
+        } else {
+            Self::load_validator_list(program_id, stake_pool_info.key, validator_list_info)?
+        };
+
+        if validator_list.find(validator_vote_info.key).is_some() {
+            msg!("Validator {} is already in the list", validator_vote_info.key);
+            return Err(StakePoolError::ValidatorAlreadyInList.into());
+        }
+        if validator_list.validators.len() >= validator_list.max_validators as usize {
+            msg!("Validator list is full ({} validators); grow it with GrowValidatorList", validator_list.max_validators);
+            return Err(StakePoolError::ValidatorListFull.into());
+        }
+        validator_list.validators.push(ValidatorEntry {
+            vote_account: *validator_vote_info.key,
+            active_stake_lamports: 0,
+            status: ValidatorStatus::Active,
+        });
+        validator_list.pack_account(&mut validator_list_info.data.borrow_mut())?;
+
+        msg!("Added validator {} ({} of {})", validator_vote_info.key,
+             validator_list.validators.len(), validator_list.max_validators);
+        Ok(())
+    }
+
+    /// Removes a validator without stake from the pool's validator list. The last
+    /// validator stays, since `Stake` needs a listed validator to delegate to.
+    fn process_remove_validator(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        vote_account: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[writable]` Validator list PDA
+        let validator_list_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_admin(authority_info, &stake_pool)?;
+        let mut validator_list = Self::load_validator_list(program_id, stake_pool_info.key, validator_list_info)?;
+
+        let validator_index = validator_list.find(vote_account).ok_or_else(|| {
+            msg!("Validator {} is not in the list", vote_account);
+            StakePoolError::ValidatorNotInList
+        })?;
+        let active_stake_lamports = validator_list.validators[validator_index].active_stake_lamports;
+        if active_stake_lamports > 0 {
+            msg!("Validator {} still has {} lamports of active stake", vote_account, active_stake_lamports);
+            return Err(StakePoolError::ValidatorHasStake.into());
+        }
+        if validator_list.validators.len() == 1 {
+            msg!("Cannot remove the only validator in the list");
+            return Err(ProgramError::InvalidArgument);
+        }
+        validator_list.validators.remove(validator_index);
+        validator_list.pack_account(&mut validator_list_info.data.borrow_mut())?;
+
+        msg!("Removed validator {}", vote_account);
+        Ok(())
+    }
+
+    /// Transfers whatever the stake pool account lacks for rent exemption from the payer.
+    fn process_top_up_pool(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer, writable]` Payer
+        let payer_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[]` System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        if !payer_info.is_signer {
+            msg!("Payer signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+        // Only top up an actual pool account
+        StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+
+        let rent_exempt_minimum = Rent::get()?.minimum_balance(stake_pool_info.data_len());
+        let shortfall = rent_exempt_minimum.saturating_sub(stake_pool_info.lamports());
+        if shortfall == 0 {
+            msg!("Stake pool is already rent-exempt");
+            return Ok(());
+        }
+
+        invoke(
+            &system_instruction::transfer(payer_info.key, stake_pool_info.key, shortfall),
+            &[
+                payer_info.clone(),
+                stake_pool_info.clone(),
+                system_program_info.clone(),
+            ],
+        ).map_err(cpi_step("transfer"))?;
+
+        msg!("Topped up stake pool with {} lamports", shortfall);
+        Ok(())
+    }
+
+    /// Sums the stake held in the passed user stake accounts and compares it with the
+    /// value of the obeSOL supply plus pending withdrawals.
+    fn process_verify_backing(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Pool token mint
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // 2. `[]` Users' stake account PDAs, as many as follow
+        let stake_account_infos = account_info_iter.as_slice();
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        assert_owned_by(pool_mint_info, &spl_token::id())?;
+        if stake_pool.mint != *pool_mint_info.key {
+            msg!("Invalid pool mint");
+            return Err(StakePoolError::InvalidMintAuthority.into());
+        }
+        let mint_supply = spl_token::state::Mint::unpack(&pool_mint_info.data.borrow())?.supply;
+
+        let mut delegated_stake = 0u64;
+        let mut pending_stake = 0u64;
+        for (position, stake_account_info) in stake_account_infos.iter().enumerate() {
+            // Counting an account twice would overstate the backing
+            if stake_account_infos[..position].iter().any(|earlier| earlier.key == stake_account_info.key) {
+                msg!("Stake account {} passed more than once", stake_account_info.key);
+                return Err(ProgramError::InvalidArgument);
+            }
+            assert_owned_by(stake_account_info, &solana_program::stake::program::id())?;
+            let state = StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..])?;
+            let meta = state.meta().ok_or_else(|| {
+                msg!("Stake account {} is not initialized", stake_account_info.key);
+                StakePoolError::WrongStakeState
+            })?;
+            // Only stake the pool controls counts: the pool's stake authority as staker
+            // and its withdraw authority as withdrawer, which every user stake PDA and
+            // split stake PDA is created with
+            if meta.authorized.staker != stake_pool.stake_authority
+                || meta.authorized.withdrawer != stake_pool.withdraw_authority
+            {
+                msg!("Stake account {} is not a stake account of this pool", stake_account_info.key);
+                return Err(StakePoolError::InvalidStakeAccountDelegation.into());
+            }
+            if let Some(stake) = state.stake() {
+                if stake.delegation.deactivation_epoch == u64::MAX {
+                    delegated_stake = safe_math::add(delegated_stake, stake.delegation.stake)?;
+                } else {
+                    pending_stake = safe_math::add(pending_stake, stake.delegation.stake)?;
+                }
+            }
+        }
+
+        let total_backing = safe_math::add(delegated_stake, pending_stake)?;
+        let booked_stake = safe_math::add(stake_pool.total_staked, stake_pool.total_pending_withdrawal)?;
+        let supply_value = stake_pool.tokens_to_lamports(mint_supply)?;
+        let obligations = safe_math::add(supply_value, stake_pool.total_pending_withdrawal)?;
+        let backing_ratio_bps = if obligations == 0 {
+            u64::MAX
+        } else {
+            safe_math::mul_div(total_backing, 10_000, obligations).unwrap_or(u64::MAX)
+        };
+        let report = BackingReport {
+            stake_accounts: stake_account_infos.len() as u32,
+            delegated_stake,
+            pending_stake,
+            total_backing,
+            mint_supply,
+            supply_value,
+            obligations,
+            backing_ratio_bps,
+            fully_backed: total_backing >= obligations,
+            shortfall: obligations.saturating_sub(total_backing),
+            partial: total_backing < booked_stake,
+        };
+
+        if report.fully_backed {
+            msg!("Fully backed: {} lamports of stake for {} owed", total_backing, obligations);
+        } else if report.partial {
+            msg!("Under-backed by the {} stake accounts passed: {} lamports of stake for {} owed, {} booked",
+                 report.stake_accounts, total_backing, obligations, booked_stake);
+        } else {
+            msg!("Under-backed: {} lamports of stake for {} owed, short {}",
+                 total_backing, obligations, report.shortfall);
+        }
+        set_return_data(&report.try_to_vec()?);
+        Ok(())
+    }
+
+    /// Raises the validator list's capacity to `max_validators`, growing its account
+    /// and topping up rent from the authority.
+    fn process_grow_validator_list(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_validators: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer, writable]` Stake pool authority (pays rent for the added space)
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[writable]` Validator list PDA
+        let validator_list_info = next_account_info(account_info_iter)?;
+        // 3. `[]` System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_admin(authority_info, &stake_pool)?;
+        let mut validator_list = Self::load_validator_list(program_id, stake_pool_info.key, validator_list_info)?;
+
+        if max_validators <= validator_list.max_validators || max_validators > MAX_VALIDATORS {
+            msg!("Validator list can only grow, from {} up to {}", validator_list.max_validators, MAX_VALIDATORS);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let new_len = ValidatorList::account_size(max_validators);
+        let required_lamports = Rent::get()?.minimum_balance(new_len);
+        if validator_list_info.lamports() < required_lamports {
+            invoke(
+                &system_instruction::transfer(
+                    authority_info.key,
+                    validator_list_info.key,
+                    required_lamports - validator_list_info.lamports(),
+                ),
+                &[authority_info.clone(), validator_list_info.clone(), system_program_info.clone()],
+            ).map_err(cpi_step("transfer"))?;
+        }
+        validator_list_info.realloc(new_len, false)?;
+        validator_list.max_validators = max_validators;
+        validator_list.pack_account(&mut validator_list_info.data.borrow_mut())?;
+
+        msg!("Validator list grown to {} validators", max_validators);
+        Ok(())
+    }
+
+    /// Sets the per-validator stake cap. Existing delegations are left in place.
+    fn process_update_validator_cap(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_validator_stake_bps: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_admin(authority_info, &stake_pool)?;
+        if !(MIN_VALIDATOR_STAKE_BPS..=10_000).contains(&max_validator_stake_bps) {
+            msg!("Validator stake cap must be {}-10000 bps", MIN_VALIDATOR_STAKE_BPS);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        stake_pool.max_validator_stake_bps = max_validator_stake_bps;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Validator stake cap set to {} bps", max_validator_stake_bps);
+        Ok(())
+    }
+
+    /// Turns the epoch advance at the start of `Stake`/`Unstake` on or off
+    fn process_set_auto_claim(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        enabled: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_admin(authority_info, &stake_pool)?;
+
+        stake_pool.auto_claim = enabled;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Auto-claim {}", if enabled { "enabled" } else { "disabled" });
+        Ok(())
+    }
+
+    /// Moves a pool stake account between listed validators: deactivates it on the first
+    /// call and, once it has cooled down, delegates it to the destination on the second.
+    fn process_migrate_validator_stake(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        from_index: u32,
+        to_index: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // 2. `[writable]` Validator list PDA
+        let validator_list_info = next_account_info(account_info_iter)?;
+        // 3. `[writable]` Stake account
+        let stake_account_info = next_account_info(account_info_iter)?;
+        // 4. `[]` Stake record PDA of the stake account
+        let stake_record_info = next_account_info(account_info_iter)?;
+        // 5. `[]` Vote account of the destination validator
+        let validator_vote_info = next_account_info(account_info_iter)?;
+        // 6. `[]` Clock sysvar
+        let clock_info = next_account_info(account_info_iter)?;
+        // 7. `[]` Stake history sysvar
+        let stake_history_info = next_account_info(account_info_iter)?;
+        // 8. `[]` Stake config account
+        let stake_config_info = next_account_info(account_info_iter)?;
+        // 9. `[]` Stake authority PDA
+        let stake_authority_info = next_account_info(account_info_iter)?;
+        // 10. `[]` Stake program id
+        let stake_program_info = next_account_info(account_info_iter)?;
+
+        assert_owned_by(stake_pool_info, program_id)?;
+        let stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        SecurityManager::verify_admin(authority_info, &stake_pool)?;
+        assert_authority_bumps(program_id, stake_pool_info.key, &stake_pool)?;
+        assert_program_id(stake_program_info, &solana_program::stake::program::id())?;
+        assert_sysvar(clock_info, &solana_program::sysvar::clock::id())?;
+        assert_sysvar(stake_history_info, &solana_program::sysvar::stake_history::id())?;
+        if *stake_authority_info.key != stake_pool.stake_authority {
+            msg!("Incorrect stake authority provided");
+            return Err(StakePoolError::InvalidStakeAuthority.into());
+        }
+        let mut validator_list = Self::load_validator_list(program_id, stake_pool_info.key, validator_list_info)?;
+        let validator_count = validator_list.validators.len();
+        if from_index as usize >= validator_count || to_index as usize >= validator_count {
+            msg!("Validator index out of range: the list holds {} validators", validator_count);
+            return Err(StakePoolError::ValidatorNotInList.into());
+        }
+        if from_index == to_index {
+            msg!("Source and destination validator are the same");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Only the record tells a migrating account from one its owner unstaked, so an
+        // account staked before records existed can't be moved
+        assert_owned_by(stake_account_info, &solana_program::stake::program::id())?;
+        let (stake_record_address, _) = Pubkey::find_program_address(
+            &[STAKE_RECORD_SEED, stake_account_info.key.as_ref()],
+            program_id,
+        );
+        if *stake_record_info.key != stake_record_address {
+            msg!("Stake record {} does not match derived PDA {}", stake_record_info.key, stake_record_address);
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let stake_record = Self::load_stake_record(program_id, stake_account_info.key, stake_record_info)?
+            .filter(|record| record.stake_pool == *stake_pool_info.key)
+            .ok_or_else(|| {
+                msg!("Stake account {} has no stake record of this pool", stake_account_info.key);
+                StakePoolError::WrongStakeState
+            })?;
+
+        let StakeStateV2::Stake(meta, stake, _) = StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..])? else {
+            msg!("Stake account {} is not delegated", stake_account_info.key);
+            return Err(StakePoolError::WrongStakeState.into());
+        };
+        if meta.authorized.staker != stake_pool.stake_authority {
+            msg!("Stake account {} is not a stake account of this pool", stake_account_info.key);
+            return Err(StakePoolError::InvalidStakeAccountDelegation.into());
+        }
+        let stake_authority_seeds = &[STAKE_AUTHORITY_SEED, stake_pool_info.key.as_ref(), &[stake_pool.stake_authority_bump_seed]];
+        let current_epoch = Clock::from_account_info(clock_info)?.epoch;
+
+        if stake.delegation.deactivation_epoch == u64::MAX {
+            // Step 1: leave the source validator
+            let source = &mut validator_list.validators[from_index as usize];
+            if stake.delegation.voter_pubkey != source.vote_account {
+                msg!("Stake account is delegated to {}, not validator {}", stake.delegation.voter_pubkey, source.vote_account);
+                return Err(StakePoolError::InvalidStakeAccountDelegation.into());
+            }
+            invoke_signed(
+                &stake_instruction::deactivate_stake(stake_account_info.key, &stake_pool.stake_authority),
+                &[
+                    stake_program_info.clone(),
+                    stake_account_info.clone(),
+                    clock_info.clone(),
+                    stake_authority_info.clone(),
+                ],
+                &[stake_authority_seeds],
+            ).map_err(cpi_step("deactivate_stake"))?;
+            source.active_stake_lamports = source.active_stake_lamports.saturating_sub(stake.delegation.stake);
+            msg!("Deactivated {} lamports on validator {}; migrate again once it has cooled down",
+                 stake.delegation.stake, source.vote_account);
+            validator_list.pack_account(&mut validator_list_info.data.borrow_mut())?;
+            return Ok(());
+        }
+
+        // Step 2: join the destination validator. A deactivated account with tokens
+        // still minted against it and nothing pending withdrawal was deactivated here.
+        if stake_record.pool_tokens == 0 || stake_record.pending_withdrawal != 0 {
+            msg!("Stake account {} was unstaked by its owner", stake_account_info.key);
+            return Err(StakePoolError::WrongStakeState.into());
+        }
+        if !cooldown_complete(stake.delegation.deactivation_epoch, current_epoch) {
+            msg!("Stake deactivated in epoch {} is still cooling down", stake.delegation.deactivation_epoch);
+            return Err(StakePoolError::CooldownNotPassed.into());
+        }
+        let destination = &mut validator_list.validators[to_index as usize];
+        if *validator_vote_info.key != destination.vote_account {
+            msg!("Vote account {} is not validator {}", validator_vote_info.key, destination.vote_account);
+            return Err(StakePoolError::InvalidVoteAccount.into());
+        }
+        invoke_signed(
+            &stake_instruction::delegate_stake(stake_account_info.key, &stake_pool.stake_authority, validator_vote_info.key),
+            &[
+                stake_program_info.clone(),
+                stake_account_info.clone(),
+                validator_vote_info.clone(),
+                clock_info.clone(),
+                stake_history_info.clone(),
+                stake_config_info.clone(),
+                stake_authority_info.clone(),
+            ],
+            &[stake_authority_seeds],
+        ).map_err(cpi_step("delegate_stake"))?;
+        let delegated_stake = match StakeStateV2::deserialize(&mut &stake_account_info.data.borrow()[..])? {
+            StakeStateV2::Stake(_, stake, _) => stake.delegation.stake,
+            _ => return Err(StakePoolError::WrongStakeState.into()),
+        };
+        destination.active_stake_lamports = safe_math::add(destination.active_stake_lamports, delegated_stake)?;
+        msg!("Delegated {} lamports to validator {}", delegated_stake, destination.vote_account);
+        validator_list.pack_account(&mut validator_list_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Test-only: sets the pool's time-sensitive bookkeeping directly so tests can
+    /// fast-forward epochs without warping the bank.
+    #[cfg(any(test, feature = "test-utils"))]
+    fn process_warp_pool_epoch(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        last_update_epoch: u64,
+        unstake_tracking_epoch: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. `[signer]` Stake pool authority
+        let authority_info = next_account_info(account_info_iter)?;
+        // 1. `[writable]` Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            msg!("Authority signature missing");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        assert_owned_by(stake_pool_info, program_id)?;
+
+        let mut stake_pool = StakePool::unpack_account(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            msg!("Stake pool not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if stake_pool.authority != *authority_info.key {
+            msg!("Signer is not the pool authority");
+            return Err(StakePoolError::InvalidAuthority.into());
+        }
+
+        stake_pool.last_update_epoch = last_update_epoch;
+        stake_pool.unstake_tracking_epoch = unstake_tracking_epoch;
+        stake_pool.pack_account(&mut stake_pool_info.data.borrow_mut())?;
+
+        msg!("Pool epochs warped: last_update_epoch={}, unstake_tracking_epoch={}",
+             last_update_epoch, unstake_tracking_epoch);
+        Ok(())
+    }
+} // <-- ADDED Closing brace for impl Processor
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client,
+        state::{ReturnPage, POOL_FLAG_PAUSED, POOL_FLAG_UNSTAKE_LIMIT, PRICE_SCALE},
+        utils::MAX_VOTE_AGE_SLOTS,
+    };
+    use solana_program::{
+        instruction::{AccountMeta, Instruction, InstructionError},
+        native_token::LAMPORTS_PER_SOL,
+        program_option::COption,
+        system_program, sysvar,
+        vote::state::{VoteInit, VoteState, VoteStateVersions},
+    };
+    use solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext};
+    use solana_sdk::{
+        account::Account,
+        message::Message,
+        signature::{Keypair, Signer},
+        transaction::{Transaction, TransactionError},
+    };
+
+    const STAKE_AMOUNT: u64 = 10 * LAMPORTS_PER_SOL;
+
+    /// A pool initialized by the test context's payer, delegating to `validator_vote`
+    struct TestPool {
+        program_id: Pubkey,
+        addresses: client::PoolAddresses,
+        validator_vote: Pubkey,
+    }
+
+    fn vote_account() -> Account {
+        vote_account_with_votes(&[])
+    }
+
+    /// A vote account that voted on `slots`
+    fn vote_account_with_votes(slots: &[u64]) -> Account {
+        let mut vote_state = VoteState::new(
+            &VoteInit {
+                node_pubkey: Pubkey::new_unique(),
+                authorized_voter: Pubkey::new_unique(),
+                authorized_withdrawer: Pubkey::new_unique(),
+                commission: 0,
+            },
+            &Clock::default(),
+        );
+        for &slot in slots {
+            vote_state.process_next_vote_slot(slot, 0, slot);
+        }
+        let mut data = vec![0; VoteState::size_of()];
+        VoteState::serialize(&VoteStateVersions::new_current(vote_state), &mut data).unwrap();
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: solana_program::vote::program::id(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    /// Starts a bank with the program, the pool validator and `extra_validators` more
+    /// vote accounts, and initializes a pool
+    async fn setup(extra_validators: usize) -> (ProgramTestContext, TestPool, Vec<Pubkey>) {
+        let program_id = Pubkey::new_unique();
+        let mut program_test = ProgramTest::new("obe_sol", program_id, processor!(crate::process_instruction));
+        let validator_vote = Pubkey::new_unique();
+        program_test.add_account(validator_vote, vote_account());
+        let extra_votes: Vec<Pubkey> = (0..extra_validators).map(|_| Pubkey::new_unique()).collect();
+        for vote in &extra_votes {
+            program_test.add_account(*vote, vote_account());
+        }
+        let mut context = program_test.start_with_context().await;
+
+        let authority = context.payer.pubkey();
+        let initialize = client::initialize(
+            &program_id,
+            &authority,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            "Test Pool".to_string(),
+            0,
+            &validator_vote,
+        );
+        send(&mut context, &[initialize], &[]).await.unwrap();
+
+        let pool = TestPool { program_id, addresses: client::PoolAddresses::derive(&authority, &program_id), validator_vote };
+        (context, pool, extra_votes)
+    }
+
+    /// Sends `instructions` paid by the context payer and signed by it and `signers`
+    async fn send(
+        context: &mut ProgramTestContext,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), TransactionError> {
+        let mut all_signers = vec![&context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&context.payer.pubkey()),
+            &all_signers,
+            context.last_blockhash,
+        );
+        context.banks_client.process_transaction(transaction).await.map_err(|err| err.unwrap())
+    }
+
+    fn assert_pool_error(result: Result<(), TransactionError>, expected: StakePoolError) {
+        match result {
+            Err(TransactionError::InstructionError(_, InstructionError::Custom(code))) => {
+                assert_eq!(code, expected as u32, "expected {:?}", expected)
+            }
+            other => panic!("expected {:?}, got {:?}", expected, other),
+        }
+    }
+
+    /// Runs a read-only instruction and decodes its return data
+    async fn query<T: BorshDeserialize>(context: &mut ProgramTestContext, instruction: Instruction) -> T {
+        query_signed(context, instruction, &[]).await
+    }
+
+    /// Simulates `instruction` signed by the context payer and `signers`, and decodes
+    /// its return data
+    async fn query_signed<T: BorshDeserialize>(
+        context: &mut ProgramTestContext,
+        instruction: Instruction,
+        signers: &[&Keypair],
+    ) -> T {
+        let mut all_signers = vec![&context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&context.payer.pubkey()),
+            &all_signers,
+            context.last_blockhash,
+        );
+        let simulation = context.banks_client.simulate_transaction(transaction).await.unwrap();
+        simulation.result.unwrap().unwrap();
+        let return_data = simulation.simulation_details.unwrap().return_data.unwrap();
+        T::try_from_slice(&return_data.data).unwrap()
+    }
+
+    /// A funded wallet with an obeSOL associated token account
+    async fn create_user(context: &mut ProgramTestContext, pool: &TestPool) -> Keypair {
+        let user = Keypair::new();
+        let instructions = [
+            system_instruction::transfer(&context.payer.pubkey(), &user.pubkey(), 100 * LAMPORTS_PER_SOL),
+            spl_associated_token_account::instruction::create_associated_token_account(
+                &context.payer.pubkey(),
+                &user.pubkey(),
+                &pool.addresses.mint,
+                &spl_token::id(),
+            ),
+        ];
+        send(context, &instructions, &[]).await.unwrap();
+        user
+    }
+
+    /// Moves the bank to the first slot of the next epoch, past its reward distribution
+    async fn advance_epoch(context: &mut ProgramTestContext) {
+        let epoch = current_epoch(context).await;
+        context.warp_to_epoch(epoch + 1).unwrap();
+        context.warp_forward_force_reward_interval_end().unwrap();
+    }
+
+    async fn current_epoch(context: &mut ProgramTestContext) -> u64 {
+        context.banks_client.get_sysvar::<Clock>().await.unwrap().epoch
+    }
+
+    async fn lamports(context: &mut ProgramTestContext, address: &Pubkey) -> u64 {
+        context.banks_client.get_balance(*address).await.unwrap()
+    }
+
+    async fn pool_state(context: &mut ProgramTestContext, pool: &TestPool) -> StakePool {
+        let account = context.banks_client.get_account(pool.addresses.stake_pool).await.unwrap().unwrap();
+        StakePool::unpack_account(&account.data).unwrap()
+    }
+
+    async fn stake_state(context: &mut ProgramTestContext, address: &Pubkey) -> StakeStateV2 {
+        let account = context.banks_client.get_account(*address).await.unwrap().unwrap();
+        StakeStateV2::deserialize(&mut &account.data[..]).unwrap()
+    }
+
+    async fn token_balance(context: &mut ProgramTestContext, pool: &TestPool, user: &Pubkey) -> u64 {
+        let address = spl_associated_token_account::get_associated_token_address(user, &pool.addresses.mint);
+        let account = context.banks_client.get_account(address).await.unwrap().unwrap();
+        spl_token::state::Account::unpack(&account.data).unwrap().amount
+    }
+
+    async fn transfer_tokens(context: &mut ProgramTestContext, pool: &TestPool, from: &Keypair, to: &Pubkey, amount: u64) {
+        let transfer = spl_token::instruction::transfer(
+            &spl_token::id(),
+            &spl_associated_token_account::get_associated_token_address(&from.pubkey(), &pool.addresses.mint),
+            &spl_associated_token_account::get_associated_token_address(to, &pool.addresses.mint),
+            &from.pubkey(),
+            &[],
+            amount,
+        )
+        .unwrap();
+        send(context, &[transfer], &[from]).await.unwrap();
+    }
+
+    /// An instruction the client module has no builder for
+    fn pool_ix(pool: &TestPool, instruction: StakePoolInstruction, accounts: Vec<AccountMeta>) -> Instruction {
+        Instruction::new_with_bytes(pool.program_id, &instruction.try_to_vec().unwrap(), accounts)
+    }
+
+    fn stake_ix(pool: &TestPool, user: &Pubkey, amount: u64) -> Instruction {
+        client::stake(&pool.program_id, user, &pool.addresses.stake_pool, &pool.validator_vote, amount)
+    }
+
+    fn user_stake_account(pool: &TestPool, user: &Pubkey) -> Pubkey {
+        pool.addresses.user_stake_account(user, &pool.program_id).0
+    }
+
+    /// An admin instruction taking only the authority and the pool
+    fn admin_ix(pool: &TestPool, authority: &Pubkey, instruction: StakePoolInstruction) -> Instruction {
+        pool_ix(
+            pool,
+            instruction,
+            vec![AccountMeta::new_readonly(*authority, true), AccountMeta::new(pool.addresses.stake_pool, false)],
+        )
+    }
+
+    fn update_config(paused: Option<bool>, max_stake: Option<u64>) -> StakePoolInstruction {
+        StakePoolInstruction::UpdatePoolConfig { fee_percentage: None, min_stake: None, max_stake, paused }
+    }
+
+    fn assert_instruction_error(result: Result<(), TransactionError>, expected: InstructionError) {
+        assert_eq!(result.unwrap_err(), TransactionError::InstructionError(0, expected));
+    }
+
+    fn stake_record(pool: &TestPool, stake_account: &Pubkey) -> Pubkey {
+        client::find_stake_record_address(stake_account, &pool.program_id).0
+    }
+
+    async fn stake_record_state(context: &mut ProgramTestContext, pool: &TestPool, stake_account: &Pubkey) -> StakeRecord {
+        let account = context.banks_client.get_account(stake_record(pool, stake_account)).await.unwrap().unwrap();
+        StakeRecord::unpack_account(&account.data).unwrap()
+    }
+
+    /// Pays `rewards` into a delegated stake account as the epoch's inflation payout
+    /// would, growing its delegation and balance. The test vote accounts never vote, so
+    /// the bank itself pays them nothing. The lamports come out of the payer, keeping the
+    /// bank's capitalization intact for later warps.
+    async fn accrue_rewards(context: &mut ProgramTestContext, address: &Pubkey, rewards: u64) {
+        let payer = context.payer.pubkey();
+        let mut payer_account = context.banks_client.get_account(payer).await.unwrap().unwrap();
+        payer_account.lamports -= rewards;
+        context.set_account(&payer, &payer_account.into());
+
+        let mut account = context.banks_client.get_account(*address).await.unwrap().unwrap();
+        let StakeStateV2::Stake(meta, mut stake, flags) = StakeStateV2::deserialize(&mut &account.data[..]).unwrap() else {
+            panic!("stake account is not delegated");
+        };
+        stake.delegation.stake += rewards;
+        StakeStateV2::Stake(meta, stake, flags).serialize(&mut &mut account.data[..]).unwrap();
+        account.lamports += rewards;
+        context.set_account(address, &account.into());
+    }
+
+    #[tokio::test]
+    async fn pool_addresses_match_initialize() {
+        let (mut context, pool, _) = setup(0).await;
+        let addresses = pool.addresses;
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.mint, addresses.mint);
+        assert_eq!(state.stake_authority, addresses.stake_authority);
+        assert_eq!(state.withdraw_authority, addresses.withdraw_authority);
+        assert_eq!(state.stake_authority_bump_seed, addresses.stake_authority_bump);
+        assert_eq!(state.withdraw_authority_bump_seed, addresses.withdraw_authority_bump);
+
+        let authority = context.payer.pubkey();
+        let seeds: [&[u8]; 3] = [authority.as_ref(), POOL_SEED, &[addresses.stake_pool_bump]];
+        assert_eq!(Pubkey::create_program_address(&seeds, &pool.program_id).unwrap(), addresses.stake_pool);
+        let seeds: [&[u8]; 3] = [addresses.stake_pool.as_ref(), MINT_SEED, &[addresses.mint_bump]];
+        assert_eq!(Pubkey::create_program_address(&seeds, &pool.program_id).unwrap(), addresses.mint);
+
+        // Initialize created the pool and its mint at the derived addresses
+        let pool_account = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+        assert_eq!(pool_account.owner, pool.program_id);
+        let mint_account = context.banks_client.get_account(addresses.mint).await.unwrap().unwrap();
+        let mint = spl_token::state::Mint::unpack(&mint_account.data).unwrap();
+        assert_eq!(mint.mint_authority, COption::Some(addresses.stake_authority));
+    }
+
+    #[tokio::test]
+    async fn corrupted_authority_bump_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let healthy = context.banks_client.get_account(pool.addresses.stake_pool).await.unwrap().unwrap();
+        let corrupt = |context: &mut ProgramTestContext, corrupt_state: fn(&mut StakePool)| {
+            let mut account = healthy.clone();
+            let mut state = StakePool::unpack_account(&account.data).unwrap();
+            corrupt_state(&mut state);
+            state.pack_account(&mut account.data).unwrap();
+            context.set_account(&pool.addresses.stake_pool, &account.into());
+        };
+
+        corrupt(&mut context, |state| state.stake_authority_bump_seed = state.stake_authority_bump_seed.wrapping_sub(1));
+        let result = send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidProgramAddress);
+
+        corrupt(&mut context, |state| state.withdraw_authority_bump_seed = state.withdraw_authority_bump_seed.wrapping_sub(1));
+        let result = send(&mut context, &[stake_ix(&pool, &user.pubkey(), 2 * STAKE_AMOUNT)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidProgramAddress);
+
+        corrupt(&mut context, |_| {});
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), 3 * STAKE_AMOUNT)], &[&user]).await.unwrap();
+    }
+
+    /// Simulates `instruction` with each pair of neighbouring accounts swapped, and returns
+    /// the positions of the pairs the processor accepted anyway
+    async fn accepted_account_swaps(
+        context: &mut ProgramTestContext,
+        instruction: &Instruction,
+        signers: &[&Keypair],
+    ) -> Vec<usize> {
+        let mut accepted = Vec::new();
+        for position in 0..instruction.accounts.len() - 1 {
+            let mut swapped = instruction.clone();
+            swapped.accounts.swap(position, position + 1);
+            let mut all_signers = vec![&context.payer];
+            all_signers.extend_from_slice(signers);
+            let transaction = Transaction::new_signed_with_payer(
+                &[swapped],
+                Some(&context.payer.pubkey()),
+                &all_signers,
+                context.last_blockhash,
+            );
+            let simulation = context.banks_client.simulate_transaction(transaction).await.unwrap();
+            if simulation.result.unwrap().is_ok() {
+                accepted.push(position);
+            }
+        }
+        accepted
+    }
+
+    #[tokio::test]
+    async fn processor_reads_builder_account_order() {
+        let (mut context, pool, _) = setup(0).await;
+
+        let authority = create_user(&mut context, &pool).await;
+        let initialize = client::initialize(
+            &pool.program_id,
+            &authority.pubkey(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            "Contract".to_string(),
+            0,
+            &pool.validator_vote,
+        );
+        // Only the manager and treasury fee accounts, both plain wallets, can trade places
+        assert_eq!(accepted_account_swaps(&mut context, &initialize, &[&authority]).await, vec![3]);
+        send(&mut context, &[initialize], &[&authority]).await.unwrap();
+
+        let user = create_user(&mut context, &pool).await;
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        assert_eq!(accepted_account_swaps(&mut context, &stake, &[&user]).await, Vec::<usize>::new());
+        // A look-alike token program would be handed the mint authority's signature
+        let mut lookalike = stake.clone();
+        lookalike.accounts[StakeAccounts::TokenProgram as usize] = AccountMeta::new_readonly(Pubkey::new_unique(), false);
+        assert_instruction_error(send(&mut context, &[lookalike], &[&user]).await, InstructionError::IncorrectProgramId);
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stake_unstake_withdraw() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+        let StakeStateV2::Stake(meta, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(meta.authorized.staker, pool.addresses.stake_authority);
+        assert_eq!(meta.authorized.withdrawer, pool.addresses.withdraw_authority);
+        assert_eq!(stake.delegation.stake, STAKE_AMOUNT);
+        assert_eq!(stake.delegation.voter_pubkey, pool.validator_vote);
+
+        advance_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, 0);
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_staked, 0);
+        assert_eq!(state.total_pending_withdrawal, STAKE_AMOUNT);
+
+        // Still cooling down
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        assert_pool_error(
+            send(&mut context, std::slice::from_ref(&withdraw), &[&user]).await,
+            StakePoolError::CooldownNotPassed,
+        );
+
+        advance_epoch(&mut context).await;
+        // Only the owner of a stake account can have it paid out
+        let other = create_user(&mut context, &pool).await;
+        let mut foreign_withdraw = client::withdraw_stake(&pool.program_id, &other.pubkey(), &pool.addresses.stake_pool);
+        foreign_withdraw.accounts[2] = AccountMeta::new(stake_account, false);
+        assert_eq!(
+            send(&mut context, &[foreign_withdraw], &[&other]).await.unwrap_err(),
+            TransactionError::InstructionError(0, InstructionError::InvalidSeeds),
+        );
+
+        let stake_account_lamports = lamports(&mut context, &stake_account).await;
+        let record_lamports = lamports(&mut context, &stake_record(&pool, &stake_account)).await;
+        let user_lamports = lamports(&mut context, &user.pubkey()).await;
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+        assert_eq!(
+            lamports(&mut context, &user.pubkey()).await,
+            user_lamports + stake_account_lamports + record_lamports,
+        );
+        assert!(context.banks_client.get_account(stake_account).await.unwrap().is_none());
+        assert!(context.banks_client.get_account(stake_record(&pool, &stake_account)).await.unwrap().is_none());
+        assert_eq!(pool_state(&mut context, &pool).await.total_pending_withdrawal, 0);
+    }
+
+    #[tokio::test]
+    async fn withdraw_opens_the_epoch_after_deactivation() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        let deactivation_epoch = stake.delegation.deactivation_epoch;
+        assert_eq!(current_epoch(&mut context).await, deactivation_epoch);
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        assert_pool_error(
+            send(&mut context, std::slice::from_ref(&withdraw), &[&user]).await,
+            StakePoolError::CooldownNotPassed,
+        );
+
+        advance_epoch(&mut context).await;
+        assert_eq!(current_epoch(&mut context).await, deactivation_epoch + 1);
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+        assert!(context.banks_client.get_account(stake_account).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn partial_unstake_splits_off_burned_value() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let other = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        let remaining = 4 * LAMPORTS_PER_SOL;
+        let unstaked = STAKE_AMOUNT - remaining;
+
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        // Burning the whole token balance still splits when it is worth less than the stake
+        transfer_tokens(&mut context, &pool, &user, &other.pubkey(), remaining).await;
+        advance_epoch(&mut context).await;
+
+        let epoch = current_epoch(&mut context).await;
+        let (split_stake, _) =
+            client::find_split_stake_address(&pool.addresses.stake_pool, &user.pubkey(), epoch, &pool.program_id);
+        let mut unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, unstaked);
+        unstake.accounts.extend([
+            AccountMeta::new(split_stake, false),
+            AccountMeta::new(stake_record(&pool, &split_stake), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ]);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, 0);
+
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(stake.delegation.stake, remaining);
+        assert_eq!(stake.delegation.deactivation_epoch, u64::MAX);
+        let StakeStateV2::Stake(_, split, _) = stake_state(&mut context, &split_stake).await else {
+            panic!("split stake account is not delegated");
+        };
+        assert_eq!(split.delegation.stake, unstaked);
+        assert_eq!(split.delegation.deactivation_epoch, epoch);
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_staked, remaining);
+        assert_eq!(state.total_pending_withdrawal, unstaked);
+
+        advance_epoch(&mut context).await;
+        let withdraw = client::withdraw_split_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, epoch);
+        let split_lamports = lamports(&mut context, &split_stake).await;
+        let split_record_lamports = lamports(&mut context, &stake_record(&pool, &split_stake)).await;
+        let user_lamports = lamports(&mut context, &user.pubkey()).await;
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+        assert_eq!(
+            lamports(&mut context, &user.pubkey()).await,
+            user_lamports + split_lamports + split_record_lamports,
+        );
+        assert!(context.banks_client.get_account(split_stake).await.unwrap().is_none());
+        assert!(context.banks_client.get_account(stake_record(&pool, &split_stake)).await.unwrap().is_none());
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_staked, remaining);
+        assert_eq!(state.total_pending_withdrawal, 0);
+    }
+
+    #[tokio::test]
+    async fn full_unstake_after_rewards_deactivates_whole_account() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        assert_eq!(stake_record_state(&mut context, &pool, &stake_account).await.pool_tokens, STAKE_AMOUNT);
+        advance_epoch(&mut context).await;
+        advance_epoch(&mut context).await;
+        let rewards = LAMPORTS_PER_SOL / 2;
+        accrue_rewards(&mut context, &stake_account, rewards).await;
+
+        // The tokens are still worth the deposit, but they are all the account's tokens,
+        // so no split accounts are needed and the rewards leave with the stake
+        let epoch = current_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(stake.delegation.stake, STAKE_AMOUNT + rewards);
+        assert_eq!(stake.delegation.deactivation_epoch, epoch);
+        let (split_stake, _) =
+            client::find_split_stake_address(&pool.addresses.stake_pool, &user.pubkey(), epoch, &pool.program_id);
+        assert!(context.banks_client.get_account(split_stake).await.unwrap().is_none());
+        assert_eq!(stake_record_state(&mut context, &pool, &stake_account).await.pool_tokens, 0);
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_staked, 0);
+        assert_eq!(state.total_shares, 0);
+    }
+
+    #[tokio::test]
+    async fn withdraw_takes_back_the_pending_stake_unstake_added() {
+        let (mut context, pool, _) = setup(0).await;
+        let full = create_user(&mut context, &pool).await;
+        let partial = create_user(&mut context, &pool).await;
+        let full_stake_account = user_stake_account(&pool, &full.pubkey());
+        send(&mut context, &[stake_ix(&pool, &full.pubkey(), STAKE_AMOUNT)], &[&full]).await.unwrap();
+        send(&mut context, &[stake_ix(&pool, &partial.pubkey(), STAKE_AMOUNT)], &[&partial]).await.unwrap();
+        advance_epoch(&mut context).await;
+
+        let epoch = current_epoch(&mut context).await;
+        let unstaked = 6 * LAMPORTS_PER_SOL;
+        let (split_stake, _) =
+            client::find_split_stake_address(&pool.addresses.stake_pool, &partial.pubkey(), epoch, &pool.program_id);
+        let mut split_unstake =
+            client::unstake(&pool.program_id, &partial.pubkey(), &pool.addresses.stake_pool, unstaked);
+        split_unstake.accounts.extend([
+            AccountMeta::new(split_stake, false),
+            AccountMeta::new(stake_record(&pool, &split_stake), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ]);
+        let full_unstake = client::unstake(&pool.program_id, &full.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[full_unstake, split_unstake], &[&full, &partial]).await.unwrap();
+
+        let get_pool_info = pool_ix(
+            &pool,
+            StakePoolInstruction::GetPoolInfo,
+            vec![AccountMeta::new_readonly(pool.addresses.stake_pool, false)],
+        );
+        let info: PoolInfo = query(&mut context, get_pool_info.clone()).await;
+        assert_eq!(info.total_staked, 2 * STAKE_AMOUNT - STAKE_AMOUNT - unstaked);
+        assert_eq!(info.total_pending_withdrawal, STAKE_AMOUNT + unstaked);
+        assert_eq!(stake_record_state(&mut context, &pool, &full_stake_account).await.pending_withdrawal, STAKE_AMOUNT);
+        assert_eq!(stake_record_state(&mut context, &pool, &split_stake).await.pending_withdrawal, unstaked);
+
+        // Deactivating stake still earns its deactivation epoch's rewards
+        accrue_rewards(&mut context, &full_stake_account, LAMPORTS_PER_SOL / 2).await;
+        advance_epoch(&mut context).await;
+        let withdraw = client::withdraw_stake(&pool.program_id, &full.pubkey(), &pool.addresses.stake_pool);
+        send(&mut context, &[withdraw], &[&full]).await.unwrap();
+        let info: PoolInfo = query(&mut context, get_pool_info.clone()).await;
+        assert_eq!(info.total_pending_withdrawal, unstaked);
+
+        let withdraw = client::withdraw_split_stake(&pool.program_id, &partial.pubkey(), &pool.addresses.stake_pool, epoch);
+        send(&mut context, &[withdraw], &[&partial]).await.unwrap();
+        let info: PoolInfo = query(&mut context, get_pool_info).await;
+        assert_eq!(info.total_pending_withdrawal, 0);
+        assert_eq!(info.total_staked, STAKE_AMOUNT - unstaked);
+    }
+
+    #[tokio::test]
+    async fn withdraw_after_rewards_closes_account_and_clears_pending() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        accrue_rewards(&mut context, &stake_account, LAMPORTS_PER_SOL).await;
+
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.total_pending_withdrawal, STAKE_AMOUNT + LAMPORTS_PER_SOL);
+
+        // Rewards for the deactivation epoch land after the unstake
+        accrue_rewards(&mut context, &stake_account, LAMPORTS_PER_SOL / 4).await;
+        advance_epoch(&mut context).await;
+        let stake_account_lamports = lamports(&mut context, &stake_account).await;
+        let record_lamports = lamports(&mut context, &stake_record(&pool, &stake_account)).await;
+        let user_lamports = lamports(&mut context, &user.pubkey()).await;
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+
+        // Everything, rent-exempt reserves included, is paid out and both accounts close
+        assert_eq!(
+            lamports(&mut context, &user.pubkey()).await,
+            user_lamports + stake_account_lamports + record_lamports,
+        );
+        assert!(context.banks_client.get_account(stake_account).await.unwrap().is_none());
+        assert!(context.banks_client.get_account(stake_record(&pool, &stake_account)).await.unwrap().is_none());
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_pending_withdrawal, 0);
+        assert_eq!(state.total_staked, 0);
+        assert_eq!(state.total_shares, 0);
+    }
+
+    #[tokio::test]
+    async fn unstake_worth_more_than_stake_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let other = create_user(&mut context, &pool).await;
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        send(&mut context, &[stake_ix(&pool, &other.pubkey(), STAKE_AMOUNT)], &[&other]).await.unwrap();
+        transfer_tokens(&mut context, &pool, &other, &user.pubkey(), LAMPORTS_PER_SOL).await;
+        advance_epoch(&mut context).await;
+
+        // The extra token is backed by the other user's stake account, not this one
+        let unstake = client::unstake(
+            &pool.program_id,
+            &user.pubkey(),
+            &pool.addresses.stake_pool,
+            STAKE_AMOUNT + LAMPORTS_PER_SOL,
+        );
+        assert_pool_error(send(&mut context, &[unstake], &[&user]).await, StakePoolError::UnstakeExceedsStake);
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_staked, 2 * STAKE_AMOUNT);
+        assert_eq!(state.total_pending_withdrawal, 0);
+    }
+
+    #[tokio::test]
+    async fn stake_into_delegated_account_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+
+        // Both while the first deposit is activating and once it is active
+        for _ in 0..2 {
+            assert_pool_error(
+                send(&mut context, &[stake_ix(&pool, &user.pubkey(), 2 * STAKE_AMOUNT)], &[&user]).await,
+                StakePoolError::StakeAccountAlreadyDelegated,
+            );
+            advance_epoch(&mut context).await;
+        }
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+        assert_eq!(pool_state(&mut context, &pool).await.total_staked, STAKE_AMOUNT);
+    }
+
+    #[tokio::test]
+    async fn stake_delegates_initialized_account() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+
+        // An account left initialized but undelegated, as by a stake whose delegation failed
+        let prepare = pool_ix(
+            &pool,
+            StakePoolInstruction::PrepareStakeAccount,
+            vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(stake_account, false),
+                AccountMeta::new_readonly(solana_program::stake::program::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        send(&mut context, &[prepare], &[&user]).await.unwrap();
+        assert!(matches!(stake_state(&mut context, &stake_account).await, StakeStateV2::Initialized(_)));
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, 0);
+
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(stake.delegation.stake, STAKE_AMOUNT);
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+    }
+
+    #[tokio::test]
+    async fn stake_at_minimum_boundary() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let limits: StakeLimits = query(&mut context, stake_limits_ix(&pool)).await;
+        let minimum = limits.effective_min_stake;
+
+        assert_pool_error(
+            send(&mut context, &[stake_ix(&pool, &user.pubkey(), minimum - 1)], &[&user]).await,
+            StakePoolError::StakeTooSmall,
+        );
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), minimum)], &[&user]).await.unwrap();
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &user_stake_account(&pool, &user.pubkey())).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(stake.delegation.stake, minimum);
+        assert_eq!(pool_state(&mut context, &pool).await.total_staked, minimum);
+    }
+
+    #[tokio::test]
+    async fn stake_into_prefunded_address_credits_surplus() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+
+        // Anyone can send lamports to the deterministic address before the first stake
+        let donation = LAMPORTS_PER_SOL;
+        let fund = system_instruction::transfer(&context.payer.pubkey(), &stake_account, donation);
+        send(&mut context, &[fund], &[]).await.unwrap();
+
+        let user_lamports = lamports(&mut context, &user.pubkey()).await;
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+
+        // The donation covers the rent-exempt reserve, and the rest is delegated and
+        // credited; the user only pays for the stake record
+        let record_rent = lamports(&mut context, &stake_record(&pool, &stake_account)).await;
+        assert_eq!(lamports(&mut context, &user.pubkey()).await, user_lamports - STAKE_AMOUNT - record_rent);
+        let StakeStateV2::Stake(meta, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        let delegated = STAKE_AMOUNT + donation - meta.rent_exempt_reserve;
+        assert_eq!(stake.delegation.stake, delegated);
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, delegated);
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_staked, delegated);
+        assert_eq!(state.total_shares, delegated);
+    }
+
+    #[tokio::test]
+    async fn stake_rent_payer_funds_stake_account_reserve() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let rent_payer = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        let user_lamports = lamports(&mut context, &user.pubkey()).await;
+        let rent_payer_lamports = lamports(&mut context, &rent_payer.pubkey()).await;
+
+        // The rent payer is the signer among the accounts after the 14 required ones
+        let mut stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        assert_eq!(stake.accounts.len(), StakeAccounts::COUNT + 1);
+        stake.accounts.push(AccountMeta::new(rent_payer.pubkey(), true));
+        send(&mut context, &[stake], &[&user, &rent_payer]).await.unwrap();
+
+        let reserve = lamports(&mut context, &stake_account).await - STAKE_AMOUNT;
+        assert!(reserve > 0);
+        let record_rent = lamports(&mut context, &stake_record(&pool, &stake_account)).await;
+        assert!(record_rent > 0);
+        assert_eq!(lamports(&mut context, &user.pubkey()).await, user_lamports - STAKE_AMOUNT);
+        assert_eq!(
+            lamports(&mut context, &rent_payer.pubkey()).await,
+            rent_payer_lamports - reserve - record_rent,
+        );
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+    }
+
+    #[tokio::test]
+    async fn stake_with_validator_list_in_any_order() {
+        let (mut context, pool, extra_votes) = setup(1).await;
+        let authority = context.payer.pubkey();
+        let (validator_list, _) = client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id);
+        send(&mut context, &[add_validator_ix(&pool, &authority, &extra_votes[0], 4)], &[]).await.unwrap();
+
+        // Without the list a listed validator can't be staked to
+        let user = create_user(&mut context, &pool).await;
+        let stake = client::stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, &extra_votes[0], STAKE_AMOUNT);
+        assert_eq!(
+            send(&mut context, std::slice::from_ref(&stake), &[&user]).await.unwrap_err(),
+            TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys),
+        );
+
+        // Only the list appended: the user pays the stake account's rent
+        let user_lamports = lamports(&mut context, &user.pubkey()).await;
+        let mut stake = stake;
+        stake.accounts.push(AccountMeta::new(validator_list, false));
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        let stake_account_lamports = lamports(&mut context, &stake_account).await;
+        let record_lamports = lamports(&mut context, &stake_record(&pool, &stake_account)).await;
+        assert_eq!(
+            lamports(&mut context, &user.pubkey()).await,
+            user_lamports - stake_account_lamports - record_lamports,
+        );
+
+        // A rent payer ahead of the list
+        let other = create_user(&mut context, &pool).await;
+        let rent_payer = create_user(&mut context, &pool).await;
+        let other_lamports = lamports(&mut context, &other.pubkey()).await;
+        let mut stake = stake_ix(&pool, &other.pubkey(), STAKE_AMOUNT);
+        stake.accounts.push(AccountMeta::new(rent_payer.pubkey(), true));
+        stake.accounts.push(AccountMeta::new(validator_list, false));
+        send(&mut context, &[stake], &[&other, &rent_payer]).await.unwrap();
+        assert_eq!(lamports(&mut context, &other.pubkey()).await, other_lamports - STAKE_AMOUNT);
+
+        let account = context.banks_client.get_account(validator_list).await.unwrap().unwrap();
+        let list = ValidatorList::unpack_account(&account.data).unwrap();
+        assert_eq!(list.validators[0].active_stake_lamports, STAKE_AMOUNT);
+        assert_eq!(list.validators[1].active_stake_lamports, STAKE_AMOUNT);
+    }
+
+    #[tokio::test]
+    async fn stake_into_deactivating_account_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+
+        // Neither in the deactivation epoch nor after the cooldown, before the withdrawal
+        for _ in 0..2 {
+            let stake_account_lamports = lamports(&mut context, &stake_account).await;
+            assert_pool_error(
+                send(&mut context, &[stake_ix(&pool, &user.pubkey(), 2 * STAKE_AMOUNT)], &[&user]).await,
+                StakePoolError::StakeAccountAlreadyDelegated,
+            );
+            assert_eq!(lamports(&mut context, &stake_account).await, stake_account_lamports);
+            let state = pool_state(&mut context, &pool).await;
+            assert_eq!(state.total_staked, 0);
+            assert_eq!(state.total_pending_withdrawal, STAKE_AMOUNT);
+            advance_epoch(&mut context).await;
+        }
+
+        // The withdrawal closes the account, and the next deposit starts a new one
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), 2 * STAKE_AMOUNT)], &[&user]).await.unwrap();
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(stake.delegation.stake, 2 * STAKE_AMOUNT);
+        assert_eq!(stake.delegation.deactivation_epoch, u64::MAX);
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.total_staked, 2 * STAKE_AMOUNT);
+        assert_eq!(state.total_pending_withdrawal, 0);
+    }
+
+    #[tokio::test]
+    async fn claim_rewards_finds_history_by_address() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let (history, _) = Pubkey::find_program_address(
+            &[HISTORY_SEED, pool.addresses.stake_pool.as_ref()],
+            &pool.program_id,
+        );
+        let create_history = pool_ix(
+            &pool,
+            StakePoolInstruction::CreateHistory { capacity: 4 },
+            vec![
+                AccountMeta::new(authority, true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(history, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        send(&mut context, &[create_history], &[]).await.unwrap();
+        advance_epoch(&mut context).await;
+
+        // An unrelated account ahead of the history PDA is ignored, not taken for it
+        let claim = pool_ix(
+            &pool,
+            StakePoolInstruction::ClaimRewards,
+            vec![
+                AccountMeta::new_readonly(authority, true),
+                AccountMeta::new(pool.addresses.stake_pool, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+                AccountMeta::new(Pubkey::new_unique(), false),
+                AccountMeta::new_readonly(pool.validator_vote, false),
+                AccountMeta::new(history, false),
+            ],
+        );
+        send(&mut context, &[claim], &[]).await.unwrap();
+
+        let epoch = current_epoch(&mut context).await;
+        let account = context.banks_client.get_account(history).await.unwrap().unwrap();
+        let entries = PoolHistory::unpack_account(&account.data).unwrap().ordered();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].epoch, epoch);
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, epoch);
+    }
+
+    #[tokio::test]
+    async fn history_ring_buffer_keeps_latest_epochs() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let (history, _) = Pubkey::find_program_address(
+            &[HISTORY_SEED, pool.addresses.stake_pool.as_ref()],
+            &pool.program_id,
+        );
+        let create_history = pool_ix(
+            &pool,
+            StakePoolInstruction::CreateHistory { capacity: 3 },
+            vec![
+                AccountMeta::new(authority, true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(history, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        send(&mut context, &[create_history], &[]).await.unwrap();
+        let account = context.banks_client.get_account(history).await.unwrap().unwrap();
+        assert_eq!(account.data.len(), PoolHistory::account_size(3));
+        assert!(PoolHistory::account_size(MAX_HISTORY_ENTRIES) <= 10 * 1024);
+        assert_eq!(
+            PoolHistory::account_size(MAX_HISTORY_ENTRIES),
+            PoolHistory::new(pool.addresses.stake_pool, MAX_HISTORY_ENTRIES).to_account_data().unwrap().len(),
+        );
+
+        // Five snapshots through a three-entry buffer, each after one more deposit
+        let mut epochs = Vec::new();
+        for _ in 0..5 {
+            let user = create_user(&mut context, &pool).await;
+            send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+            advance_epoch(&mut context).await;
+            let claim = pool_ix(
+                &pool,
+                StakePoolInstruction::ClaimRewards,
+                vec![
+                    AccountMeta::new_readonly(authority, true),
+                    AccountMeta::new(pool.addresses.stake_pool, false),
+                    AccountMeta::new_readonly(sysvar::clock::id(), false),
+                    AccountMeta::new(history, false),
+                ],
+            );
+            send(&mut context, &[claim], &[]).await.unwrap();
+            epochs.push(current_epoch(&mut context).await);
+        }
+
+        let get_history = pool_ix(
+            &pool,
+            StakePoolInstruction::GetHistory { offset: 0, limit: 10 },
+            vec![AccountMeta::new_readonly(history, false)],
+        );
+        let page: ReturnPage<HistoryEntry> = query(&mut context, get_history).await;
+        assert!(!page.has_more);
+        let entries = page.items;
+        assert_eq!(entries.iter().map(|entry| entry.epoch).collect::<Vec<_>>(), epochs[2..]);
+        for (deposits, entry) in (3..).zip(&entries) {
+            assert_eq!(entry.total_staked, deposits * STAKE_AMOUNT);
+            assert_eq!(entry.total_shares, deposits * STAKE_AMOUNT);
+            assert_eq!(entry.price, PRICE_SCALE);
+            assert_eq!((entry.rewards_booked, entry.fees_taken), (0, 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_claim_advances_a_stale_pool() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let (history, _) = Pubkey::find_program_address(
+            &[HISTORY_SEED, pool.addresses.stake_pool.as_ref()],
+            &pool.program_id,
+        );
+        let create_history = pool_ix(
+            &pool,
+            StakePoolInstruction::CreateHistory { capacity: 4 },
+            vec![
+                AccountMeta::new(authority, true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(history, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        send(&mut context, &[create_history], &[]).await.unwrap();
+        let users = [create_user(&mut context, &pool).await, create_user(&mut context, &pool).await];
+        let stake_with_history = |user: &Pubkey| {
+            let mut stake = stake_ix(&pool, user, STAKE_AMOUNT);
+            stake.accounts.push(AccountMeta::new(history, false));
+            stake
+        };
+        let set_auto_claim = |signer: &Pubkey, enabled| {
+            admin_ix(&pool, signer, StakePoolInstruction::SetAutoClaim { enabled })
+        };
+
+        // Off by default: a deposit leaves a stale pool stale
+        advance_epoch(&mut context).await;
+        let stale_epoch = pool_state(&mut context, &pool).await.last_update_epoch;
+        send(&mut context, &[stake_with_history(&users[0].pubkey())], &[&users[0]]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, stale_epoch);
+
+        let result = send(&mut context, &[set_auto_claim(&users[0].pubkey(), true)], &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        send(&mut context, &[set_auto_claim(&authority, true)], &[]).await.unwrap();
+        assert!(pool_state(&mut context, &pool).await.auto_claim);
+
+        // The next deposit advances the pool, snapshotting it before pricing the deposit
+        let before = pool_state(&mut context, &pool).await;
+        send(&mut context, &[stake_with_history(&users[1].pubkey())], &[&users[1]]).await.unwrap();
+        let epoch = current_epoch(&mut context).await;
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, epoch);
+        assert_eq!(token_balance(&mut context, &pool, &users[1].pubkey()).await, before.lamports_to_tokens(STAKE_AMOUNT).unwrap());
+        let account = context.banks_client.get_account(history).await.unwrap().unwrap();
+        let entries = PoolHistory::unpack_account(&account.data).unwrap().ordered();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].epoch, entries[0].price), (epoch, before.price().unwrap()));
+        assert_eq!(entries[0].total_staked, before.total_staked);
+
+        // So does the next unstake, once the epoch has moved on again
+        advance_epoch(&mut context).await;
+        let mut unstake = client::unstake(&pool.program_id, &users[0].pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        unstake.accounts.push(AccountMeta::new(history, false));
+        send(&mut context, &[unstake], &[&users[0]]).await.unwrap();
+        let epoch = current_epoch(&mut context).await;
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, epoch);
+        let account = context.banks_client.get_account(history).await.unwrap().unwrap();
+        let entries = PoolHistory::unpack_account(&account.data).unwrap().ordered();
+        assert_eq!(entries.last().unwrap().epoch, epoch);
+        assert_eq!(entries.last().unwrap().total_staked, 2 * STAKE_AMOUNT);
+    }
+
+    #[tokio::test]
+    async fn warp_pool_epoch_fast_forwards_bookkeeping() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let warp = |last_update_epoch, unstake_tracking_epoch| {
+            StakePoolInstruction::WarpPoolEpoch { last_update_epoch, unstake_tracking_epoch }
+        };
+        let users = [create_user(&mut context, &pool).await, create_user(&mut context, &pool).await];
+        for user in &users {
+            send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[user]).await.unwrap();
+        }
+        let set_limit = StakePoolInstruction::SetUnstakeLimit { max_unstake_per_epoch: STAKE_AMOUNT };
+        send(&mut context, &[admin_ix(&pool, &authority, set_limit)], &[]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let epoch = current_epoch(&mut context).await;
+
+        // Only the authority can warp
+        let result = send(&mut context, &[admin_ix(&pool, &users[0].pubkey(), warp(0, 0))], &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+
+        // A pool marked as processed in the current epoch takes no further update until
+        // its marker is wound back
+        let claim = pool_ix(
+            &pool,
+            StakePoolInstruction::ClaimRewards,
+            vec![
+                AccountMeta::new_readonly(authority, true),
+                AccountMeta::new(pool.addresses.stake_pool, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+            ],
+        );
+        send(&mut context, std::slice::from_ref(&claim), &[]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, epoch);
+        send(&mut context, &[admin_ix(&pool, &authority, warp(epoch - 1, 0))], &[]).await.unwrap();
+        let state = pool_state(&mut context, &pool).await;
+        assert!(state.needs_epoch_update(epoch));
+        // A fresh blockhash keeps the repeated claim from being taken for the first
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[claim], &[]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, epoch);
+
+        // The first unstake uses up the epoch's budget
+        let unstake = |user: &Keypair| client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake(&users[0])], &[&users[0]]).await.unwrap();
+        assert_pool_error(send(&mut context, &[unstake(&users[1])], &[&users[1]]).await, StakePoolError::UnstakeLimitReached);
+        // Dating the tracked unstakes to an earlier epoch frees it again
+        send(&mut context, &[admin_ix(&pool, &authority, warp(epoch, epoch - 1))], &[]).await.unwrap();
+        // The rejected unstake is remembered by its signature, so retry under a new blockhash
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[unstake(&users[1])], &[&users[1]]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.unstake_tracking_epoch, epoch);
+    }
+
+    fn add_validator_ix(pool: &TestPool, authority: &Pubkey, vote: &Pubkey, max_validators: u32) -> Instruction {
+        pool_ix(
+            pool,
+            StakePoolInstruction::AddValidator { max_validators },
+            vec![
+                AccountMeta::new(*authority, true),
+                AccountMeta::new(pool.addresses.stake_pool, false),
+                AccountMeta::new(client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id).0, false),
+                AccountMeta::new_readonly(*vote, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn validator_list_fills_to_capacity_then_grows() {
+        let (mut context, pool, extra_votes) = setup(4).await;
+        let authority = context.payer.pubkey();
+        let (validator_list, _) = client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id);
+
+        // A list needs room for at least the Helius validator and the one added
+        let too_small = add_validator_ix(&pool, &authority, &extra_votes[0], 1);
+        assert!(matches!(
+            send(&mut context, &[too_small], &[]).await,
+            Err(TransactionError::InstructionError(0, InstructionError::InvalidArgument))
+        ));
+
+        // Created with the Helius validator and room for one more, so it is full at once
+        send(&mut context, &[add_validator_ix(&pool, &authority, &extra_votes[0], 2)], &[]).await.unwrap();
+        let result = send(&mut context, &[add_validator_ix(&pool, &authority, &extra_votes[1], 2)], &[]).await;
+        assert_pool_error(result, StakePoolError::ValidatorListFull);
+
+        let grow = |max_validators| pool_ix(
+            &pool,
+            StakePoolInstruction::GrowValidatorList { max_validators },
+            vec![
+                AccountMeta::new(authority, true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(validator_list, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        );
+        assert!(matches!(
+            send(&mut context, &[grow(2)], &[]).await,
+            Err(TransactionError::InstructionError(0, InstructionError::InvalidArgument))
+        ));
+        send(&mut context, &[grow(4)], &[]).await.unwrap();
+
+        // max_validators only applies to the call that creates the list
+        for vote in &extra_votes[1..3] {
+            send(&mut context, &[add_validator_ix(&pool, &authority, vote, 3)], &[]).await.unwrap();
+        }
+        let account = context.banks_client.get_account(validator_list).await.unwrap().unwrap();
+        assert_eq!(account.data.len(), ValidatorList::account_size(4));
+        let rent = context.banks_client.get_rent().await.unwrap();
+        assert!(rent.is_exempt(account.lamports, account.data.len()));
+        let list = ValidatorList::unpack_account(&account.data).unwrap();
+        assert_eq!(list.max_validators, 4);
+        assert_eq!(list.validators.len(), 4);
+        assert_eq!(list.validators[0].vote_account, pool.validator_vote);
+        assert_eq!(list.validators[3].vote_account, extra_votes[2]);
+
+        let result = send(&mut context, &[add_validator_ix(&pool, &authority, &extra_votes[3], 3)], &[]).await;
+        assert_pool_error(result, StakePoolError::ValidatorListFull);
+    }
+
+    /// A deposit to `vote` with the validator list appended
+    fn listed_stake_ix(pool: &TestPool, user: &Pubkey, vote: &Pubkey, amount: u64) -> Instruction {
+        let mut stake = client::stake(&pool.program_id, user, &pool.addresses.stake_pool, vote, amount);
+        let (validator_list, _) = client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id);
+        stake.accounts.push(AccountMeta::new(validator_list, false));
+        stake
+    }
+
+    fn remove_validator_ix(pool: &TestPool, authority: &Pubkey, vote_account: Pubkey) -> Instruction {
+        pool_ix(
+            pool,
+            StakePoolInstruction::RemoveValidator { vote_account },
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id).0, false),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn validator_stake_limit_and_removal() {
+        let (mut context, pool, extra_votes) = setup(2).await;
+        let authority = context.payer.pubkey();
+        for vote in &extra_votes {
+            send(&mut context, &[add_validator_ix(&pool, &authority, vote, 4)], &[]).await.unwrap();
+        }
+        let mut users = Vec::new();
+        for _ in 0..4 {
+            users.push(create_user(&mut context, &pool).await);
+        }
+
+        // The least-staked validator is exempt, so an empty list can take a first deposit
+        send(&mut context, &[listed_stake_ix(&pool, &users[0].pubkey(), &pool.validator_vote, STAKE_AMOUNT)], &[&users[0]])
+            .await
+            .unwrap();
+        // With three validators each may hold a third, which a second deposit would exceed
+        let result = send(
+            &mut context,
+            &[listed_stake_ix(&pool, &users[1].pubkey(), &pool.validator_vote, STAKE_AMOUNT)],
+            &[&users[1]],
+        )
+        .await;
+        assert_pool_error(result, StakePoolError::ValidatorStakeLimitExceeded);
+        send(&mut context, &[listed_stake_ix(&pool, &users[1].pubkey(), &extra_votes[0], STAKE_AMOUNT)], &[&users[1]])
+            .await
+            .unwrap();
+
+        // A validator still holding stake stays; an empty one can go
+        let result = send(&mut context, &[remove_validator_ix(&pool, &authority, extra_votes[0])], &[]).await;
+        assert_pool_error(result, StakePoolError::ValidatorHasStake);
+        send(&mut context, &[remove_validator_ix(&pool, &authority, extra_votes[1])], &[]).await.unwrap();
+
+        // Both remaining validators are now least-staked, so either takes more
+        send(&mut context, &[listed_stake_ix(&pool, &users[2].pubkey(), &pool.validator_vote, STAKE_AMOUNT)], &[&users[2]])
+            .await
+            .unwrap();
+        let result = send(
+            &mut context,
+            &[listed_stake_ix(&pool, &users[3].pubkey(), &pool.validator_vote, STAKE_AMOUNT)],
+            &[&users[3]],
+        )
+        .await;
+        assert_pool_error(result, StakePoolError::ValidatorStakeLimitExceeded);
+
+        let (validator_list, _) = client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id);
+        let account = context.banks_client.get_account(validator_list).await.unwrap().unwrap();
+        let list = ValidatorList::unpack_account(&account.data).unwrap();
+        assert_eq!(list.validators.len(), 2);
+        assert_eq!(list.validators[0].active_stake_lamports, 2 * STAKE_AMOUNT);
+        assert_eq!(list.validators[1].active_stake_lamports, STAKE_AMOUNT);
+    }
+
+    #[tokio::test]
+    async fn validator_cap_update_applies_to_new_deposits() {
+        let (mut context, pool, extra_votes) = setup(2).await;
+        let authority = context.payer.pubkey();
+        for vote in &extra_votes {
+            send(&mut context, &[add_validator_ix(&pool, &authority, vote, 4)], &[]).await.unwrap();
+        }
+        let mut users = Vec::new();
+        for _ in 0..5 {
+            users.push(create_user(&mut context, &pool).await);
+        }
+        let set_cap = |signer: &Pubkey, max_validator_stake_bps| {
+            admin_ix(&pool, signer, StakePoolInstruction::UpdateValidatorCap { max_validator_stake_bps })
+        };
+        assert_eq!(pool_state(&mut context, &pool).await.max_validator_stake_bps, DEFAULT_VALIDATOR_STAKE_BPS);
+
+        let result = send(&mut context, &[set_cap(&users[0].pubkey(), 5_000)], &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        for out_of_range in [MIN_VALIDATOR_STAKE_BPS - 1, 10_001] {
+            let result = send(&mut context, &[set_cap(&authority, out_of_range)], &[]).await;
+            assert_instruction_error(result, InstructionError::InvalidArgument);
+        }
+
+        // Without a cap one validator can take everything
+        send(&mut context, &[set_cap(&authority, 10_000)], &[]).await.unwrap();
+        for user in &users[..2] {
+            send(&mut context, &[listed_stake_ix(&pool, &user.pubkey(), &pool.validator_vote, STAKE_AMOUNT)], &[user])
+                .await
+                .unwrap();
+        }
+
+        // Lowered to the floor, the even split of a third takes over: the over-cap
+        // validator keeps its stake but takes no more, while the others still can
+        send(&mut context, &[set_cap(&authority, MIN_VALIDATOR_STAKE_BPS)], &[]).await.unwrap();
+        let result = send(
+            &mut context,
+            &[listed_stake_ix(&pool, &users[2].pubkey(), &pool.validator_vote, STAKE_AMOUNT)],
+            &[&users[2]],
+        )
+        .await;
+        assert_pool_error(result, StakePoolError::ValidatorStakeLimitExceeded);
+        for (user, vote) in users[3..].iter().zip(&extra_votes) {
+            send(&mut context, &[listed_stake_ix(&pool, &user.pubkey(), vote, STAKE_AMOUNT)], &[user]).await.unwrap();
+        }
+
+        for user in &users[..2] {
+            let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &user_stake_account(&pool, &user.pubkey())).await
+            else {
+                panic!("stake account is not delegated");
+            };
+            assert_eq!(stake.delegation.voter_pubkey, pool.validator_vote);
+            assert_eq!(stake.delegation.deactivation_epoch, u64::MAX);
+        }
+        let (validator_list, _) = client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id);
+        let account = context.banks_client.get_account(validator_list).await.unwrap().unwrap();
+        let list = ValidatorList::unpack_account(&account.data).unwrap();
+        let stakes: Vec<u64> = list.validators.iter().map(|validator| validator.active_stake_lamports).collect();
+        assert_eq!(stakes, [2 * STAKE_AMOUNT, STAKE_AMOUNT, STAKE_AMOUNT]);
+    }
+
+    fn migrate_ix(pool: &TestPool, authority: &Pubkey, user: &Pubkey, to_vote: &Pubkey, from_index: u32, to_index: u32) -> Instruction {
+        let (validator_list, _) = client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id);
+        let stake_account = user_stake_account(pool, user);
+        #[allow(deprecated)]
+        let stake_config = solana_program::stake::config::id();
+        pool_ix(
+            pool,
+            StakePoolInstruction::MigrateValidatorStake { from_index, to_index },
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(validator_list, false),
+                AccountMeta::new(stake_account, false),
+                AccountMeta::new_readonly(stake_record(pool, &stake_account), false),
+                AccountMeta::new_readonly(*to_vote, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+                AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+                AccountMeta::new_readonly(stake_config, false),
+                AccountMeta::new_readonly(pool.addresses.stake_authority, false),
+                AccountMeta::new_readonly(solana_program::stake::program::id(), false),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn migrated_validator_can_be_removed() {
+        let (mut context, pool, extra_votes) = setup(1).await;
+        let authority = context.payer.pubkey();
+        let leaving = extra_votes[0];
+        send(&mut context, &[add_validator_ix(&pool, &authority, &leaving, 4)], &[]).await.unwrap();
+        let user = create_user(&mut context, &pool).await;
+        send(&mut context, &[listed_stake_ix(&pool, &user.pubkey(), &leaving, STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        let (validator_list, _) = client::find_validator_list_address(&pool.addresses.stake_pool, &pool.program_id);
+
+        let result = send(&mut context, &[remove_validator_ix(&pool, &authority, leaving)], &[]).await;
+        assert_pool_error(result, StakePoolError::ValidatorHasStake);
+
+        // Only the pool authority migrates, and only between listed validators
+        let result = send(&mut context, &[migrate_ix(&pool, &user.pubkey(), &user.pubkey(), &pool.validator_vote, 1, 0)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        let result = send(&mut context, &[migrate_ix(&pool, &authority, &user.pubkey(), &pool.validator_vote, 1, 2)], &[]).await;
+        assert_pool_error(result, StakePoolError::ValidatorNotInList);
+        let result = send(&mut context, &[migrate_ix(&pool, &authority, &user.pubkey(), &pool.validator_vote, 0, 1)], &[]).await;
+        assert_pool_error(result, StakePoolError::InvalidStakeAccountDelegation);
+
+        // Step 1 takes the stake off the source while it cools down
+        let migrate = migrate_ix(&pool, &authority, &user.pubkey(), &pool.validator_vote, 1, 0);
+        send(&mut context, &[migrate.clone()], &[]).await.unwrap();
+        let account = context.banks_client.get_account(validator_list).await.unwrap().unwrap();
+        let list = ValidatorList::unpack_account(&account.data).unwrap();
+        assert_eq!(list.validators[1].active_stake_lamports, 0);
+        context.get_new_latest_blockhash().await.unwrap();
+        let result = send(&mut context, &[migrate.clone()], &[]).await;
+        assert_pool_error(result, StakePoolError::CooldownNotPassed);
+        // The owner's obeSOL is still outstanding, so the deactivated stake isn't theirs to take
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        let result = send(&mut context, &[withdraw], &[&user]).await;
+        assert_pool_error(result, StakePoolError::StakeMigrating);
+
+        // Step 2 delegates the cooled-down stake to the destination
+        advance_epoch(&mut context).await;
+        let result = send(&mut context, &[migrate_ix(&pool, &authority, &user.pubkey(), &leaving, 1, 0)], &[]).await;
+        assert_pool_error(result, StakePoolError::InvalidVoteAccount);
+        send(&mut context, &[migrate], &[]).await.unwrap();
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &stake_account).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(stake.delegation.voter_pubkey, pool.validator_vote);
+        assert_eq!(stake.delegation.deactivation_epoch, u64::MAX);
+        let account = context.banks_client.get_account(validator_list).await.unwrap().unwrap();
+        let list = ValidatorList::unpack_account(&account.data).unwrap();
+        assert_eq!(list.validators[0].active_stake_lamports, stake.delegation.stake);
+        assert_eq!(list.validators[1].active_stake_lamports, 0);
+
+        send(&mut context, &[remove_validator_ix(&pool, &authority, leaving)], &[]).await.unwrap();
+        let account = context.banks_client.get_account(validator_list).await.unwrap().unwrap();
+        let list = ValidatorList::unpack_account(&account.data).unwrap();
+        assert_eq!(list.validators.len(), 1);
+        assert_eq!(list.validators[0].vote_account, pool.validator_vote);
+    }
+
+    #[tokio::test]
+    async fn undecodable_instruction_data_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let accounts = vec![AccountMeta::new_readonly(pool.addresses.stake_pool, false)];
+        let stake_tag = StakePoolInstruction::Stake { amount: 0 }.try_to_vec().unwrap()[0];
+        let mut trailing_byte = StakePoolInstruction::GetPoolInfo.try_to_vec().unwrap();
+        trailing_byte.push(0);
+
+        // Empty, an unknown variant, truncated arguments and a trailing byte
+        for data in [vec![], vec![u8::MAX], vec![stake_tag, 1, 2], trailing_byte] {
+            let instruction = Instruction::new_with_bytes(pool.program_id, &data, accounts.clone());
+            assert_instruction_error(send(&mut context, &[instruction], &[]).await, InstructionError::InvalidInstructionData);
+        }
+    }
+
+    #[tokio::test]
+    async fn initialize_rejects_invalid_arguments() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = create_user(&mut context, &pool).await;
+        let manager = Pubkey::new_unique();
+        let treasury = Pubkey::new_unique();
+        let initialize = |name: &str, fee_percentage, manager: &Pubkey| {
+            client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                manager,
+                &treasury,
+                name.to_string(),
+                fee_percentage,
+                &pool.validator_vote,
+            )
+        };
+
+        let result = send(&mut context, &[initialize("Pool", 101, &manager)], &[&authority]).await;
+        assert_pool_error(result, StakePoolError::InvalidFeePercentage);
+        let result = send(&mut context, &[initialize("ab", 0, &manager)], &[&authority]).await;
+        assert_pool_error(result, StakePoolError::InvalidPoolName);
+        let result = send(&mut context, &[initialize("Pool", 0, &treasury)], &[&authority]).await;
+        assert_pool_error(result, StakePoolError::InvalidFeeAccount);
+        let mut wrong_vote = initialize("Pool", 1, &manager);
+        wrong_vote.accounts[8] = AccountMeta::new_readonly(Pubkey::new_unique(), false);
+        assert_pool_error(send(&mut context, &[wrong_vote], &[&authority]).await, StakePoolError::InvalidVoteAccount);
+
+        send(&mut context, &[initialize("Pool", 100, &manager)], &[&authority]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn strict_pool_rejects_stale_vote_accounts() {
+        let (mut context, pool, extra_votes) = setup(1).await;
+        // Far enough in that a vote can be more than MAX_VOTE_AGE_SLOTS old
+        context.warp_to_slot(2 * MAX_VOTE_AGE_SLOTS).unwrap();
+        let slot = context.banks_client.get_sysvar::<Clock>().await.unwrap().slot;
+        let live_vote = Pubkey::new_unique();
+        context.set_account(&live_vote, &vote_account_with_votes(&[slot]).into());
+        let stale_vote = Pubkey::new_unique();
+        context.set_account(&stale_vote, &vote_account_with_votes(&[slot - MAX_VOTE_AGE_SLOTS - 1]).into());
+
+        let authority = create_user(&mut context, &pool).await;
+        let strict_initialize = |vote: &Pubkey| {
+            let mut initialize = client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                "Strict Pool".to_string(),
+                0,
+                vote,
+            );
+            initialize.accounts.push(AccountMeta::new_readonly(sysvar::clock::id(), false));
+            initialize
+        };
+        // Neither a vote account that never voted nor one that stopped voting will do
+        for vote in [pool.validator_vote, stale_vote] {
+            let result = send(&mut context, &[strict_initialize(&vote)], &[&authority]).await;
+            assert_pool_error(result, StakePoolError::InvalidVoteAccount);
+        }
+        send(&mut context, &[strict_initialize(&live_vote)], &[&authority]).await.unwrap();
+        let strict_pool = TestPool {
+            program_id: pool.program_id,
+            addresses: client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id),
+            validator_vote: live_vote,
+        };
+        assert!(pool_state(&mut context, &strict_pool).await.require_voting_validator);
+        assert!(!pool_state(&mut context, &pool).await.require_voting_validator);
+
+        // Validators added later are held to the same check, which needs the clock
+        let add_validator = |vote: &Pubkey| {
+            let mut add = add_validator_ix(&strict_pool, &authority.pubkey(), vote, 4);
+            add.accounts.push(AccountMeta::new_readonly(sysvar::clock::id(), false));
+            add
+        };
+        let result = send(&mut context, &[add_validator_ix(&strict_pool, &authority.pubkey(), &live_vote, 4)], &[&authority]).await;
+        assert_instruction_error(result, InstructionError::NotEnoughAccountKeys);
+        let result = send(&mut context, &[add_validator(&extra_votes[0])], &[&authority]).await;
+        assert_pool_error(result, StakePoolError::InvalidVoteAccount);
+        let result = send(&mut context, &[add_validator(&stale_vote)], &[&authority]).await;
+        assert_pool_error(result, StakePoolError::InvalidVoteAccount);
+        let live_validator = Pubkey::new_unique();
+        context.set_account(&live_validator, &vote_account_with_votes(&[slot]).into());
+        send(&mut context, &[add_validator(&live_validator)], &[&authority]).await.unwrap();
+
+        // A non-strict pool still takes any vote account
+        let payer = context.payer.pubkey();
+        send(&mut context, &[add_validator_ix(&pool, &payer, &extra_votes[0], 4)], &[]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn initialize_lays_out_short_and_long_names() {
+        let (mut context, pool, _) = setup(0).await;
+        for name in ["abc", "a".repeat(32).as_str()] {
+            let authority = create_user(&mut context, &pool).await;
+            let treasury = Pubkey::new_unique();
+            let initialize = client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                &Pubkey::new_unique(),
+                &treasury,
+                name.to_string(),
+                5,
+                &pool.validator_vote,
+            );
+            send(&mut context, &[initialize], &[&authority]).await.unwrap();
+
+            let addresses = client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id);
+            let account = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+            let state = StakePool::try_from_slice(&account.data[ACCOUNT_DISCRIMINATOR_LEN..]).unwrap();
+            assert_eq!(state.name, name);
+            assert_eq!(state.mint, addresses.mint);
+            assert_eq!(state.reserve, Pubkey::default());
+            assert_eq!(state.stake_authority_bump_seed, addresses.stake_authority_bump);
+            assert_eq!(state.withdraw_authority_bump_seed, addresses.withdraw_authority_bump);
+            assert_eq!(state.helius_validator_vote, pool.validator_vote);
+            assert_eq!(state.treasury_fee_account, treasury);
+            assert_eq!(state.fee_percentage, 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn update_pool_config_edits_only_requested_fields() {
+        let (mut context, pool, _) = setup(0).await;
+        for name in ["abc", "a".repeat(32).as_str()] {
+            let authority = create_user(&mut context, &pool).await;
+            let initialize = client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                name.to_string(),
+                5,
+                &pool.validator_vote,
+            );
+            send(&mut context, &[initialize], &[&authority]).await.unwrap();
+            let name_pool = TestPool {
+                program_id: pool.program_id,
+                addresses: client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id),
+                validator_vote: pool.validator_vote,
+            };
+            let update = |signer: &Pubkey, fee_percentage, min_stake, max_stake, paused| {
+                admin_ix(
+                    &name_pool,
+                    signer,
+                    StakePoolInstruction::UpdatePoolConfig { fee_percentage, min_stake, max_stake, paused },
+                )
+            };
+            let before = context.banks_client.get_account(name_pool.addresses.stake_pool).await.unwrap().unwrap();
+
+            // All-None changes nothing
+            send(&mut context, &[update(&authority.pubkey(), None, None, None, None)], &[&authority]).await.unwrap();
+            let after = context.banks_client.get_account(name_pool.addresses.stake_pool).await.unwrap().unwrap();
+            assert_eq!(after.data, before.data);
+
+            let user = create_user(&mut context, &pool).await;
+            let result = send(&mut context, &[update(&user.pubkey(), Some(1), None, None, None)], &[&user]).await;
+            assert_pool_error(result, StakePoolError::InvalidAuthority);
+            let result = send(&mut context, &[update(&authority.pubkey(), Some(101), None, None, None)], &[&authority]).await;
+            assert_pool_error(result, StakePoolError::InvalidFeePercentage);
+            let min_above_max = update(&authority.pubkey(), None, Some(3 * LAMPORTS_PER_SOL), Some(2 * LAMPORTS_PER_SOL), None);
+            assert_instruction_error(send(&mut context, &[min_above_max], &[&authority]).await, InstructionError::InvalidArgument);
+
+            // Every field at once, with min == max allowed; the fields after the name keep their values
+            let update_all = update(
+                &authority.pubkey(),
+                Some(100),
+                Some(2 * LAMPORTS_PER_SOL),
+                Some(2 * LAMPORTS_PER_SOL),
+                Some(true),
+            );
+            send(&mut context, &[update_all], &[&authority]).await.unwrap();
+            let after = context.banks_client.get_account(name_pool.addresses.stake_pool).await.unwrap().unwrap();
+            assert_eq!(after.data.len(), before.data.len());
+            let mut expected = StakePool::unpack_account(&before.data).unwrap();
+            expected.fee_percentage = 100;
+            expected.min_stake = 2 * LAMPORTS_PER_SOL;
+            expected.max_stake = 2 * LAMPORTS_PER_SOL;
+            expected.paused = true;
+            assert_eq!(StakePool::unpack_account(&after.data).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn stake_rejects_invalid_requests() {
+        let (mut context, pool, extra_votes) = setup(1).await;
+        let authority = context.payer.pubkey();
+        let user = create_user(&mut context, &pool).await;
+        let max_stake = pool_state(&mut context, &pool).await.max_stake;
+
+        let result = send(&mut context, &[stake_ix(&pool, &user.pubkey(), max_stake + 1)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::StakeTooLarge);
+        let result = send(&mut context, &[stake_ix(&pool, &user.pubkey(), 200 * LAMPORTS_PER_SOL)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InsufficientBalance);
+
+        let unlisted = client::stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, &extra_votes[0], STAKE_AMOUNT);
+        assert_pool_error(send(&mut context, &[unlisted], &[&user]).await, StakePoolError::InvalidStakeAccountDelegation);
+        let mut wrong_authority = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        wrong_authority.accounts[StakeAccounts::StakeAuthority as usize] = AccountMeta::new_readonly(Pubkey::new_unique(), false);
+        assert_pool_error(send(&mut context, &[wrong_authority], &[&user]).await, StakePoolError::InvalidStakeAuthority);
+
+        // Only the authority can pause, and a paused pool takes no deposits
+        let pause = admin_ix(&pool, &user.pubkey(), update_config(Some(true), None));
+        assert_pool_error(send(&mut context, &[pause], &[&user]).await, StakePoolError::InvalidAuthority);
+        send(&mut context, &[admin_ix(&pool, &authority, update_config(Some(true), None))], &[]).await.unwrap();
+        let result = send(&mut context, &[stake_ix(&pool, &user.pubkey(), 2 * STAKE_AMOUNT)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::PoolPaused);
+        send(&mut context, &[admin_ix(&pool, &authority, update_config(Some(false), None))], &[]).await.unwrap();
+
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unstake_and_withdraw_reject_wrong_state() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let unstake = |user: &Pubkey, amount| client::unstake(&pool.program_id, user, &pool.addresses.stake_pool, amount);
+
+        let prepare = pool_ix(
+            &pool,
+            StakePoolInstruction::PrepareStakeAccount,
+            vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(user_stake_account(&pool, &user.pubkey()), false),
+                AccountMeta::new_readonly(solana_program::stake::program::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        send(&mut context, &[prepare], &[&user]).await.unwrap();
+
+        // In an empty pool tokens are worth nothing
+        let result = send(&mut context, &[unstake(&user.pubkey(), 1)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::OutputBelowMinimum);
+
+        // An account that was never delegated has nothing to unstake
+        let staker = create_user(&mut context, &pool).await;
+        send(&mut context, &[stake_ix(&pool, &staker.pubkey(), STAKE_AMOUNT)], &[&staker]).await.unwrap();
+        let result = send(&mut context, &[unstake(&user.pubkey(), 2)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::WrongStakeState);
+
+        // Active stake can't be withdrawn, and only through the pool's withdraw authority
+        let withdraw = client::withdraw_stake(&pool.program_id, &staker.pubkey(), &pool.addresses.stake_pool);
+        let mut wrong_authority = withdraw.clone();
+        wrong_authority.accounts[3] = AccountMeta::new_readonly(Pubkey::new_unique(), false);
+        assert_pool_error(send(&mut context, &[wrong_authority], &[&staker]).await, StakePoolError::InvalidWithdrawAuthority);
+        assert_pool_error(send(&mut context, &[withdraw], &[&staker]).await, StakePoolError::StakeNotDeactivated);
+    }
+
+    fn verify_backing_ix(pool: &TestPool, stake_accounts: &[Pubkey]) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+            AccountMeta::new_readonly(pool.addresses.mint, false),
+        ];
+        accounts.extend(stake_accounts.iter().map(|address| AccountMeta::new_readonly(*address, false)));
+        pool_ix(pool, StakePoolInstruction::VerifyBacking, accounts)
+    }
+
+    #[tokio::test]
+    async fn verify_backing_reports_healthy_and_partial_pools() {
+        let (mut context, pool, _) = setup(0).await;
+        let mut stake_accounts = Vec::new();
+        for _ in 0..2 {
+            let user = create_user(&mut context, &pool).await;
+            send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+            stake_accounts.push(user_stake_account(&pool, &user.pubkey()));
+        }
+
+        let report: BackingReport = query(&mut context, verify_backing_ix(&pool, &stake_accounts)).await;
+        assert_eq!(report.stake_accounts, 2);
+        assert_eq!(report.delegated_stake, 2 * STAKE_AMOUNT);
+        assert_eq!(report.obligations, 2 * STAKE_AMOUNT);
+        assert_eq!(report.backing_ratio_bps, 10_000);
+        assert!(report.fully_backed && !report.partial);
+        assert_eq!(report.shortfall, 0);
+
+        // Leaving an account out is flagged rather than passed off as a deficit
+        let report: BackingReport = query(&mut context, verify_backing_ix(&pool, &stake_accounts[..1])).await;
+        assert!(!report.fully_backed && report.partial);
+        assert_eq!(report.shortfall, STAKE_AMOUNT);
+
+        let duplicate = verify_backing_ix(&pool, &[stake_accounts[0], stake_accounts[0]]);
+        assert_instruction_error(send(&mut context, &[duplicate], &[]).await, InstructionError::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn verify_backing_reports_deficit() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+
+        // obeSOL minted outside the pool's books is owed but backed by nothing
+        let mut mint_account = context.banks_client.get_account(pool.addresses.mint).await.unwrap().unwrap();
+        let mut mint = spl_token::state::Mint::unpack(&mint_account.data).unwrap();
+        mint.supply += STAKE_AMOUNT / 4;
+        spl_token::state::Mint::pack(mint, &mut mint_account.data).unwrap();
+        context.set_account(&pool.addresses.mint, &mint_account.into());
+
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        let report: BackingReport = query(&mut context, verify_backing_ix(&pool, &[stake_account])).await;
+        assert_eq!(report.total_backing, STAKE_AMOUNT);
+        assert_eq!(report.obligations, STAKE_AMOUNT + STAKE_AMOUNT / 4);
+        assert_eq!(report.backing_ratio_bps, 8_000);
+        assert!(!report.fully_backed && !report.partial);
+        assert_eq!(report.shortfall, STAKE_AMOUNT / 4);
+    }
+
+    /// Compute units `instructions` consume, measured by simulation before sending them
+    async fn send_metered(context: &mut ProgramTestContext, instructions: &[Instruction], signers: &[&Keypair]) -> u64 {
+        let mut all_signers = vec![&context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&context.payer.pubkey()),
+            &all_signers,
+            context.last_blockhash,
+        );
+        let simulation = context.banks_client.simulate_transaction(transaction).await.unwrap();
+        simulation.result.unwrap().unwrap();
+        let units = simulation.simulation_details.unwrap().units_consumed;
+        send(context, instructions, signers).await.unwrap();
+        units
+    }
+
+    /// Compute-unit budget per instruction, next to the baseline measured when it was
+    /// set. `processor!` runs the program natively, so its own code is not metered: the
+    /// figures cover the syscalls it makes and the programs it invokes, and grow with
+    /// every added CPI. On-chain costs are higher and need an SBF build to measure.
+    const COMPUTE_BUDGETS: [(&str, u64, u64); 5] = [
+        // (instruction, baseline, budget)
+        ("Initialize", 3_361, 4_500),
+        ("Stake", 7_544, 9_500),
+        ("ClaimRewards", 118, 500),
+        ("Unstake", 5_575, 7_000),
+        ("WithdrawStake", 868, 1_500),
+    ];
+
+    #[tokio::test]
+    async fn compute_unit_budgets() {
+        let (mut context, pool, _) = setup(0).await;
+        let mut measured = Vec::new();
+
+        let authority = create_user(&mut context, &pool).await;
+        let initialize = client::initialize(
+            &pool.program_id,
+            &authority.pubkey(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            "Metered".to_string(),
+            0,
+            &pool.validator_vote,
+        );
+        measured.push(("Initialize", send_metered(&mut context, &[initialize], &[&authority]).await));
+        let user = create_user(&mut context, &pool).await;
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        measured.push(("Stake", send_metered(&mut context, &[stake], &[&user]).await));
+        advance_epoch(&mut context).await;
+        let claim = pool_ix(
+            &pool,
+            StakePoolInstruction::ClaimRewards,
+            vec![
+                AccountMeta::new_readonly(user.pubkey(), true),
+                AccountMeta::new(pool.addresses.stake_pool, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+            ],
+        );
+        measured.push(("ClaimRewards", send_metered(&mut context, &[claim], &[&user]).await));
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        measured.push(("Unstake", send_metered(&mut context, &[unstake], &[&user]).await));
+        advance_epoch(&mut context).await;
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        measured.push(("WithdrawStake", send_metered(&mut context, &[withdraw], &[&user]).await));
+
+        for ((name, units), (budget_name, baseline, budget)) in measured.into_iter().zip(COMPUTE_BUDGETS) {
+            assert_eq!(name, budget_name);
+            assert!(units <= budget, "{} used {} compute units, over its budget of {} (baseline {})", name, units, budget, baseline);
+        }
+    }
+
+    #[tokio::test]
+    async fn unstake_all_exits_the_whole_balance() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let unstake_all = |min_tokens| {
+            let mut unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, 0);
+            unstake.data = StakePoolInstruction::UnstakeAll { min_tokens }.try_to_vec().unwrap();
+            unstake
+        };
+
+        // The balance is below what the client expected to burn
+        let result = send(&mut context, &[unstake_all(STAKE_AMOUNT + 1)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InsufficientBalance);
+
+        send(&mut context, &[unstake_all(STAKE_AMOUNT)], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, 0);
+        assert_eq!(stake_record_state(&mut context, &pool, &stake_account).await.pool_tokens, 0);
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!((state.total_staked, state.total_shares), (0, 0));
+        assert_eq!(state.total_pending_withdrawal, STAKE_AMOUNT);
+
+        advance_epoch(&mut context).await;
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+        assert!(context.banks_client.get_account(stake_account).await.unwrap().is_none());
+        assert_eq!(pool_state(&mut context, &pool).await.total_pending_withdrawal, 0);
+    }
+
+
+    /// Rewrites the pool mint in place; its lamports stay the same
+    async fn edit_mint(context: &mut ProgramTestContext, pool: &TestPool, edit: impl FnOnce(&mut spl_token::state::Mint)) {
+        let mut mint_account = context.banks_client.get_account(pool.addresses.mint).await.unwrap().unwrap();
+        let mut mint = spl_token::state::Mint::unpack(&mint_account.data).unwrap();
+        edit(&mut mint);
+        spl_token::state::Mint::pack(mint, &mut mint_account.data).unwrap();
+        context.set_account(&pool.addresses.mint, &mint_account.into());
+    }
+
+    #[tokio::test]
+    async fn freezable_mint_blocks_stake_and_unstake() {
+        let (mut context, pool, _) = setup(0).await;
+        let users = [create_user(&mut context, &pool).await, create_user(&mut context, &pool).await];
+        send(&mut context, &[stake_ix(&pool, &users[0].pubkey(), STAKE_AMOUNT)], &[&users[0]]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &users[0].pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+
+        let freeze_authority = Pubkey::new_unique();
+        edit_mint(&mut context, &pool, |mint| mint.freeze_authority = COption::Some(freeze_authority)).await;
+        let result = send(&mut context, &[stake_ix(&pool, &users[1].pubkey(), STAKE_AMOUNT)], &[&users[1]]).await;
+        assert_pool_error(result, StakePoolError::MintHasFreezeAuthority);
+        let result = send(&mut context, std::slice::from_ref(&unstake), &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::MintHasFreezeAuthority);
+
+        edit_mint(&mut context, &pool, |mint| mint.freeze_authority = COption::None).await;
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake_ix(&pool, &users[1].pubkey(), STAKE_AMOUNT)], &[&users[1]]).await.unwrap();
+        send(&mut context, &[unstake], &[&users[0]]).await.unwrap();
+    }
+
+
+    #[tokio::test]
+    async fn initialize_rejects_colliding_fee_accounts() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = create_user(&mut context, &pool).await;
+        let initialize = |manager: &Pubkey, treasury: &Pubkey| {
+            client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                manager,
+                treasury,
+                "Fee Pool".to_string(),
+                5,
+                &pool.validator_vote,
+            )
+        };
+        let (manager, treasury) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        // Manager and treasury must differ, and neither may be the authority's wallet
+        for (manager, treasury) in [(manager, manager), (authority.pubkey(), treasury), (manager, authority.pubkey())] {
+            let result = send(&mut context, &[initialize(&manager, &treasury)], &[&authority]).await;
+            assert_pool_error(result, StakePoolError::InvalidFeeAccount);
+        }
+
+        send(&mut context, &[initialize(&manager, &treasury)], &[&authority]).await.unwrap();
+        let addresses = client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id);
+        let account = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+        let state = StakePool::unpack_account(&account.data).unwrap();
+        assert_eq!((state.manager_fee_account, state.treasury_fee_account), (manager, treasury));
+    }
+
+
+    #[tokio::test]
+    async fn preview_withdraw_matches_the_withdrawal() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        let preview = pool_ix(
+            &pool,
+            StakePoolInstruction::PreviewWithdraw,
+            vec![
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new_readonly(stake_account, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+            ],
+        );
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        let active: WithdrawPreview = query(&mut context, preview.clone()).await;
+        assert_eq!((active.deactivation_epoch, active.ready), (u64::MAX, false));
+
+        advance_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+        let cooling: WithdrawPreview = query(&mut context, preview.clone()).await;
+        assert_eq!((cooling.deactivation_epoch, cooling.ready), (current_epoch(&mut context).await, false));
+
+        advance_epoch(&mut context).await;
+        let ready: WithdrawPreview = query(&mut context, preview.clone()).await;
+        assert!(ready.ready);
+        assert_eq!(ready.withdrawable_lamports, lamports(&mut context, &stake_account).await);
+        let record_lamports = lamports(&mut context, &stake_record(&pool, &stake_account)).await;
+        let user_lamports = lamports(&mut context, &user.pubkey()).await;
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+        assert_eq!(lamports(&mut context, &user.pubkey()).await, user_lamports + ready.withdrawable_lamports + record_lamports);
+
+        // Nothing is left to preview once the account is closed
+        assert_instruction_error(send(&mut context, &[preview], &[]).await, InstructionError::IllegalOwner);
+    }
+
+
+    #[tokio::test]
+    async fn initialize_twice_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = create_user(&mut context, &pool).await;
+        let initialize = |name: &str| {
+            client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                name.to_string(),
+                0,
+                &pool.validator_vote,
+            )
+        };
+        send(&mut context, &[initialize("First Pool")], &[&authority]).await.unwrap();
+        let addresses = client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id);
+        let before = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+
+        // The same authority derives the same pool address, which is already taken
+        let result = send(&mut context, &[initialize("Second Pool")], &[&authority]).await;
+        assert_instruction_error(result, InstructionError::AccountAlreadyInitialized);
+        let after = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+        assert_eq!(after.data, before.data);
+    }
+
+
+    fn stake_limits_ix(pool: &TestPool) -> Instruction {
+        pool_ix(
+            pool,
+            StakePoolInstruction::GetStakeLimits,
+            vec![
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new_readonly(solana_program::stake::program::id(), false),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn configured_minimum_below_minimum_delegation_is_raised() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let user = create_user(&mut context, &pool).await;
+        let set_min_stake = StakePoolInstruction::UpdatePoolConfig { fee_percentage: None, min_stake: Some(1), max_stake: None, paused: None };
+        send(&mut context, &[admin_ix(&pool, &authority, set_min_stake)], &[]).await.unwrap();
+
+        let limits: StakeLimits = query(&mut context, stake_limits_ix(&pool)).await;
+        assert_eq!(limits.min_stake, 1);
+        assert!(limits.minimum_delegation > 1, "the stake program's minimum delegation should be above 1 lamport");
+        let minimum = limits.minimum_delegation;
+
+        // The stake program would refuse the smaller delegation, so the pool refuses it first
+        let result = send(&mut context, &[stake_ix(&pool, &user.pubkey(), minimum - 1)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::StakeTooSmall);
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), minimum)], &[&user]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.total_staked, minimum);
+    }
+
+
+    /// Rewrites the pool state in place; its lamports stay the same
+    async fn edit_pool(context: &mut ProgramTestContext, pool: &TestPool, edit: impl FnOnce(&mut StakePool)) {
+        let mut account = context.banks_client.get_account(pool.addresses.stake_pool).await.unwrap().unwrap();
+        let mut state = StakePool::unpack_account(&account.data).unwrap();
+        edit(&mut state);
+        state.pack_account(&mut account.data).unwrap();
+        context.set_account(&pool.addresses.stake_pool, &account.into());
+    }
+
+    #[tokio::test]
+    async fn repair_mint_restores_an_unset_mint() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let repair = |signer: &Pubkey, mint: Pubkey| {
+            pool_ix(
+                &pool,
+                StakePoolInstruction::RepairMint { mint },
+                vec![
+                    AccountMeta::new_readonly(*signer, true),
+                    AccountMeta::new(pool.addresses.stake_pool, false),
+                    AccountMeta::new_readonly(pool.addresses.mint, false),
+                ],
+            )
+        };
+
+        // A pool with its mint recorded has nothing to repair
+        let result = send(&mut context, &[repair(&authority, pool.addresses.mint)], &[]).await;
+        assert_instruction_error(result, InstructionError::AccountAlreadyInitialized);
+
+        edit_pool(&mut context, &pool, |state| state.mint = Pubkey::default()).await;
+        let user = create_user(&mut context, &pool).await;
+        let result = send(&mut context, &[repair(&user.pubkey(), pool.addresses.mint)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        let result = send(&mut context, &[repair(&authority, Pubkey::new_unique())], &[]).await;
+        assert_instruction_error(result, InstructionError::InvalidSeeds);
+
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[repair(&authority, pool.addresses.mint)], &[]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.mint, pool.addresses.mint);
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+    }
+
+
+    fn claim_rewards_ix(pool: &TestPool, cranker: &Pubkey, optional_accounts: &[AccountMeta]) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*cranker, true),
+            AccountMeta::new(pool.addresses.stake_pool, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ];
+        accounts.extend_from_slice(optional_accounts);
+        pool_ix(pool, StakePoolInstruction::ClaimRewards, accounts)
+    }
+
+    #[tokio::test]
+    async fn second_crank_in_an_epoch_changes_nothing() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let (history, _) = Pubkey::find_program_address(
+            &[HISTORY_SEED, pool.addresses.stake_pool.as_ref()],
+            &pool.program_id,
+        );
+        let create_history = pool_ix(
+            &pool,
+            StakePoolInstruction::CreateHistory { capacity: 4 },
+            vec![
+                AccountMeta::new(authority, true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(history, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        send(&mut context, &[create_history], &[]).await.unwrap();
+        let cranker = create_user(&mut context, &pool).await;
+        let crank = claim_rewards_ix(&pool, &cranker.pubkey(), &[AccountMeta::new(history, false)]);
+
+        advance_epoch(&mut context).await;
+        let mut unsigned = crank.clone();
+        unsigned.accounts[0].is_signer = false;
+        assert_instruction_error(send(&mut context, &[unsigned], &[]).await, InstructionError::MissingRequiredSignature);
+        send(&mut context, std::slice::from_ref(&crank), &[&cranker]).await.unwrap();
+        let pool_after_first = context.banks_client.get_account(pool.addresses.stake_pool).await.unwrap().unwrap();
+        let history_after_first = context.banks_client.get_account(history).await.unwrap().unwrap();
+
+        // A front-running second crank in the same epoch leaves both accounts untouched
+        let rival = create_user(&mut context, &pool).await;
+        let rival_crank = claim_rewards_ix(&pool, &rival.pubkey(), &[AccountMeta::new(history, false)]);
+        send(&mut context, &[rival_crank], &[&rival]).await.unwrap();
+        let pool_account = context.banks_client.get_account(pool.addresses.stake_pool).await.unwrap().unwrap();
+        assert_eq!(pool_account.data, pool_after_first.data);
+        assert_eq!(context.banks_client.get_account(history).await.unwrap().unwrap().data, history_after_first.data);
+
+        // The next epoch advances again
+        advance_epoch(&mut context).await;
+        send(&mut context, &[crank], &[&cranker]).await.unwrap();
+        let epoch = current_epoch(&mut context).await;
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, epoch);
+        let account = context.banks_client.get_account(history).await.unwrap().unwrap();
+        let entries = PoolHistory::unpack_account(&account.data).unwrap().ordered();
+        assert_eq!(entries.iter().map(|entry| entry.epoch).collect::<Vec<_>>(), [epoch - 1, epoch]);
+    }
+
+
+    #[tokio::test]
+    async fn underfunded_first_stake_reports_insufficient_balance() {
+        let (mut context, pool, _) = setup(0).await;
+        let payer = context.payer.pubkey();
+        let user = Keypair::new();
+        let rent = context.banks_client.get_rent().await.unwrap();
+        let rent_due = rent.minimum_balance(STAKE_ACCOUNT_SIZE)
+            + rent.minimum_balance(StakeRecord::default().to_account_data().unwrap().len());
+        let create_ata = spl_associated_token_account::instruction::create_associated_token_account(
+            &payer,
+            &user.pubkey(),
+            &pool.addresses.mint,
+            &spl_token::id(),
+        );
+        // Enough for the stake itself but one lamport short of the new accounts' rent
+        let fund = system_instruction::transfer(&payer, &user.pubkey(), STAKE_AMOUNT + rent_due - 1);
+        send(&mut context, &[fund, create_ata], &[]).await.unwrap();
+
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        let result = send(&mut context, std::slice::from_ref(&stake), &[&user]).await;
+        assert_pool_error(result, StakePoolError::InsufficientBalance);
+
+        send(&mut context, &[system_instruction::transfer(&payer, &user.pubkey(), 1)], &[]).await.unwrap();
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+        assert_eq!(lamports(&mut context, &user.pubkey()).await, 0);
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn initialize_rejects_pool_accounts_as_fee_accounts() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = create_user(&mut context, &pool).await;
+        let addresses = client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id);
+        let initialize = |manager: &Pubkey, treasury: &Pubkey| {
+            client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                manager,
+                treasury,
+                "Fee Pool".to_string(),
+                5,
+                &pool.validator_vote,
+            )
+        };
+        let manager = Pubkey::new_unique();
+
+        for own_account in [addresses.stake_pool, addresses.mint, addresses.stake_authority, addresses.withdraw_authority] {
+            let result = send(&mut context, &[initialize(&manager, &own_account)], &[&authority]).await;
+            assert_pool_error(result, StakePoolError::InvalidFeeAccount);
+            let result = send(&mut context, &[initialize(&own_account, &manager)], &[&authority]).await;
+            assert_pool_error(result, StakePoolError::InvalidFeeAccount);
+        }
+
+        let treasury = Pubkey::new_unique();
+        send(&mut context, &[initialize(&manager, &treasury)], &[&authority]).await.unwrap();
+        let account = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+        assert_eq!(StakePool::unpack_account(&account.data).unwrap().treasury_fee_account, treasury);
+    }
+
+
+    #[tokio::test]
+    async fn unstake_paying_out_nothing_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        // At half a lamport per token a single token rounds down to nothing
+        edit_pool(&mut context, &pool, |state| state.total_shares = 2 * state.total_staked).await;
+        let unstake = |amount| client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, amount);
+
+        let result = send(&mut context, &[unstake(1)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::OutputBelowMinimum);
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+        // Two tokens pay out a lamport, so only the split's minimum delegation stops them
+        let result = send(&mut context, &[unstake(2)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::PartialUnstakeBelowMinimum);
+        send(&mut context, &[unstake(STAKE_AMOUNT)], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, 0);
+    }
+
+
+    #[tokio::test]
+    async fn sweep_dust_normalizes_an_emptied_pool() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let users = [create_user(&mut context, &pool).await, create_user(&mut context, &pool).await];
+        send(&mut context, &[stake_ix(&pool, &users[0].pubkey(), STAKE_AMOUNT)], &[&users[0]]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &users[0].pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&users[0]]).await.unwrap();
+        // Every token is burned, yet rounding left residue in both totals
+        edit_pool(&mut context, &pool, |state| {
+            state.total_staked = 7;
+            state.total_shares = 3;
+        })
+        .await;
+        let sweep = |signer: &Pubkey, dust_threshold| {
+            pool_ix(
+                &pool,
+                StakePoolInstruction::SweepDust { dust_threshold },
+                vec![
+                    AccountMeta::new_readonly(*signer, true),
+                    AccountMeta::new(pool.addresses.stake_pool, false),
+                    AccountMeta::new_readonly(pool.addresses.mint, false),
+                ],
+            )
+        };
+
+        let result = send(&mut context, &[sweep(&users[0].pubkey(), 10)], &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        let result = send(&mut context, &[sweep(&authority, MAX_DUST_THRESHOLD + 1)], &[]).await;
+        assert_instruction_error(result, InstructionError::InvalidArgument);
+        // Residue above the threshold is left for manual review
+        send(&mut context, &[sweep(&authority, 2)], &[]).await.unwrap();
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!((state.total_staked, state.total_shares), (7, 3));
+
+        send(&mut context, &[sweep(&authority, 10)], &[]).await.unwrap();
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!((state.total_staked, state.total_shares), (0, 0));
+        // The next depositor is priced one to one again
+        send(&mut context, &[stake_ix(&pool, &users[1].pubkey(), STAKE_AMOUNT)], &[&users[1]]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &users[1].pubkey()).await, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn claim_rewards_reports_an_already_processed_epoch() {
+        let (mut context, pool, _) = setup(0).await;
+        let cranker = context.payer.pubkey();
+        advance_epoch(&mut context).await;
+        let epoch = current_epoch(&mut context).await;
+        let claim = claim_rewards_ix(&pool, &cranker, &[]);
+
+        let mut wrong_clock = claim.clone();
+        wrong_clock.accounts[2] = AccountMeta::new_readonly(sysvar::rent::id(), false);
+        assert_instruction_error(send(&mut context, &[wrong_clock], &[]).await, InstructionError::InvalidArgument);
+
+        let first: ClaimStatus = query(&mut context, claim.clone()).await;
+        assert_eq!(first, ClaimStatus { already_processed: false, current_epoch: epoch });
+        send(&mut context, std::slice::from_ref(&claim), &[]).await.unwrap();
+        // The second call in the epoch is a no-op, not an error
+        context.get_new_latest_blockhash().await.unwrap();
+        let second: ClaimStatus = query(&mut context, claim).await;
+        assert_eq!(second, ClaimStatus { already_processed: true, current_epoch: epoch });
+    }
+
+
+    #[tokio::test]
+    async fn unstake_limit_holds_exits_until_the_next_epoch() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let mut users = Vec::new();
+        for _ in 0..3 {
+            let user = create_user(&mut context, &pool).await;
+            send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+            users.push(user);
+        }
+        let set_limit = |signer: &Pubkey, max_unstake_per_epoch| {
+            admin_ix(&pool, signer, StakePoolInstruction::SetUnstakeLimit { max_unstake_per_epoch })
+        };
+        let unstake = |user: &Keypair| client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+
+        let result = send(&mut context, &[set_limit(&users[0].pubkey(), 2 * STAKE_AMOUNT)], &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        send(&mut context, &[set_limit(&authority, 2 * STAKE_AMOUNT)], &[]).await.unwrap();
+        advance_epoch(&mut context).await;
+
+        // Two exits fill the epoch's allowance and the third waits for the next epoch
+        send(&mut context, &[unstake(&users[0])], &[&users[0]]).await.unwrap();
+        send(&mut context, &[unstake(&users[1])], &[&users[1]]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.unstaked_this_epoch, 2 * STAKE_AMOUNT);
+        let result = send(&mut context, &[unstake(&users[2])], &[&users[2]]).await;
+        assert_pool_error(result, StakePoolError::UnstakeLimitReached);
+        assert_eq!(token_balance(&mut context, &pool, &users[2].pubkey()).await, STAKE_AMOUNT);
+
+        advance_epoch(&mut context).await;
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[unstake(&users[2])], &[&users[2]]).await.unwrap();
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.unstaked_this_epoch, STAKE_AMOUNT);
+        assert_eq!(state.unstake_tracking_epoch, current_epoch(&mut context).await);
+    }
+
+
+    #[tokio::test]
+    async fn initialize_rejects_a_premade_mint_it_does_not_control() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = create_user(&mut context, &pool).await;
+        let addresses = client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id);
+        let initialize = client::initialize(
+            &pool.program_id,
+            &authority.pubkey(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            "Front Run Pool".to_string(),
+            0,
+            &pool.validator_vote,
+        );
+        let premade_mint = |supply, mint_authority| {
+            let mint = spl_token::state::Mint {
+                mint_authority: COption::Some(mint_authority),
+                supply,
+                decimals: 9,
+                is_initialized: true,
+                freeze_authority: COption::None,
+            };
+            let mut data = vec![0; spl_token::state::Mint::LEN];
+            spl_token::state::Mint::pack(mint, &mut data).unwrap();
+            Account {
+                lamports: Rent::default().minimum_balance(data.len()),
+                data,
+                owner: spl_token::id(),
+                executable: false,
+                rent_epoch: 0,
+            }
+        };
+
+        // Tokens minted ahead of the pool would be backed by nothing
+        context.set_account(&addresses.mint, &premade_mint(LAMPORTS_PER_SOL, addresses.stake_authority).into());
+        let result = send(&mut context, std::slice::from_ref(&initialize), &[&authority]).await;
+        assert_pool_error(result, StakePoolError::MintSupplyNotZero);
+        context.set_account(&addresses.mint, &premade_mint(0, Pubkey::new_unique()).into());
+        context.get_new_latest_blockhash().await.unwrap();
+        let result = send(&mut context, std::slice::from_ref(&initialize), &[&authority]).await;
+        assert_pool_error(result, StakePoolError::InvalidMintAuthority);
+
+        // An empty mint the pool controls, e.g. from an interrupted init, is adopted
+        context.set_account(&addresses.mint, &premade_mint(0, addresses.stake_authority).into());
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[initialize], &[&authority]).await.unwrap();
+        let account = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+        assert_eq!(StakePool::unpack_account(&account.data).unwrap().mint, addresses.mint);
+    }
+
+
+    #[tokio::test]
+    async fn claim_and_query_returns_the_refreshed_value() {
+        let (mut context, pool, _) = setup(0).await;
+        let users = [create_user(&mut context, &pool).await, create_user(&mut context, &pool).await];
+        for user in &users {
+            send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[user]).await.unwrap();
+        }
+        let claim_and_query = |user: &Pubkey, token_owner: &Pubkey| {
+            pool_ix(
+                &pool,
+                StakePoolInstruction::ClaimAndQuery,
+                vec![
+                    AccountMeta::new_readonly(*user, true),
+                    AccountMeta::new(pool.addresses.stake_pool, false),
+                    AccountMeta::new_readonly(sysvar::clock::id(), false),
+                    AccountMeta::new_readonly(
+                        spl_associated_token_account::get_associated_token_address(token_owner, &pool.addresses.mint),
+                        false,
+                    ),
+                ],
+            )
+        };
+        // Rewards booked into the pool raise every token's value by a tenth
+        let rewards = 2 * STAKE_AMOUNT / 10;
+        edit_pool(&mut context, &pool, |state| state.total_staked += rewards).await;
+        advance_epoch(&mut context).await;
+        let epoch = current_epoch(&mut context).await;
+
+        let result = send(&mut context, &[claim_and_query(&users[0].pubkey(), &users[1].pubkey())], &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::InvalidOwner);
+
+        let query_ix = claim_and_query(&users[0].pubkey(), &users[0].pubkey());
+        let first: ClaimQueryResult = query_signed(&mut context, query_ix.clone(), &[&users[0]]).await;
+        assert_eq!(first, ClaimQueryResult {
+            already_processed: false,
+            current_epoch: epoch,
+            pool_tokens: STAKE_AMOUNT,
+            sol_value: STAKE_AMOUNT + rewards / 2,
+        });
+        send(&mut context, std::slice::from_ref(&query_ix), &[&users[0]]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.last_update_epoch, epoch);
+        // Once the epoch is processed, only the value comes back
+        context.get_new_latest_blockhash().await.unwrap();
+        let second: ClaimQueryResult = query_signed(&mut context, query_ix, &[&users[0]]).await;
+        assert_eq!(second, ClaimQueryResult { already_processed: true, ..first });
+    }
+
+
+    /// Simulates `instruction` without verifying signatures, so accounts no one can
+    /// sign for reach the program as signers
+    async fn simulate_unsigned(context: &mut ProgramTestContext, instruction: Instruction) -> Result<(), TransactionError> {
+        let message = Message::new_with_blockhash(&[instruction], Some(&context.payer.pubkey()), &context.last_blockhash);
+        let simulation = context.banks_client.simulate_transaction(Transaction::new_unsigned(message)).await.unwrap();
+        simulation.result.unwrap()
+    }
+
+    #[tokio::test]
+    async fn initialize_rejects_an_authority_that_cannot_sign() {
+        let (mut context, pool, _) = setup(0).await;
+        let initialize = |authority: &Pubkey| {
+            client::initialize(
+                &pool.program_id,
+                authority,
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                "Bricked Pool".to_string(),
+                0,
+                &pool.validator_vote,
+            )
+        };
+
+        // Neither the zero key nor the incinerator can ever sign for the pool
+        for authority in [Pubkey::default(), solana_program::incinerator::id()] {
+            let result = simulate_unsigned(&mut context, initialize(&authority)).await;
+            assert_pool_error(result, StakePoolError::InvalidAuthority);
+        }
+
+        let wallet = create_user(&mut context, &pool).await;
+        send(&mut context, &[initialize(&wallet.pubkey())], &[&wallet]).await.unwrap();
+    }
+
+
+    #[tokio::test]
+    async fn reclaim_excess_rent_moves_only_the_surplus() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let user = create_user(&mut context, &pool).await;
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        let treasury = pool_state(&mut context, &pool).await.treasury_fee_account;
+        let rent_exempt_minimum = lamports(&mut context, &pool.addresses.stake_pool).await;
+        send(&mut context, &[system_instruction::transfer(&authority, &pool.addresses.stake_pool, LAMPORTS_PER_SOL)], &[])
+            .await
+            .unwrap();
+        let reclaim = |signer: &Pubkey, target: &Pubkey, recipient: &Pubkey| {
+            pool_ix(
+                &pool,
+                StakePoolInstruction::ReclaimExcessRent,
+                vec![
+                    AccountMeta::new_readonly(*signer, true),
+                    AccountMeta::new(pool.addresses.stake_pool, false),
+                    AccountMeta::new(*target, false),
+                    AccountMeta::new(*recipient, false),
+                ],
+            )
+        };
+
+        let result = send(&mut context, &[reclaim(&user.pubkey(), &pool.addresses.stake_pool, &treasury)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        let result = send(&mut context, &[reclaim(&authority, &pool.addresses.stake_pool, &user.pubkey())], &[]).await;
+        assert_pool_error(result, StakePoolError::InvalidFeeAccount);
+        // Stake records are the program's too, but not the pool's to drain
+        let record = stake_record(&pool, &user_stake_account(&pool, &user.pubkey()));
+        let result = send(&mut context, &[reclaim(&authority, &record, &treasury)], &[]).await;
+        assert_instruction_error(result, InstructionError::InvalidSeeds);
+
+        send(&mut context, &[reclaim(&authority, &pool.addresses.stake_pool, &treasury)], &[]).await.unwrap();
+        assert_eq!(lamports(&mut context, &pool.addresses.stake_pool).await, rent_exempt_minimum);
+        assert_eq!(lamports(&mut context, &treasury).await, LAMPORTS_PER_SOL);
+        // Nothing more comes out of a rent-exempt account
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[reclaim(&authority, &pool.addresses.stake_pool, &treasury)], &[]).await.unwrap();
+        assert_eq!(lamports(&mut context, &pool.addresses.stake_pool).await, rent_exempt_minimum);
+    }
+
+
+    #[tokio::test]
+    async fn stake_rejects_a_mint_authority_the_pool_does_not_hold() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+
+        edit_mint(&mut context, &pool, |mint| mint.mint_authority = COption::None).await;
+        let result = send(&mut context, std::slice::from_ref(&stake), &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidMintAuthority);
+
+        edit_mint(&mut context, &pool, |mint| mint.mint_authority = COption::Some(Pubkey::new_unique())).await;
+        context.get_new_latest_blockhash().await.unwrap();
+        let result = send(&mut context, std::slice::from_ref(&stake), &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidMintAuthority);
+
+        edit_mint(&mut context, &pool, |mint| mint.mint_authority = COption::Some(pool.addresses.stake_authority)).await;
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn program_info_reports_compiled_features_and_pool_flags() {
+        let (mut context, pool, _) = setup(0).await;
+        let program_info = |metas| pool_ix(&pool, StakePoolInstruction::GetProgramInfo, metas);
+
+        let info: ProgramInfo = query(&mut context, program_info(vec![])).await;
+        let mut compiled = FEATURE_TEST_UTILS;
+        if cfg!(feature = "debug-logging") {
+            compiled |= FEATURE_DEBUG_LOGGING;
+        }
+        assert_eq!(info.program_version, PROGRAM_VERSION);
+        assert_eq!(info.pool_state_version, POOL_STATE_VERSION);
+        assert_eq!(info.features, compiled);
+        assert_eq!(info.pool_flags, 0);
+
+        edit_pool(&mut context, &pool, |state| {
+            state.paused = true;
+            state.max_unstake_per_epoch = LAMPORTS_PER_SOL;
+        })
+        .await;
+        let info: ProgramInfo =
+            query(&mut context, program_info(vec![AccountMeta::new_readonly(pool.addresses.stake_pool, false)])).await;
+        assert_eq!(info.features, compiled);
+        assert_eq!(info.pool_flags, POOL_FLAG_PAUSED | POOL_FLAG_UNSTAKE_LIMIT);
+
+        // Only this program's pools carry flags
+        let result = send(&mut context, &[program_info(vec![AccountMeta::new_readonly(pool.addresses.mint, false)])], &[]).await;
+        assert_instruction_error(result, InstructionError::IllegalOwner);
+    }
+
+
+    #[tokio::test]
+    async fn user_values_are_priced_at_the_pool_ratio() {
+        let (mut context, pool, _) = setup(0).await;
+        let mut token_accounts = Vec::new();
+        for amount in [STAKE_AMOUNT, 2 * STAKE_AMOUNT, 3 * STAKE_AMOUNT] {
+            let user = create_user(&mut context, &pool).await;
+            send(&mut context, &[stake_ix(&pool, &user.pubkey(), amount)], &[&user]).await.unwrap();
+            token_accounts.push(spl_associated_token_account::get_associated_token_address(&user.pubkey(), &pool.addresses.mint));
+        }
+        // Each token is now worth two lamports
+        edit_pool(&mut context, &pool, |state| state.total_staked *= 2).await;
+        let user_values = |pool_account: Pubkey, offset, limit| {
+            let mut metas = vec![AccountMeta::new_readonly(pool_account, false)];
+            metas.extend(token_accounts.iter().map(|account| AccountMeta::new_readonly(*account, false)));
+            metas.push(AccountMeta::new_readonly(pool.addresses.mint, false));
+            pool_ix(&pool, StakePoolInstruction::GetUserValues { offset, limit }, metas)
+        };
+
+        let page: ReturnPage<UserValue> = query(&mut context, user_values(pool.addresses.stake_pool, 0, 10)).await;
+        assert!(!page.has_more);
+        assert_eq!(page.items.len(), 4);
+        for (value, (account, amount)) in page.items.iter().zip(token_accounts.iter().zip(1..)) {
+            assert_eq!(value.token_account, *account);
+            assert!(value.valid);
+            assert_eq!(value.pool_tokens, amount * STAKE_AMOUNT);
+            assert_eq!(value.sol_value, 2 * amount * STAKE_AMOUNT);
+        }
+        // The mint is not a token account: flagged, not fatal
+        assert_eq!(page.items[3], UserValue { token_account: pool.addresses.mint, ..UserValue::default() });
+
+        let page: ReturnPage<UserValue> = query(&mut context, user_values(pool.addresses.stake_pool, 1, 1)).await;
+        assert!(page.has_more);
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].token_account, token_accounts[1]);
+
+        let result = send(&mut context, &[user_values(pool.addresses.mint, 0, 10)], &[]).await;
+        assert_instruction_error(result, InstructionError::IllegalOwner);
+    }
+
+
+    /// Rewrites the commission of `pool`'s validator vote account
+    async fn set_commission(context: &mut ProgramTestContext, pool: &TestPool, commission: u8) {
+        let mut account = context.banks_client.get_account(pool.validator_vote).await.unwrap().unwrap();
+        let mut vote_state = VoteState::deserialize(&account.data).unwrap();
+        vote_state.commission = commission;
+        VoteState::serialize(&VoteStateVersions::new_current(vote_state), &mut account.data).unwrap();
+        context.set_account(&pool.validator_vote, &account.into());
+    }
+
+    #[tokio::test]
+    async fn commission_hike_holds_deposits_until_acknowledged() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let user = create_user(&mut context, &pool).await;
+        let late_user = create_user(&mut context, &pool).await;
+        let commission_ix = |signer: &Pubkey, instruction| {
+            let mut ix = admin_ix(&pool, signer, instruction);
+            ix.accounts.push(AccountMeta::new_readonly(pool.validator_vote, false));
+            ix
+        };
+        let claim = claim_rewards_ix(&pool, &authority, &[AccountMeta::new_readonly(pool.validator_vote, false)]);
+        let policy = StakePoolInstruction::SetCommissionPolicy { alert_threshold: 5, pause_on_hike: true };
+        let result = send(&mut context, &[commission_ix(&user.pubkey(), policy.clone())], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        send(&mut context, &[commission_ix(&authority, policy)], &[]).await.unwrap();
+
+        // A rise below the threshold is only recorded
+        advance_epoch(&mut context).await;
+        set_commission(&mut context, &pool, 3).await;
+        send(&mut context, std::slice::from_ref(&claim), &[]).await.unwrap();
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.last_known_commission, 3);
+        assert!(!state.commission_hold);
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+
+        advance_epoch(&mut context).await;
+        set_commission(&mut context, &pool, 10).await;
+        send(&mut context, &[claim], &[]).await.unwrap();
+        assert!(pool_state(&mut context, &pool).await.commission_hold);
+        let result = send(&mut context, &[stake_ix(&pool, &late_user.pubkey(), STAKE_AMOUNT)], &[&late_user]).await;
+        assert_pool_error(result, StakePoolError::CommissionHold);
+
+        let result = send(&mut context, &[commission_ix(&user.pubkey(), StakePoolInstruction::AcknowledgeCommission)], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        send(&mut context, &[commission_ix(&authority, StakePoolInstruction::AcknowledgeCommission)], &[]).await.unwrap();
+        assert!(!pool_state(&mut context, &pool).await.commission_hold);
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake_ix(&pool, &late_user.pubkey(), STAKE_AMOUNT)], &[&late_user]).await.unwrap();
+    }
+
+
+    #[tokio::test]
+    async fn pool_with_a_foreign_discriminator_is_rejected() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        let original = context.banks_client.get_account(pool.addresses.stake_pool).await.unwrap().unwrap();
+        assert_eq!(original.data[..ACCOUNT_DISCRIMINATOR_LEN], StakePool::DISCRIMINATOR);
+
+        // Same bytes after the tag, but tagged as another account type
+        let mut relabeled = original.clone();
+        relabeled.data[..ACCOUNT_DISCRIMINATOR_LEN].copy_from_slice(&PoolHistory::DISCRIMINATOR);
+        context.set_account(&pool.addresses.stake_pool, &relabeled.into());
+        let result = send(&mut context, std::slice::from_ref(&stake), &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidAccountDiscriminator);
+
+        context.set_account(&pool.addresses.stake_pool, &original.into());
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+    }
+
+
+    #[tokio::test]
+    async fn require_ata_rejects_other_token_accounts() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let users = [create_user(&mut context, &pool).await, create_user(&mut context, &pool).await];
+        // A pool token account of users[0] that is not its ATA
+        let token_account = Keypair::new();
+        let space = spl_token::state::Account::LEN;
+        let create = [
+            system_instruction::create_account(
+                &authority,
+                &token_account.pubkey(),
+                Rent::default().minimum_balance(space),
+                space as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account3(
+                &spl_token::id(),
+                &token_account.pubkey(),
+                &pool.addresses.mint,
+                &users[0].pubkey(),
+            )
+            .unwrap(),
+        ];
+        send(&mut context, &create, &[&token_account]).await.unwrap();
+        let mut stake_to_account = stake_ix(&pool, &users[0].pubkey(), STAKE_AMOUNT);
+        stake_to_account.accounts[StakeAccounts::UserTokenAccount as usize].pubkey = token_account.pubkey();
+
+        let require_ata = |signer: &Pubkey, require_ata| admin_ix(&pool, signer, StakePoolInstruction::SetRequireAta { require_ata });
+        let result = send(&mut context, &[require_ata(&users[1].pubkey(), true)], &[&users[1]]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        send(&mut context, &[require_ata(&authority, true)], &[]).await.unwrap();
+        let result = send(&mut context, std::slice::from_ref(&stake_to_account), &[&users[0]]).await;
+        assert_pool_error(result, StakePoolError::NonCanonicalTokenAccount);
+        send(&mut context, &[stake_ix(&pool, &users[1].pubkey(), STAKE_AMOUNT)], &[&users[1]]).await.unwrap();
+
+        send(&mut context, &[require_ata(&authority, false)], &[]).await.unwrap();
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake_to_account], &[&users[0]]).await.unwrap();
+        let account = context.banks_client.get_account(token_account.pubkey()).await.unwrap().unwrap();
+        assert_eq!(spl_token::state::Account::unpack(&account.data).unwrap().amount, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn initialize_rejects_a_validator_that_is_not_a_vote_account() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = create_user(&mut context, &pool).await;
+        let initialize = |validator: &Pubkey| {
+            client::initialize(
+                &pool.program_id,
+                &authority.pubkey(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                "Vote Pool".to_string(),
+                5,
+                validator,
+            )
+        };
+
+        // The zero key, a wallet, and a vote account other than the named validator
+        let mut mismatched = initialize(&pool.validator_vote);
+        mismatched.accounts[8].pubkey = Pubkey::new_unique();
+        for instruction in [initialize(&Pubkey::default()), initialize(&authority.pubkey()), mismatched] {
+            let result = send(&mut context, &[instruction], &[&authority]).await;
+            assert_pool_error(result, StakePoolError::InvalidVoteAccount);
+        }
+
+        send(&mut context, &[initialize(&pool.validator_vote)], &[&authority]).await.unwrap();
+        let addresses = client::PoolAddresses::derive(&authority.pubkey(), &pool.program_id);
+        let account = context.banks_client.get_account(addresses.stake_pool).await.unwrap().unwrap();
+        let state = StakePool::unpack_account(&account.data).unwrap();
+        assert_eq!(state.helius_validator_vote, pool.validator_vote);
+    }
+
+
+    #[tokio::test]
+    async fn every_instruction_variant_reaches_a_handler() {
+        let (mut context, pool, _) = setup(0).await;
+        // Enough zero bytes for any variant's arguments: every tag up to the last
+        // variant decodes, with zero, empty or `None` fields
+        let variants: Vec<StakePoolInstruction> = (0..=u8::MAX)
+            .map_while(|tag| {
+                let mut data = vec![tag];
+                data.resize(256, 0);
+                StakePoolInstruction::deserialize(&mut data.as_slice()).ok()
+            })
+            .collect();
+        // WarpPoolEpoch is declared last, so reaching it means no variant was missed
+        assert!(matches!(variants.last(), Some(StakePoolInstruction::WarpPoolEpoch { .. })));
+
+        // Without accounts a handler fails on its first account or, for the queries
+        // that need none, succeeds; neither is a decoding failure
+        for variant in &variants {
+            let instruction = Instruction::new_with_bytes(pool.program_id, &variant.try_to_vec().unwrap(), vec![]);
+            if let Err(error) = send(&mut context, &[instruction], &[]).await {
+                assert_ne!(
+                    error,
+                    TransactionError::InstructionError(0, InstructionError::InvalidInstructionData),
+                    "{} was not dispatched",
+                    variant.name()
+                );
+            }
+        }
+
+        let past_last = Instruction::new_with_bytes(pool.program_id, &[variants.len() as u8], vec![]);
+        assert_instruction_error(send(&mut context, &[past_last], &[]).await, InstructionError::InvalidInstructionData);
+    }
+
+
+    #[tokio::test]
+    async fn stake_rejects_a_wrongly_sized_stake_account() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        let prepare = pool_ix(
+            &pool,
+            StakePoolInstruction::PrepareStakeAccount,
+            vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new(stake_account, false),
+                AccountMeta::new_readonly(solana_program::stake::program::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        send(&mut context, &[prepare], &[&user]).await.unwrap();
+        let prepared = context.banks_client.get_account(stake_account).await.unwrap().unwrap();
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+
+        // The same initialized state, as laid out by a program using a longer account
+        let mut resized = prepared.clone();
+        resized.data.resize(STAKE_ACCOUNT_SIZE + 8, 0);
+        context.set_account(&stake_account, &resized.into());
+        let result = send(&mut context, std::slice::from_ref(&stake), &[&user]).await;
+        assert_pool_error(result, StakePoolError::StakeAccountSizeMismatch);
+
+        context.set_account(&stake_account, &prepared.into());
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn validate_stake_reports_each_failed_precondition() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        // ValidateStake takes Stake's accounts in its own order, without the programs
+        // and sysvars it doesn't need
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        let order = [
+            StakeAccounts::User,
+            StakeAccounts::StakePool,
+            StakeAccounts::UserTokenAccount,
+            StakeAccounts::PoolMint,
+            StakeAccounts::UserStakeAccount,
+            StakeAccounts::ValidatorVote,
+            StakeAccounts::StakeAuthority,
+            StakeAccounts::StakeProgram,
+        ];
+        let validate = |amount, replace: Option<(StakeAccounts, Pubkey)>| {
+            let mut accounts: Vec<AccountMeta> = order
+                .iter()
+                .map(|account| {
+                    let meta = &stake.accounts[*account as usize];
+                    match replace {
+                        Some((replaced, pubkey)) if replaced as usize == *account as usize => {
+                            AccountMeta::new_readonly(pubkey, meta.is_signer)
+                        }
+                        _ => AccountMeta::new_readonly(meta.pubkey, meta.is_signer),
+                    }
+                })
+                .collect();
+            accounts.extend(stake.accounts[StakeAccounts::COUNT..].iter().cloned());
+            pool_ix(&pool, StakePoolInstruction::ValidateStake { amount }, accounts)
+        };
+
+        let report: StakeValidation = query_signed(&mut context, validate(STAKE_AMOUNT, None), &[&user]).await;
+        assert!(report.passed && report.user_signed && report.sufficient_balance && report.mints_tokens);
+        assert!(report.required_lamports > STAKE_AMOUNT);
+        assert_eq!(report.pool_tokens, STAKE_AMOUNT);
+
+        let mut unsigned = validate(STAKE_AMOUNT, None);
+        unsigned.accounts[0].is_signer = false;
+        let report: StakeValidation = query(&mut context, unsigned).await;
+        assert!(!report.passed && !report.user_signed);
+
+        let cases: [(u64, Option<(StakeAccounts, Pubkey)>, fn(&StakeValidation) -> bool); 7] = [
+            (report.min_stake - 1, None, |report| report.above_minimum),
+            (report.max_stake + 1, None, |report| report.below_maximum),
+            (100 * LAMPORTS_PER_SOL, None, |report| report.sufficient_balance),
+            (STAKE_AMOUNT, Some((StakeAccounts::PoolMint, Pubkey::new_unique())), |report| report.mint_valid),
+            (STAKE_AMOUNT, Some((StakeAccounts::UserTokenAccount, user.pubkey())), |report| report.token_account_valid),
+            (STAKE_AMOUNT, Some((StakeAccounts::ValidatorVote, Pubkey::new_unique())), |report| report.validator_matches),
+            (STAKE_AMOUNT, Some((StakeAccounts::UserStakeAccount, Pubkey::new_unique())), |report| report.stake_account_matches),
+        ];
+        for (amount, replace, check) in cases {
+            let report: StakeValidation = query_signed(&mut context, validate(amount, replace), &[&user]).await;
+            assert!(!report.passed && !check(&report));
+        }
+
+        edit_pool(&mut context, &pool, |state| state.paused = true).await;
+        context.get_new_latest_blockhash().await.unwrap();
+        let report: StakeValidation = query_signed(&mut context, validate(STAKE_AMOUNT, None), &[&user]).await;
+        assert!(!report.passed && !report.not_paused);
+
+        // Without the pool there is nothing to report against
+        let result = send(
+            &mut context,
+            &[validate(STAKE_AMOUNT, Some((StakeAccounts::StakePool, pool.addresses.mint)))],
+            &[&user],
+        )
+        .await;
+        assert_instruction_error(result, InstructionError::IllegalOwner);
+    }
+
+
+    #[tokio::test]
+    async fn reconcile_shares_repairs_only_a_small_divergence() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let user = create_user(&mut context, &pool).await;
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        let reconcile = |signer: &Pubkey| {
+            let mut ix = admin_ix(&pool, signer, StakePoolInstruction::ReconcileShares);
+            ix.accounts.push(AccountMeta::new_readonly(pool.addresses.mint, false));
+            ix
+        };
+        let allowed = STAKE_AMOUNT * MAX_SHARES_DIVERGENCE_BPS / 10_000;
+
+        // Too far off to repair without review
+        edit_pool(&mut context, &pool, |state| state.total_shares = 2 * STAKE_AMOUNT).await;
+        let result = send(&mut context, &[reconcile(&authority)], &[]).await;
+        assert_pool_error(result, StakePoolError::SharesDivergenceTooLarge);
+
+        edit_pool(&mut context, &pool, |state| state.total_shares = STAKE_AMOUNT - allowed / 2).await;
+        let result = send(&mut context, &[reconcile(&user.pubkey())], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidAuthority);
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[reconcile(&authority)], &[]).await.unwrap();
+        assert_eq!(pool_state(&mut context, &pool).await.total_shares, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn colliding_authorities_are_rejected() {
+        // Real seeds can't be made to collide, so the guard Initialize runs is fed a
+        // collision directly
+        let authority = Pubkey::new_unique();
+        assert_eq!(
+            assert_distinct_authorities(&authority, &authority),
+            Err(StakePoolError::InvalidProgramAddress.into())
+        );
+        assert_eq!(assert_distinct_authorities(&authority, &Pubkey::new_unique()), Ok(()));
+
+        let (mut context, pool, _) = setup(0).await;
+        let state = pool_state(&mut context, &pool).await;
+        assert_eq!(state.stake_authority, pool.addresses.stake_authority);
+        assert_ne!(state.stake_authority, state.withdraw_authority);
+    }
+
+
+    #[tokio::test]
+    async fn stake_with_setup_onboards_a_new_user() {
+        let (mut context, pool, _) = setup(0).await;
+        // A wallet with SOL and nothing else
+        let user = Keypair::new();
+        let payer = context.payer.pubkey();
+        send(&mut context, &[system_instruction::transfer(&payer, &user.pubkey(), 100 * LAMPORTS_PER_SOL)], &[])
+            .await
+            .unwrap();
+        let ata = spl_associated_token_account::get_associated_token_address(&user.pubkey(), &pool.addresses.mint);
+        let stake_with_setup = |amount, associated_token_program: Pubkey| {
+            let mut ix = stake_ix(&pool, &user.pubkey(), amount);
+            ix.data = StakePoolInstruction::StakeWithSetup { amount }.try_to_vec().unwrap();
+            ix.accounts.insert(StakeAccounts::COUNT, AccountMeta::new_readonly(associated_token_program, false));
+            ix
+        };
+
+        let result = send(&mut context, &[stake_with_setup(STAKE_AMOUNT, Pubkey::new_unique())], &[&user]).await;
+        assert_instruction_error(result, InstructionError::IncorrectProgramId);
+        // A failed stake also undoes the ATA creation
+        let result = send(&mut context, &[stake_with_setup(1, spl_associated_token_account::id())], &[&user]).await;
+        assert_pool_error(result, StakePoolError::StakeTooSmall);
+        assert!(context.banks_client.get_account(ata).await.unwrap().is_none());
+
+        send(&mut context, &[stake_with_setup(STAKE_AMOUNT, spl_associated_token_account::id())], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+        let StakeStateV2::Stake(_, stake, _) = stake_state(&mut context, &user_stake_account(&pool, &user.pubkey())).await else {
+            panic!("stake account is not delegated");
+        };
+        assert_eq!(stake.delegation.voter_pubkey, pool.validator_vote);
+        assert_eq!(pool_state(&mut context, &pool).await.total_staked, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn snapshots_form_a_hash_chain() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let snapshot_address = |index: u64| {
+            Pubkey::find_program_address(
+                &[SNAPSHOT_SEED, pool.addresses.stake_pool.as_ref(), &index.to_le_bytes()],
+                &pool.program_id,
+            )
+            .0
+        };
+        // Anyone can take a snapshot; the user pays for it here
+        let snapshot_ix = |index: u64, previous: Option<Pubkey>| {
+            let mut accounts = vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new_readonly(pool.addresses.stake_pool, false),
+                AccountMeta::new_readonly(pool.addresses.mint, false),
+                AccountMeta::new(snapshot_address(index), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+            ];
+            accounts.extend(previous.map(|previous| AccountMeta::new_readonly(previous, false)));
+            pool_ix(&pool, StakePoolInstruction::Snapshot { index }, accounts)
+        };
+        let read_snapshot = |account: Account| PoolSnapshot::unpack_account(&account.data).unwrap();
+
+        send(&mut context, &[snapshot_ix(0, None)], &[&user]).await.unwrap();
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        send(&mut context, &[snapshot_ix(1, Some(snapshot_address(0)))], &[&user]).await.unwrap();
+
+        let first = read_snapshot(context.banks_client.get_account(snapshot_address(0)).await.unwrap().unwrap());
+        let second = read_snapshot(context.banks_client.get_account(snapshot_address(1)).await.unwrap().unwrap());
+        assert_eq!(first.prev_hash, [0; 32]);
+        assert_eq!(first.hash, first.compute_hash().unwrap());
+        assert_eq!((first.total_staked, first.mint_supply), (0, 0));
+        assert_eq!(second.prev_hash, first.hash);
+        assert_eq!(second.hash, second.compute_hash().unwrap());
+        assert_eq!((second.index, second.total_staked, second.mint_supply), (1, STAKE_AMOUNT, STAKE_AMOUNT));
+
+        // An index is taken once, and only on top of the snapshot just before it
+        context.get_new_latest_blockhash().await.unwrap();
+        let result = send(&mut context, &[snapshot_ix(0, None)], &[&user]).await;
+        assert_instruction_error(result, InstructionError::AccountAlreadyInitialized);
+        let result = send(&mut context, &[snapshot_ix(2, Some(snapshot_address(0)))], &[&user]).await;
+        assert_instruction_error(result, InstructionError::InvalidSeeds);
+        let result = send(&mut context, &[snapshot_ix(3, Some(snapshot_address(2)))], &[&user]).await;
+        assert_instruction_error(result, InstructionError::IllegalOwner);
+    }
+
+
+    #[tokio::test]
+    async fn stake_and_withdraw_reject_a_substituted_stake_history() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        // The clock is a real sysvar, just not the one the stake program expects here
+        let mut stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        let mut withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        for (instruction, index) in [(&mut stake, StakeAccounts::StakeHistorySysvar as usize), (&mut withdraw, 6)] {
+            assert_eq!(instruction.accounts[index].pubkey, sysvar::stake_history::id());
+            instruction.accounts[index].pubkey = sysvar::clock::id();
+        }
+
+        let result = send(&mut context, &[stake], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidSysvar);
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+
+        let result = send(&mut context, &[withdraw], &[&user]).await;
+        assert_pool_error(result, StakePoolError::InvalidSysvar);
+        let before = lamports(&mut context, &user.pubkey()).await;
+        let withdraw = client::withdraw_stake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool);
+        send(&mut context, &[withdraw], &[&user]).await.unwrap();
+        assert!(lamports(&mut context, &user.pubkey()).await > before + STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn stake_limits_report_the_larger_minimum() {
+        let (mut context, pool, _) = setup(0).await;
+        let authority = context.payer.pubkey();
+        let set_limits = |min_stake, max_stake| {
+            let config = StakePoolInstruction::UpdatePoolConfig {
+                fee_percentage: None,
+                min_stake: Some(min_stake),
+                max_stake: Some(max_stake),
+                paused: None,
+            };
+            admin_ix(&pool, &authority, config)
+        };
+
+        // The stake program's minimum delegation wins over a lower configured minimum
+        send(&mut context, &[set_limits(1, 50 * LAMPORTS_PER_SOL)], &[]).await.unwrap();
+        let limits: StakeLimits = query(&mut context, stake_limits_ix(&pool)).await;
+        assert!(limits.minimum_delegation > 1);
+        assert_eq!(
+            limits,
+            StakeLimits {
+                min_stake: 1,
+                max_stake: 50 * LAMPORTS_PER_SOL,
+                minimum_delegation: limits.minimum_delegation,
+                effective_min_stake: limits.minimum_delegation,
+            }
+        );
+
+        let min_stake = limits.minimum_delegation + LAMPORTS_PER_SOL;
+        send(&mut context, &[set_limits(min_stake, 50 * LAMPORTS_PER_SOL)], &[]).await.unwrap();
+        let limits: StakeLimits = query(&mut context, stake_limits_ix(&pool)).await;
+        assert_eq!((limits.min_stake, limits.effective_min_stake), (min_stake, min_stake));
+
+        let mut wrong_program = stake_limits_ix(&pool);
+        wrong_program.accounts[1].pubkey = system_program::id();
+        assert_instruction_error(send(&mut context, &[wrong_program], &[]).await, InstructionError::IncorrectProgramId);
+        let mut not_a_pool = stake_limits_ix(&pool);
+        not_a_pool.accounts[0].pubkey = pool.addresses.mint;
+        assert_instruction_error(send(&mut context, &[not_a_pool], &[]).await, InstructionError::IllegalOwner);
+    }
+
+
+    #[tokio::test]
+    async fn unstake_rejects_an_undelegated_stake_account() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake_account = user_stake_account(&pool, &user.pubkey());
+        send(&mut context, &[stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT)], &[&user]).await.unwrap();
+        advance_epoch(&mut context).await;
+        let delegated = context.banks_client.get_account(stake_account).await.unwrap().unwrap();
+        let StakeStateV2::Stake(meta, _, _) = StakeStateV2::deserialize(&mut delegated.data.as_slice()).unwrap() else {
+            panic!("stake account is not delegated");
+        };
+        let unstake = client::unstake(&pool.program_id, &user.pubkey(), &pool.addresses.stake_pool, STAKE_AMOUNT);
+
+        // Left uninitialized or never delegated, e.g. by an earlier failure
+        for state in [StakeStateV2::Uninitialized, StakeStateV2::Initialized(meta)] {
+            let mut account = delegated.clone();
+            account.data.fill(0);
+            let encoded = state.try_to_vec().unwrap();
+            account.data[..encoded.len()].copy_from_slice(&encoded);
+            context.set_account(&stake_account, &account.into());
+            context.get_new_latest_blockhash().await.unwrap();
+            let result = send(&mut context, std::slice::from_ref(&unstake), &[&user]).await;
+            assert_pool_error(result, StakePoolError::WrongStakeState);
+        }
+
+        context.set_account(&stake_account, &delegated.into());
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[unstake], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, 0);
+    }
+
+
+    #[tokio::test]
+    async fn stake_consumes_exactly_the_named_accounts() {
+        let (mut context, pool, _) = setup(0).await;
+        let user = create_user(&mut context, &pool).await;
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        let expected = [
+            (StakeAccounts::User, user.pubkey()),
+            (StakeAccounts::StakePool, pool.addresses.stake_pool),
+            (StakeAccounts::UserTokenAccount, spl_associated_token_account::get_associated_token_address(&user.pubkey(), &pool.addresses.mint)),
+            (StakeAccounts::PoolMint, pool.addresses.mint),
+            (StakeAccounts::UserStakeAccount, user_stake_account(&pool, &user.pubkey())),
+            (StakeAccounts::TokenProgram, spl_token::id()),
+            (StakeAccounts::StakeProgram, solana_program::stake::program::id()),
+            (StakeAccounts::SystemProgram, system_program::id()),
+            (StakeAccounts::RentSysvar, sysvar::rent::id()),
+            (StakeAccounts::ClockSysvar, sysvar::clock::id()),
+            (StakeAccounts::StakeHistorySysvar, sysvar::stake_history::id()),
+            (StakeAccounts::StakeConfig, solana_program::stake::config::id()),
+            (StakeAccounts::ValidatorVote, pool.validator_vote),
+            (StakeAccounts::StakeAuthority, pool.addresses.stake_authority),
+        ];
+        assert_eq!(expected.len(), StakeAccounts::COUNT);
+        for (index, (account, key)) in expected.into_iter().enumerate() {
+            assert_eq!(account as usize, index);
+            assert_eq!(stake.accounts[index].pubkey, key, "{:?}", account);
+        }
+
+        // One account short of the named ones, Stake and StakeWithSetup both stop
+        let mut short = stake.clone();
+        short.accounts.truncate(StakeAccounts::COUNT - 1);
+        assert_instruction_error(send(&mut context, &[short.clone()], &[&user]).await, InstructionError::NotEnoughAccountKeys);
+        short.data = StakePoolInstruction::StakeWithSetup { amount: STAKE_AMOUNT }.try_to_vec().unwrap();
+        assert_instruction_error(send(&mut context, &[short], &[&user]).await, InstructionError::NotEnoughAccountKeys);
+
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+    }
+
+
+    #[tokio::test]
+    async fn underfunded_pool_refuses_deposits_until_topped_up() {
+        let (mut context, pool, _) = setup(0).await;
+        let payer = context.payer.pubkey();
+        let user = create_user(&mut context, &pool).await;
+        let stake = stake_ix(&pool, &user.pubkey(), STAKE_AMOUNT);
+        let rent_exempt_minimum = lamports(&mut context, &pool.addresses.stake_pool).await;
+
+        // Drain part of the pool's rent into the payer, keeping total lamports unchanged
+        let shortfall = rent_exempt_minimum / 2;
+        let mut pool_account = context.banks_client.get_account(pool.addresses.stake_pool).await.unwrap().unwrap();
+        pool_account.lamports -= shortfall;
+        context.set_account(&pool.addresses.stake_pool, &pool_account.into());
+        let mut payer_account = context.banks_client.get_account(payer).await.unwrap().unwrap();
+        payer_account.lamports += shortfall;
+        context.set_account(&payer, &payer_account.into());
+
+        let result = send(&mut context, std::slice::from_ref(&stake), &[&user]).await;
+        assert_pool_error(result, StakePoolError::PoolNotRentExempt);
+
+        let top_up = |target: Pubkey| {
+            pool_ix(
+                &pool,
+                StakePoolInstruction::TopUpPool,
+                vec![
+                    AccountMeta::new(payer, true),
+                    AccountMeta::new(target, false),
+                    AccountMeta::new_readonly(system_program::id(), false),
+                ],
+            )
+        };
+        let result = send(&mut context, &[top_up(pool.addresses.mint)], &[]).await;
+        assert_instruction_error(result, InstructionError::IllegalOwner);
+        send(&mut context, &[top_up(pool.addresses.stake_pool)], &[]).await.unwrap();
+        assert_eq!(lamports(&mut context, &pool.addresses.stake_pool).await, rent_exempt_minimum);
+
+        context.get_new_latest_blockhash().await.unwrap();
+        send(&mut context, &[stake], &[&user]).await.unwrap();
+        assert_eq!(token_balance(&mut context, &pool, &user.pubkey()).await, STAKE_AMOUNT);
+    }
+}
@@ -0,0 +1,88 @@
+//! Checked arithmetic for pool accounting.
+//!
+//! Every amount the program tracks (lamports, pool tokens) is a `u64`. These helpers
+//! fail with `MathOverflow` instead of wrapping, and with `CalculationFailure` on a
+//! division by zero, so handlers don't have to pick the error at each call site.
+
+use crate::error::StakePoolError;
+
+/// `a + b`
+pub fn add(a: u64, b: u64) -> Result<u64, StakePoolError> {
+    a.checked_add(b).ok_or(StakePoolError::MathOverflow)
+}
+
+/// `a - b`
+pub fn sub(a: u64, b: u64) -> Result<u64, StakePoolError> {
+    a.checked_sub(b).ok_or(StakePoolError::MathOverflow)
+}
+
+/// `a * b`
+#[allow(dead_code)] // Part of the full set; no handler needs it yet
+pub fn mul(a: u64, b: u64) -> Result<u64, StakePoolError> {
+    a.checked_mul(b).ok_or(StakePoolError::MathOverflow)
+}
+
+/// `a / b`, rounded down
+#[allow(dead_code)] // Part of the full set; no handler needs it yet
+pub fn div(a: u64, b: u64) -> Result<u64, StakePoolError> {
+    a.checked_div(b).ok_or(StakePoolError::CalculationFailure)
+}
+
+/// `a * b / c`, rounded down. The product is computed in `u128` so it cannot
+/// overflow; only a result that doesn't fit in a `u64` fails.
+pub fn mul_div(a: u64, b: u64, c: u64) -> Result<u64, StakePoolError> {
+    if c == 0 {
+        return Err(StakePoolError::CalculationFailure);
+    }
+    let result = (a as u128) * (b as u128) / (c as u128);
+    u64::try_from(result).map_err(|_| StakePoolError::MathOverflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_overflow() {
+        assert_eq!(add(u64::MAX - 1, 1).unwrap(), u64::MAX);
+        assert!(matches!(add(u64::MAX, 1), Err(StakePoolError::MathOverflow)));
+    }
+
+    #[test]
+    fn sub_underflow() {
+        assert_eq!(sub(5, 5).unwrap(), 0);
+        assert!(matches!(sub(0, 1), Err(StakePoolError::MathOverflow)));
+    }
+
+    #[test]
+    fn mul_overflow() {
+        assert_eq!(mul(u64::MAX, 1).unwrap(), u64::MAX);
+        assert_eq!(mul(u64::MAX, 0).unwrap(), 0);
+        assert!(matches!(mul(u64::MAX / 2 + 1, 2), Err(StakePoolError::MathOverflow)));
+    }
+
+    #[test]
+    fn div_by_zero() {
+        assert_eq!(div(7, 2).unwrap(), 3);
+        assert_eq!(div(0, 1).unwrap(), 0);
+        assert!(matches!(div(1, 0), Err(StakePoolError::CalculationFailure)));
+    }
+
+    #[test]
+    fn mul_div_wide_intermediate() {
+        // u64::MAX * u64::MAX overflows u64 but not the u128 intermediate
+        assert_eq!(mul_div(u64::MAX, u64::MAX, u64::MAX).unwrap(), u64::MAX);
+        assert_eq!(mul_div(10, 3, 4).unwrap(), 7);
+    }
+
+    #[test]
+    fn mul_div_result_overflow() {
+        assert!(matches!(mul_div(u64::MAX, 2, 1), Err(StakePoolError::MathOverflow)));
+    }
+
+    #[test]
+    fn mul_div_by_zero() {
+        assert!(matches!(mul_div(1, 1, 0), Err(StakePoolError::CalculationFailure)));
+        assert!(matches!(mul_div(0, 0, 0), Err(StakePoolError::CalculationFailure)));
+    }
+}
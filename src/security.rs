@@ -1,26 +1,32 @@
 use solana_program::{
     account_info::AccountInfo,
+    msg,
     program_error::ProgramError,
     pubkey::Pubkey,
-    clock::Clock,
-    sysvar::Sysvar,
 };
 use crate::{
     error::StakePoolError,
+    safe_math,
     state::{StakePool, ValidatorList},
 };
 
+/// Seconds that must pass between a stake and an unstake (2 days)
+const MINIMUM_COOLDOWN_PERIOD: i64 = 2 * 24 * 60 * 60;
+
 pub struct SecurityManager;
 
+#[allow(dead_code)] // Not every check has a caller yet
 impl SecurityManager {
     pub fn verify_admin(
         admin_info: &AccountInfo,
         stake_pool: &StakePool,
     ) -> Result<(), ProgramError> {
         if !admin_info.is_signer {
+            msg!("Authority signature missing");
             return Err(ProgramError::MissingRequiredSignature);
         }
         if stake_pool.authority != *admin_info.key {
+            msg!("Signer is not the pool authority");
             return Err(StakePoolError::InvalidAuthority.into());
         }
         Ok(())
@@ -54,6 +60,7 @@ impl SecurityManager {
 
     pub fn verify_not_paused(stake_pool: &StakePool) -> Result<(), ProgramError> {
         if stake_pool.paused {
+            msg!("Stake pool is paused");
             return Err(StakePoolError::PoolPaused.into());
         }
         Ok(())
@@ -72,29 +79,36 @@ impl SecurityManager {
         Ok(())
     }
 
+    /// Checks that delegating `amount` more to validator `validator_index` keeps it
+    /// within its share of the stake delegated through the list: at most
+    /// `max_validator_stake_bps` of the total after the deposit, or an even split
+    /// while the list is too short for that cap to be reachable. Topping up the
+    /// least-staked validator is always allowed, so a new list can fill up from zero.
     pub fn verify_validator_stake_limit(
         validator_list: &ValidatorList,
         validator_index: usize,
         amount: u64,
+        max_validator_stake_bps: u16,
     ) -> Result<(), ProgramError> {
-        const MAX_VALIDATOR_STAKE_PERCENTAGE: u64 = 10; // 10% max per validator
-        
-        let total_stake: u64 = validator_list.validators.iter()
+        let validator = validator_list.validators.get(validator_index)
+            .ok_or(StakePoolError::ValidatorNotInList)?;
+        let least_staked = validator_list.validators.iter()
             .map(|v| v.active_stake_lamports)
-            .sum();
-
-        let validator = &validator_list.validators[validator_index];
-        let new_validator_stake = validator.active_stake_lamports
-            .checked_add(amount)
-            .ok_or(StakePoolError::CalculationFailure)?;
+            .min()
+            .unwrap_or(0);
+        if validator.active_stake_lamports <= least_staked {
+            return Ok(());
+        }
 
-        let max_allowed = total_stake
-            .checked_mul(MAX_VALIDATOR_STAKE_PERCENTAGE)
-            .ok_or(StakePoolError::CalculationFailure)?
-            .checked_div(100)
-            .ok_or(StakePoolError::CalculationFailure)?;
+        let total_stake = safe_math::add(validator_list.total_active_stake()?, amount)?;
+        let new_validator_stake = safe_math::add(validator.active_stake_lamports, amount)?;
+        let even_split_bps = 10_000u64.div_ceil(validator_list.validators.len() as u64);
+        let max_bps = u64::from(max_validator_stake_bps).max(even_split_bps);
+        let max_allowed = safe_math::mul_div(total_stake, max_bps, 10_000)?;
 
         if new_validator_stake > max_allowed {
+            msg!("Validator {} would hold {} of {} lamports, above its {} bps limit",
+                 validator.vote_account, new_validator_stake, total_stake, max_bps);
             return Err(StakePoolError::ValidatorStakeLimitExceeded.into());
         }
 
@@ -105,8 +119,6 @@ impl SecurityManager {
         last_stake_timestamp: i64,
         current_time: i64,
     ) -> Result<(), ProgramError> {
-        const MINIMUM_COOLDOWN_PERIOD: i64 = 2 * 24 * 60 * 60; // 2 days in seconds
-        
         if current_time - last_stake_timestamp < MINIMUM_COOLDOWN_PERIOD {
             return Err(StakePoolError::UnstakeCooldownNotMet.into());
         }
@@ -136,8 +148,8 @@ impl SecurityManager {
         program_id: &Pubkey,
         seeds: &[&[u8]],
     ) -> Result<(Pubkey, u8), ProgramError> {
-        Pubkey::find_program_address(seeds, program_id)
-            .map_err(|_| StakePoolError::InvalidProgramAddress.into())
+        Pubkey::try_find_program_address(seeds, program_id)
+            .ok_or_else(|| StakePoolError::InvalidProgramAddress.into())
     }
 
     pub fn verify_program_derived_address(
@@ -146,8 +158,10 @@ impl SecurityManager {
         seeds: &[&[u8]],
         bump_seed: u8,
     ) -> Result<(), ProgramError> {
+        let bump = [bump_seed];
+        let seeds_with_bump = [seeds, &[&bump[..]]].concat();
         let expected_address = Pubkey::create_program_address(
-            &[&seeds[..], &[bump_seed]].concat(),
+            &seeds_with_bump,
             program_id,
         ).map_err(|_| StakePoolError::InvalidProgramAddress)?;
 
@@ -156,4 +170,19 @@ impl SecurityManager {
         }
         Ok(())
     }
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unstake_cooldown_boundary() {
+        let staked_at = 1_700_000_000;
+        assert!(SecurityManager::verify_unstake_cooldown(staked_at, staked_at + MINIMUM_COOLDOWN_PERIOD).is_ok());
+        assert_eq!(
+            SecurityManager::verify_unstake_cooldown(staked_at, staked_at + MINIMUM_COOLDOWN_PERIOD - 1),
+            Err(StakePoolError::UnstakeCooldownNotMet.into()),
+        );
+    }
+}
@@ -1,10 +1,104 @@
 use borsh::{BorshDeserialize, BorshSerialize};
+use crate::{error::StakePoolError, safe_math};
 use solana_program::{
+    hash::hashv,
+    msg,
+    program_error::ProgramError,
     program_pack::{IsInitialized, Sealed},
     pubkey::Pubkey,
+    stake::state::StakeStateV2,
 };
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+/// Seed for the stake pool PDA, combined with the authority pubkey.
+/// Change this when forking so your pool doesn't collide with others.
+pub const POOL_SEED: &[u8] = b"obelisk_pool_04";
+
+/// Seed for the pool token mint PDA, combined with the stake pool pubkey
+pub const MINT_SEED: &[u8] = b"mint";
+
+/// Seed for the stake authority PDA, combined with the stake pool pubkey
+pub const STAKE_AUTHORITY_SEED: &[u8] = b"stake_authority";
+
+/// Seed for the withdraw authority PDA, combined with the stake pool pubkey
+pub const WITHDRAW_AUTHORITY_SEED: &[u8] = b"withdraw_authority";
+
+/// Seed for a user's stake account PDA, combined with the pool, user and stake authority
+pub const STAKE_ACCOUNT_SEED: &[u8] = b"stake_account";
+
+/// Data length of a user's stake account PDA, used when creating it and checked when loading it
+pub const STAKE_ACCOUNT_SIZE: usize = std::mem::size_of::<StakeStateV2>();
+
+/// Seed for the pool history PDA, combined with the stake pool pubkey
+pub const HISTORY_SEED: &[u8] = b"history";
+
+/// Seed for a pool snapshot PDA, combined with the stake pool pubkey and the snapshot index (u64 LE)
+pub const SNAPSHOT_SEED: &[u8] = b"snapshot";
+
+/// Seed for a split stake account PDA created by a partial `Unstake`, combined with the
+/// stake pool pubkey, the user pubkey and the unstake epoch (u64 LE)
+pub const SPLIT_STAKE_SEED: &[u8] = b"split_stake";
+
+/// Seed for the stake record PDA of a pool stake account, combined with the stake account pubkey
+pub const STAKE_RECORD_SEED: &[u8] = b"stake_record";
+
+/// Seed for the validator list PDA, combined with the stake pool pubkey
+pub const VALIDATOR_LIST_SEED: &[u8] = b"validator_list";
+
+/// Largest validator list `AddValidator` can create or `GrowValidatorList` grow to
+/// (bounded by the 10KiB CPI allocation limit)
+pub const MAX_VALIDATORS: u32 = 248;
+
+/// Share of the list's stake (bps) a validator may hold unless the pool sets its own cap
+pub const DEFAULT_VALIDATOR_STAKE_BPS: u16 = 1_000;
+
+/// Lowest per-validator cap `UpdateValidatorCap` accepts, in bps
+pub const MIN_VALIDATOR_STAKE_BPS: u16 = 100;
+
+/// Largest dust threshold `SweepDust` accepts, in lamports / pool token base units
+pub const MAX_DUST_THRESHOLD: u64 = 1_000_000;
+
+/// Largest history a `CreateHistory` can allocate (bounded by the 10KiB CPI allocation limit)
+pub const MAX_HISTORY_ENTRIES: u32 = 200;
+
+/// Pool tokens a `HistoryEntry.price` is quoted for
+pub const PRICE_SCALE: u64 = 1_000_000_000;
+
+/// Largest `total_shares` correction `ReconcileShares` applies, in basis points of the
+/// larger of `total_shares` and the mint supply; bigger gaps need manual review
+pub const MAX_SHARES_DIVERGENCE_BPS: u64 = 100;
+
+/// Length of the type tag at the start of every account this program owns
+pub const ACCOUNT_DISCRIMINATOR_LEN: usize = 8;
+
+/// Program release, reported by `GetProgramInfo` (taken from Cargo.toml)
+pub const PROGRAM_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// `StakePool.version` written by this program
+pub const POOL_STATE_VERSION: u8 = 1;
+
+/// `ProgramInfo.features` bit: built with the `debug-logging` feature
+pub const FEATURE_DEBUG_LOGGING: u64 = 1 << 0;
+
+/// `ProgramInfo.features` bit: built with `WarpPoolEpoch` (the `test-utils` feature or
+/// this crate's unit tests)
+pub const FEATURE_TEST_UTILS: u64 = 1 << 1;
+
+/// `ProgramInfo.pool_flags` bit: the pool is paused
+pub const POOL_FLAG_PAUSED: u64 = 1 << 0;
+
+/// `ProgramInfo.pool_flags` bit: a per-epoch unstake limit is set
+pub const POOL_FLAG_UNSTAKE_LIMIT: u64 = 1 << 1;
+
+/// `ProgramInfo.pool_flags` bit: deposits are held after a commission increase
+pub const POOL_FLAG_COMMISSION_HOLD: u64 = 1 << 2;
+
+/// `ProgramInfo.pool_flags` bit: deposits must mint to the user's associated token account
+pub const POOL_FLAG_REQUIRE_ATA: u64 = 1 << 3;
+
+/// `ProgramInfo.pool_flags` bit: stake is spread over the validators in the validator list
+pub const POOL_FLAG_VALIDATOR_LIST: u64 = 1 << 4;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
 pub struct StakePool {
     /// Pool version for upgrade compatibility
     pub version: u8,
@@ -63,37 +157,162 @@ pub struct StakePool {
     /// Bump seed for the withdraw authority PDA
     pub withdraw_authority_bump_seed: u8,
 
+    /// Maximum SOL value that may be unstaked per epoch (0 = unlimited)
+    pub max_unstake_per_epoch: u64,
+
+    /// SOL value unstaked so far in `unstake_tracking_epoch`
+    pub unstaked_this_epoch: u64,
+
+    /// Epoch `unstaked_this_epoch` refers to
+    pub unstake_tracking_epoch: u64,
+
+    /// Validator commission (percent) seen at the last check
+    pub last_known_commission: u8,
+
+    /// Commission increase (percentage points) that raises an alert, 0 = alerts off
+    pub commission_alert_threshold: u8,
+
+    /// Whether an alerting commission increase holds new deposits
+    pub pause_on_commission_hike: bool,
+
+    /// New deposits are held until the authority acknowledges a commission increase
+    pub commission_hold: bool,
+
+    /// Only mint to the user's canonical associated token account
+    pub require_ata: bool,
+
+    /// Stake (lamports) deactivated by `Unstake` and not yet withdrawn
+    pub total_pending_withdrawal: u64,
+
+    /// A validator list exists and `Stake`/`Unstake` must keep it up to date
+    pub has_validator_list: bool,
+
+    /// Share of the list's stake (bps) one validator may hold; 0 means
+    /// DEFAULT_VALIDATOR_STAKE_BPS (pools created before the cap was configurable)
+    pub max_validator_stake_bps: u16,
+
+    /// Validators must be live (recently voting) when the pool is pointed at them
+    pub require_voting_validator: bool,
+
+    /// `Stake`/`Unstake` advance a stale pool epoch themselves, as `ClaimRewards` would
+    pub auto_claim: bool,
+
     /// Reserved space for future features (NGO donations, service payments)
-    pub reserved: [u8; 62], // Reduced size to accommodate bumps
-}
-
-impl Default for StakePool {
-    fn default() -> Self {
-        StakePool {
-            version: 0, // Usually 0 indicates uninitialized, but using for default
-            authority: Pubkey::default(),
-            stake_authority: Pubkey::default(),
-            withdraw_authority: Pubkey::default(),
-            name: String::new(), // Default empty string
-            fee_percentage: 0,
-            total_staked: 0,
-            total_shares: 0,
-            mint: Pubkey::default(),
-            reserve: Pubkey::default(),
-            helius_validator_vote: Pubkey::default(),
-            manager_fee_account: Pubkey::default(),
-            treasury_fee_account: Pubkey::default(),
-            paused: false,
-            last_update_epoch: 0,
-            min_stake: 0,
-            max_stake: 0,
-            stake_authority_bump_seed: 0,
-            withdraw_authority_bump_seed: 0,
-            reserved: [0u8; 62], // Default zeroed array
+    pub reserved: [u8; 20], // Reduced size to accommodate bumps, unstake limit, commission tracking, ATA flag, pending withdrawals, validator list flag, validator cap, vote check and auto-claim
+}
+
+impl StakePool {
+    /// Per-validator stake cap in effect, in bps
+    pub fn validator_stake_cap_bps(&self) -> u16 {
+        if self.max_validator_stake_bps == 0 {
+            DEFAULT_VALIDATOR_STAKE_BPS
+        } else {
+            self.max_validator_stake_bps
+        }
+    }
+
+    /// Enabled options of this pool as `POOL_FLAG_*` bits
+    pub fn flags(&self) -> u64 {
+        let mut flags = 0;
+        if self.paused {
+            flags |= POOL_FLAG_PAUSED;
+        }
+        if self.max_unstake_per_epoch != 0 {
+            flags |= POOL_FLAG_UNSTAKE_LIMIT;
+        }
+        if self.commission_hold {
+            flags |= POOL_FLAG_COMMISSION_HOLD;
+        }
+        if self.require_ata {
+            flags |= POOL_FLAG_REQUIRE_ATA;
+        }
+        if self.has_validator_list {
+            flags |= POOL_FLAG_VALIDATOR_LIST;
+        }
+        flags
+    }
+
+    /// Whether the once-per-epoch update still has to run for `current_epoch`.
+    ///
+    /// The update moves `last_update_epoch` straight to the current epoch in the same
+    /// write as any other epoch bookkeeping, so an epoch can never be partially
+    /// processed and then processed again.
+    pub fn needs_epoch_update(&self, current_epoch: u64) -> bool {
+        self.last_update_epoch < current_epoch
+    }
+
+    /// SOL value (lamports) of `pool_tokens` at the current pool ratio; 0 for an empty pool
+    pub fn tokens_to_lamports(&self, pool_tokens: u64) -> Result<u64, StakePoolError> {
+        if self.total_shares == 0 {
+            return Ok(0);
+        }
+        safe_math::mul_div(pool_tokens, self.total_staked, self.total_shares)
+    }
+
+    /// Lamports `PRICE_SCALE` pool tokens are worth; 1:1 while the pool is empty
+    pub fn price(&self) -> Result<u64, StakePoolError> {
+        if self.total_shares == 0 || self.total_staked == 0 {
+            return Ok(PRICE_SCALE);
+        }
+        safe_math::mul_div(PRICE_SCALE, self.total_staked, self.total_shares)
+    }
+
+    /// Pool tokens minted for a deposit of `lamports`; 1:1 while the pool is empty
+    pub fn lamports_to_tokens(&self, lamports: u64) -> Result<u64, StakePoolError> {
+        if self.total_shares == 0 || self.total_staked == 0 {
+            return Ok(lamports);
+        }
+        safe_math::mul_div(lamports, self.total_shares, self.total_staked)
+    }
+
+    /// SOL value already unstaked in `current_epoch`; the counter resets when the epoch rolls over
+    pub fn unstaked_in_epoch(&self, current_epoch: u64) -> u64 {
+        if self.unstake_tracking_epoch == current_epoch {
+            self.unstaked_this_epoch
+        } else {
+            0
         }
     }
 }
 
+/// An account type stored as an 8-byte type tag followed by its borsh encoding, so
+/// RPC clients can select one type with a `memcmp` filter at offset 0.
+pub trait DiscriminatedAccount: BorshSerialize + BorshDeserialize {
+    /// Type tag written at the start of the account
+    const DISCRIMINATOR: [u8; ACCOUNT_DISCRIMINATOR_LEN];
+
+    /// Decodes account data, failing if it doesn't start with this type's tag
+    fn unpack_account(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() < ACCOUNT_DISCRIMINATOR_LEN || data[..ACCOUNT_DISCRIMINATOR_LEN] != Self::DISCRIMINATOR {
+            msg!("Account data does not start with the {:?} discriminator",
+                 std::str::from_utf8(&Self::DISCRIMINATOR).unwrap_or_default());
+            return Err(StakePoolError::InvalidAccountDiscriminator.into());
+        }
+        Ok(Self::try_from_slice(&data[ACCOUNT_DISCRIMINATOR_LEN..])?)
+    }
+
+    /// Writes the tag and encoding into existing account data
+    fn pack_account(&self, data: &mut [u8]) -> Result<(), ProgramError> {
+        if data.len() < ACCOUNT_DISCRIMINATOR_LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        data[..ACCOUNT_DISCRIMINATOR_LEN].copy_from_slice(&Self::DISCRIMINATOR);
+        self.serialize(&mut &mut data[ACCOUNT_DISCRIMINATOR_LEN..])?;
+        Ok(())
+    }
+
+    /// Full account contents (tag + encoding), e.g. to size a new account
+    fn to_account_data(&self) -> Result<Vec<u8>, ProgramError> {
+        let mut data = Self::DISCRIMINATOR.to_vec();
+        self.serialize(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl DiscriminatedAccount for StakePool {
+    const DISCRIMINATOR: [u8; ACCOUNT_DISCRIMINATOR_LEN] = *b"obe_pool";
+}
+
 impl Sealed for StakePool {}
 
 impl IsInitialized for StakePool {
@@ -102,6 +321,539 @@ impl IsInitialized for StakePool {
     }
 }
 
+/// Result of `PreviewWithdraw`, returned via `set_return_data`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct WithdrawPreview {
+    /// Lamports a `WithdrawStake` would transfer to the user
+    pub withdrawable_lamports: u64,
+
+    /// Epoch the stake account was deactivated in (u64::MAX while still active)
+    pub deactivation_epoch: u64,
+
+    /// Whether the stake account is deactivated and its cooldown has passed
+    pub ready: bool,
+}
+
+/// Result of `ClaimRewards`, returned via `set_return_data`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct ClaimStatus {
+    /// True when the epoch had already been processed and the call did nothing
+    pub already_processed: bool,
+
+    /// Epoch the call observed
+    pub current_epoch: u64,
+}
+
+/// Result of `ClaimAndQuery`, returned via `set_return_data`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct ClaimQueryResult {
+    /// True when the epoch had already been processed and no update was made
+    pub already_processed: bool,
+
+    /// Epoch the call observed
+    pub current_epoch: u64,
+
+    /// Pool tokens held in the user's token account
+    pub pool_tokens: u64,
+
+    /// SOL value (lamports) of those tokens at the current pool ratio
+    pub sol_value: u64,
+}
+
+/// Result of `GetProgramInfo`, returned via `set_return_data`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct ProgramInfo {
+    /// Program release (`PROGRAM_VERSION`)
+    pub program_version: String,
+
+    /// `StakePool.version` this program writes (`POOL_STATE_VERSION`)
+    pub pool_state_version: u8,
+
+    /// Compiled features (`FEATURE_*` bits)
+    pub features: u64,
+
+    /// Enabled options of the queried pool (`POOL_FLAG_*` bits), 0 when no pool was passed
+    pub pool_flags: u64,
+}
+
+/// Result of `GetPoolInfo`, returned via `set_return_data`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct PoolInfo {
+    /// SOL (lamports) backing outstanding pool tokens
+    pub total_staked: u64,
+
+    /// Outstanding pool tokens
+    pub total_shares: u64,
+
+    /// Stake (lamports) in cooldown after `Unstake`, not yet withdrawn
+    pub total_pending_withdrawal: u64,
+}
+
+/// Result of `VerifyBacking`, returned via `set_return_data`. All amounts in lamports.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct BackingReport {
+    /// Pool stake accounts counted
+    pub stake_accounts: u32,
+
+    /// Active delegated stake of the counted accounts
+    pub delegated_stake: u64,
+
+    /// Stake of the counted accounts that is deactivating or deactivated, not yet withdrawn
+    pub pending_stake: u64,
+
+    /// `delegated_stake + pending_stake`
+    pub total_backing: u64,
+
+    /// Supply of the pool mint
+    pub mint_supply: u64,
+
+    /// SOL value of `mint_supply` at the pool price
+    pub supply_value: u64,
+
+    /// What the stake must cover: `supply_value` plus the pool's `total_pending_withdrawal`
+    pub obligations: u64,
+
+    /// `total_backing / obligations` in basis points, `u64::MAX` when nothing is owed
+    pub backing_ratio_bps: u64,
+
+    /// `total_backing` covers `obligations`
+    pub fully_backed: bool,
+
+    /// `obligations - total_backing`, 0 when fully backed
+    pub shortfall: u64,
+
+    /// The counted stake is below what the pool has booked (`total_staked` plus
+    /// `total_pending_withdrawal`), so some pool stake accounts may not have been passed
+    /// and a shortfall need not be a real deficit. A fully backed report holds either way.
+    pub partial: bool,
+}
+
+/// Result of `GetStakeLimits`, returned via `set_return_data`. All amounts in lamports.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct StakeLimits {
+    /// Configured minimum stake (`StakePool.min_stake`)
+    pub min_stake: u64,
+
+    /// Configured maximum stake per deposit (`StakePool.max_stake`)
+    pub max_stake: u64,
+
+    /// Stake program's current minimum delegation
+    pub minimum_delegation: u64,
+
+    /// Smallest deposit `Stake` accepts: the larger of `min_stake` and `minimum_delegation`
+    pub effective_min_stake: u64,
+}
+
+/// One entry of `GetUserValues`, returned in a `ReturnPage`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq)]
+pub struct UserValue {
+    /// Token account queried
+    pub token_account: Pubkey,
+
+    /// False if the account is not a pool token account; the amounts are then 0
+    pub valid: bool,
+
+    /// Pool tokens held
+    pub pool_tokens: u64,
+
+    /// SOL value (lamports) at the current pool ratio
+    pub sol_value: u64,
+}
+
+/// Result of `ValidateStake`, returned via `set_return_data`. Each flag is one
+/// precondition of `Stake`; `passed` is set only when all of them hold.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct StakeValidation {
+    /// Every check below passed; a `Stake` with the same accounts and amount should succeed
+    pub passed: bool,
+
+    /// The user account (and the rent payer, if one was passed) signed
+    pub user_signed: bool,
+
+    /// The pool is not paused
+    pub not_paused: bool,
+
+    /// Deposits are not held after a validator commission increase
+    pub no_commission_hold: bool,
+
+    /// The mint is the pool's mint, controlled by the stake authority and not freezable
+    pub mint_valid: bool,
+
+    /// The token account is a pool token account (and the user's ATA if the pool requires it)
+    pub token_account_valid: bool,
+
+    /// `amount` is at least `min_stake`
+    pub above_minimum: bool,
+
+    /// `amount` is at most `max_stake`
+    pub below_maximum: bool,
+
+    /// The vote account is the pool's validator (or a listed one)
+    pub validator_matches: bool,
+
+    /// The deposit keeps the validator within its share of the validator list's stake
+    pub within_validator_limit: bool,
+
+    /// The stake authority is the pool's stake authority PDA
+    pub stake_authority_matches: bool,
+
+    /// The stake account is the user's stake account PDA
+    pub stake_account_matches: bool,
+
+    /// The stake account is either not yet created or an initialized, undelegated stake account
+    pub stake_account_usable: bool,
+
+    /// The user holds `required_lamports`, and a separate rent payer holds the stake account's rent
+    pub sufficient_balance: bool,
+
+    /// `amount` mints a nonzero number of pool tokens
+    pub mints_tokens: bool,
+
+    /// Effective minimum stake, in lamports
+    pub min_stake: u64,
+
+    /// Maximum stake, in lamports
+    pub max_stake: u64,
+
+    /// Lamports the user needs: `amount`, plus rent for a stake account not yet
+    /// created unless a separate rent payer covers it
+    pub required_lamports: u64,
+
+    /// Pool tokens the deposit would mint
+    pub pool_tokens: u64,
+}
+
+/// Snapshot of the pool taken each time the epoch is advanced
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct HistoryEntry {
+    /// Epoch the snapshot was taken in
+    pub epoch: u64,
+
+    /// Lamports `PRICE_SCALE` pool tokens were worth (`StakePool::price`)
+    pub price: u64,
+
+    /// Total SOL staked at that epoch
+    pub total_staked: u64,
+
+    /// Total shares issued at that epoch
+    pub total_shares: u64,
+
+    /// Rewards (lamports) booked into `total_staked` during the epoch
+    pub rewards_booked: u64,
+
+    /// Fees (pool tokens) minted to the fee accounts during the epoch
+    pub fees_taken: u64,
+}
+
+impl HistoryEntry {
+    /// Bytes of one encoded entry
+    pub const LEN: usize = 6 * 8;
+}
+
+/// `HistoryEntry` as written before it recorded price, rewards and fees. Untagged
+/// history accounts awaiting `MigrateAccountLayout` still hold these.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LegacyHistoryEntry {
+    pub epoch: u64,
+    pub total_staked: u64,
+    pub total_shares: u64,
+}
+
+/// `PoolHistory` in the untagged layout, with `LegacyHistoryEntry` entries
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct LegacyPoolHistory {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub head: u32,
+    pub len: u32,
+    pub entries: Vec<LegacyHistoryEntry>,
+}
+
+impl LegacyPoolHistory {
+    /// Converts to the current layout, deriving each entry's price from its totals.
+    /// Rewards and fees were not recorded, so they read as 0.
+    pub fn upgrade(self) -> Result<PoolHistory, StakePoolError> {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let totals = StakePool {
+                    total_staked: entry.total_staked,
+                    total_shares: entry.total_shares,
+                    ..StakePool::default()
+                };
+                Ok(HistoryEntry {
+                    epoch: entry.epoch,
+                    price: totals.price()?,
+                    total_staked: entry.total_staked,
+                    total_shares: entry.total_shares,
+                    rewards_booked: 0,
+                    fees_taken: 0,
+                })
+            })
+            .collect::<Result<Vec<_>, StakePoolError>>()?;
+        Ok(PoolHistory {
+            version: self.version,
+            stake_pool: self.stake_pool,
+            head: self.head,
+            len: self.len,
+            entries,
+        })
+    }
+}
+
+/// Fixed-size ring buffer of per-epoch pool snapshots
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct PoolHistory {
+    /// History version, 0 while uninitialized
+    pub version: u8,
+
+    /// Stake pool this history belongs to
+    pub stake_pool: Pubkey,
+
+    /// Slot the next snapshot is written to
+    pub head: u32,
+
+    /// Number of slots holding a snapshot
+    pub len: u32,
+
+    /// Snapshot slots; always `capacity` long so the account size never changes
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl PoolHistory {
+    /// Bytes of account data for a history of `capacity` snapshots
+    pub fn account_size(capacity: u32) -> usize {
+        ACCOUNT_DISCRIMINATOR_LEN + 1 + 32 + 4 + 4 + 4 + HistoryEntry::LEN * capacity as usize
+    }
+
+    /// Creates an empty history holding up to `capacity` snapshots
+    pub fn new(stake_pool: Pubkey, capacity: u32) -> Self {
+        PoolHistory {
+            version: 1,
+            stake_pool,
+            head: 0,
+            len: 0,
+            entries: vec![HistoryEntry::default(); capacity as usize],
+        }
+    }
+
+    /// Records a snapshot, overwriting the oldest one once the buffer is full
+    pub fn push(&mut self, entry: HistoryEntry) {
+        let capacity = self.entries.len() as u32;
+        if capacity == 0 {
+            return;
+        }
+        self.entries[self.head as usize] = entry;
+        self.head = (self.head + 1) % capacity;
+        self.len = (self.len + 1).min(capacity);
+    }
+
+    /// Returns the recorded snapshots from oldest to newest
+    pub fn ordered(&self) -> Vec<HistoryEntry> {
+        let capacity = self.entries.len() as u32;
+        let start = if self.len < capacity { 0 } else { self.head };
+        (0..self.len)
+            .map(|i| self.entries[((start + i) % capacity) as usize])
+            .collect()
+    }
+}
+
+impl DiscriminatedAccount for PoolHistory {
+    const DISCRIMINATOR: [u8; ACCOUNT_DISCRIMINATOR_LEN] = *b"obe_hist";
+}
+
+impl Sealed for PoolHistory {}
+
+impl IsInitialized for PoolHistory {
+    fn is_initialized(&self) -> bool {
+        self.version > 0
+    }
+}
+
+/// Write-once record of the pool's key figures, linked to the previous snapshot by hash
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq)]
+pub struct PoolSnapshot {
+    /// Snapshot version, 0 while uninitialized
+    pub version: u8,
+
+    /// Stake pool the snapshot was taken of
+    pub stake_pool: Pubkey,
+
+    /// Position in the pool's snapshot chain, starting at 0
+    pub index: u64,
+
+    /// Slot the snapshot was taken in
+    pub slot: u64,
+
+    /// Cluster time the snapshot was taken at
+    pub unix_timestamp: i64,
+
+    /// Epoch the snapshot was taken in
+    pub epoch: u64,
+
+    /// `StakePool.total_staked`
+    pub total_staked: u64,
+
+    /// `StakePool.total_shares`
+    pub total_shares: u64,
+
+    /// Supply of the pool mint
+    pub mint_supply: u64,
+
+    /// `StakePool.total_pending_withdrawal`
+    pub total_pending_withdrawal: u64,
+
+    /// `hash` of the previous snapshot, zero for the first one
+    pub prev_hash: [u8; 32],
+
+    /// Hash of this snapshot's other fields, including `prev_hash`
+    pub hash: [u8; 32],
+}
+
+impl PoolSnapshot {
+    /// Hashes the discriminator and every field but `hash`, which is zeroed while hashing
+    pub fn compute_hash(&self) -> Result<[u8; 32], ProgramError> {
+        let mut unhashed = self.clone();
+        unhashed.hash = [0u8; 32];
+        Ok(hashv(&[&Self::DISCRIMINATOR, &unhashed.try_to_vec()?]).to_bytes())
+    }
+}
+
+impl DiscriminatedAccount for PoolSnapshot {
+    const DISCRIMINATOR: [u8; ACCOUNT_DISCRIMINATOR_LEN] = *b"obe_snap";
+}
+
+impl Sealed for PoolSnapshot {}
+
+impl IsInitialized for PoolSnapshot {
+    fn is_initialized(&self) -> bool {
+        self.version > 0
+    }
+}
+
+/// Whether a validator in the list can receive new stake
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ValidatorStatus {
+    /// Accepts new delegations
+    Active,
+}
+
+/// A validator the pool delegates to
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ValidatorEntry {
+    /// Vote account stake is delegated to
+    pub vote_account: Pubkey,
+
+    /// Stake (lamports) users have delegated to this validator through the pool
+    pub active_stake_lamports: u64,
+
+    /// Whether the validator accepts new stake
+    pub status: ValidatorStatus,
+}
+
+/// Validators the pool spreads user stake over
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct ValidatorList {
+    /// List version, 0 while uninitialized
+    pub version: u8,
+
+    /// Stake pool this list belongs to
+    pub stake_pool: Pubkey,
+
+    /// Number of entries the account has room for
+    pub max_validators: u32,
+
+    /// Validators in the order they were added
+    pub validators: Vec<ValidatorEntry>,
+}
+
+impl ValidatorList {
+    /// Bytes of account data needed for a list of up to `max_validators` entries
+    pub fn account_size(max_validators: u32) -> usize {
+        const ENTRY_SIZE: usize = 32 + 8 + 1;
+        ACCOUNT_DISCRIMINATOR_LEN + 1 + 32 + 4 + 4 + ENTRY_SIZE * max_validators as usize
+    }
+
+    /// Position of `vote_account` in the list
+    pub fn find(&self, vote_account: &Pubkey) -> Option<usize> {
+        self.validators.iter().position(|v| v.vote_account == *vote_account)
+    }
+
+    /// Stake delegated through the pool across all validators
+    pub fn total_active_stake(&self) -> Result<u64, StakePoolError> {
+        self.validators
+            .iter()
+            .try_fold(0u64, |total, v| safe_math::add(total, v.active_stake_lamports))
+    }
+}
+
+impl DiscriminatedAccount for ValidatorList {
+    const DISCRIMINATOR: [u8; ACCOUNT_DISCRIMINATOR_LEN] = *b"obe_vals";
+
+    /// The account is allocated for `max_validators` entries up front, so the encoding
+    /// is followed by unused space until the list is full
+    fn unpack_account(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() < ACCOUNT_DISCRIMINATOR_LEN || data[..ACCOUNT_DISCRIMINATOR_LEN] != Self::DISCRIMINATOR {
+            msg!("Account data does not start with the \"obe_vals\" discriminator");
+            return Err(StakePoolError::InvalidAccountDiscriminator.into());
+        }
+        Ok(Self::deserialize(&mut &data[ACCOUNT_DISCRIMINATOR_LEN..])?)
+    }
+}
+
+impl Sealed for ValidatorList {}
+
+impl IsInitialized for ValidatorList {
+    fn is_initialized(&self) -> bool {
+        self.version > 0
+    }
+}
+
+/// Pool tokens minted against one pool stake account, so `Unstake` can tell a burn of
+/// the user's whole share (rewards included) from a partial one, and the stake it
+/// deactivated there. Split stake accounts get a record of their own.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq)]
+pub struct StakeRecord {
+    /// Record version, 0 while uninitialized
+    pub version: u8,
+
+    /// Stake pool the stake account belongs to
+    pub stake_pool: Pubkey,
+
+    /// Stake account this record tracks
+    pub stake_account: Pubkey,
+
+    /// Pool tokens minted against the stake account and not yet burned
+    pub pool_tokens: u64,
+
+    /// Stake (lamports) `Unstake` deactivated in the account and added to
+    /// `StakePool.total_pending_withdrawal`; `WithdrawStake` takes exactly this back off
+    pub pending_withdrawal: u64,
+}
+
+impl DiscriminatedAccount for StakeRecord {
+    const DISCRIMINATOR: [u8; ACCOUNT_DISCRIMINATOR_LEN] = *b"obe_srec";
+}
+
+impl Sealed for StakeRecord {}
+
+impl IsInitialized for StakeRecord {
+    fn is_initialized(&self) -> bool {
+        self.version > 0
+    }
+}
+
+/// One page of a list returned via `set_return_data`
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct ReturnPage<T> {
+    /// Items in this page
+    pub items: Vec<T>,
+
+    /// Whether more items follow; request the next page at `offset + items.len()`
+    pub has_more: bool,
+}
+
 /* // Unused struct
 #[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
 pub struct UnstakeInfo {
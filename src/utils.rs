@@ -1,13 +1,28 @@
 use solana_program::{
     account_info::AccountInfo,
     entrypoint::ProgramResult,
-    program::{invoke, invoke_signed},
+    msg,
+    program::{invoke, invoke_signed, set_return_data, MAX_RETURN_DATA},
     program_error::ProgramError,
+    program_option::COption,
+    program_pack::Pack,
     pubkey::Pubkey,
     system_instruction,
     sysvar::{rent::Rent, Sysvar},
 };
+use borsh::BorshSerialize;
+use crate::{
+    error::StakePoolError,
+    state::{ReturnPage, StakePool, STAKE_AUTHORITY_SEED, WITHDRAW_AUTHORITY_SEED},
+};
 
+/// Creates a PDA owned by `program_id` with `size` bytes of data.
+///
+/// An empty PDA is created with a single `create_account` CPI. If the address was
+/// already pre-funded (anyone can transfer lamports to it, which makes
+/// `create_account` fail), it falls back to topping up rent and issuing separate
+/// `allocate` and `assign` CPIs. Every call is a direct CPI into the system
+/// program, so this never adds invoke depth beyond one level below the caller.
 pub fn create_or_allocate_account_raw<'a>(
     program_id: &Pubkey,
     new_account_info: &AccountInfo<'a>,
@@ -20,6 +35,24 @@ pub fn create_or_allocate_account_raw<'a>(
     let rent = &Rent::from_account_info(rent_sysvar_info)?;
     let required_lamports = rent.minimum_balance(size);
 
+    if new_account_info.lamports() == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(
+                payer_info.key,
+                new_account_info.key,
+                required_lamports,
+                size as u64,
+                program_id,
+            ),
+            &[
+                payer_info.clone(),
+                new_account_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        ).map_err(cpi_step("create_account"));
+    }
+
     if new_account_info.lamports() < required_lamports {
         let lamports_diff = required_lamports.saturating_sub(new_account_info.lamports());
         invoke(
@@ -29,24 +62,49 @@ pub fn create_or_allocate_account_raw<'a>(
                 new_account_info.clone(),
                 system_program_info.clone(),
             ],
-        )?;
+        ).map_err(cpi_step("transfer"))?;
     }
 
     invoke_signed(
         &system_instruction::allocate(new_account_info.key, size as u64),
         &[new_account_info.clone(), system_program_info.clone()],
         &[signer_seeds],
-    )?;
+    ).map_err(cpi_step("allocate"))?;
 
     invoke_signed(
         &system_instruction::assign(new_account_info.key, program_id),
         &[new_account_info.clone(), system_program_info.clone()],
         &[signer_seeds],
-    )?;
+    ).map_err(cpi_step("assign"))?;
+
+    Ok(())
+}
 
+/// Closes an account owned by this program, moving all of its lamports to
+/// `destination_info` and zeroing its data. The runtime purges the empty account at
+/// the end of the transaction.
+pub fn close_program_account(account_info: &AccountInfo, destination_info: &AccountInfo) -> ProgramResult {
+    let lamports = account_info.lamports();
+    let destination_lamports = destination_info
+        .lamports()
+        .checked_add(lamports)
+        .ok_or(StakePoolError::MathOverflow)?;
+    **destination_info.try_borrow_mut_lamports()? = destination_lamports;
+    **account_info.try_borrow_mut_lamports()? = 0;
+    account_info.try_borrow_mut_data()?.fill(0);
     Ok(())
 }
 
+/// Tags a failed CPI with the step that issued it, for use as
+/// `invoke(..).map_err(cpi_step("burn"))?`. The error itself is passed through
+/// unchanged; only a log line naming the step is added.
+pub fn cpi_step(step: &'static str) -> impl Fn(ProgramError) -> ProgramError {
+    move |err| {
+        msg!("{} CPI failed: {}", step, err);
+        err
+    }
+}
+
 pub fn assert_owned_by(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
     if account.owner != owner {
         Err(ProgramError::IllegalOwner)
@@ -55,15 +113,230 @@ pub fn assert_owned_by(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
     }
 }
 
-/* // Unused helper
+/// Checks that `account` is the program `expected` before a CPI is sent to it, so a
+/// look-alike program never receives the pool's signatures.
+pub fn assert_program_id(account: &AccountInfo, expected: &Pubkey) -> ProgramResult {
+    if account.key != expected {
+        msg!("Expected program {}, got {}", expected, account.key);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(())
+}
+
+/// Checks that `account` is the sysvar `expected` before it is handed to a CPI,
+/// where a substitute would only surface as a confusing stake program error.
+pub fn assert_sysvar(account: &AccountInfo, expected: &Pubkey) -> ProgramResult {
+    if account.key != expected {
+        msg!("Expected sysvar {}, got {}", expected, account.key);
+        return Err(StakePoolError::InvalidSysvar.into());
+    }
+    Ok(())
+}
+
+/// Recomputes the stake and withdraw authority PDAs from the bump seeds stored in
+/// the pool and checks they match the stored authorities, so a corrupted bump is
+/// reported up front instead of as an opaque `invoke_signed` failure.
+pub fn assert_authority_bumps(
+    program_id: &Pubkey,
+    stake_pool_key: &Pubkey,
+    stake_pool: &StakePool,
+) -> ProgramResult {
+    let stake_authority = Pubkey::create_program_address(
+        &[STAKE_AUTHORITY_SEED, stake_pool_key.as_ref(), &[stake_pool.stake_authority_bump_seed]],
+        program_id,
+    )
+    .map_err(|_| StakePoolError::InvalidProgramAddress)?;
+    if stake_authority != stake_pool.stake_authority {
+        msg!("Stored stake authority bump {} does not derive {}", stake_pool.stake_authority_bump_seed, stake_pool.stake_authority);
+        return Err(StakePoolError::InvalidProgramAddress.into());
+    }
+
+    let withdraw_authority = Pubkey::create_program_address(
+        &[WITHDRAW_AUTHORITY_SEED, stake_pool_key.as_ref(), &[stake_pool.withdraw_authority_bump_seed]],
+        program_id,
+    )
+    .map_err(|_| StakePoolError::InvalidProgramAddress)?;
+    if withdraw_authority != stake_pool.withdraw_authority {
+        msg!("Stored withdraw authority bump {} does not derive {}", stake_pool.withdraw_authority_bump_seed, stake_pool.withdraw_authority);
+        return Err(StakePoolError::InvalidProgramAddress.into());
+    }
+
+    Ok(())
+}
+
+/// Checks that the stake and withdraw authorities are different addresses, so staking
+/// and withdrawal permissions are never held by the same PDA. Logs nothing; the caller
+/// reports the collision.
+pub fn assert_distinct_authorities(stake_authority: &Pubkey, withdraw_authority: &Pubkey) -> ProgramResult {
+    if stake_authority == withdraw_authority {
+        return Err(StakePoolError::InvalidProgramAddress.into());
+    }
+    Ok(())
+}
+
+/// Checks that the pool mint has no freeze authority, so obeSOL holders can never
+/// have their tokens frozen.
+pub fn assert_mint_not_freezable(mint_info: &AccountInfo) -> ProgramResult {
+    let mint = spl_token::state::Mint::unpack(&mint_info.data.borrow())?;
+    if let COption::Some(freeze_authority) = mint.freeze_authority {
+        msg!("Pool mint {} has freeze authority {}", mint_info.key, freeze_authority);
+        return Err(StakePoolError::MintHasFreezeAuthority.into());
+    }
+    Ok(())
+}
+
+/// Checks that a mint's authority is `expected`. The SPL mint authority is optional:
+/// a mint with no authority (fixed supply) fails the same as one with the wrong key.
+pub fn assert_mint_authority(mint: &spl_token::state::Mint, expected: &Pubkey) -> ProgramResult {
+    match mint.mint_authority {
+        COption::Some(authority) if authority == *expected => Ok(()),
+        COption::Some(authority) => {
+            msg!("Mint authority is {}, expected {}", authority, expected);
+            Err(StakePoolError::InvalidMintAuthority.into())
+        }
+        COption::None => {
+            msg!("Mint has no mint authority, expected {}", expected);
+            Err(StakePoolError::InvalidMintAuthority.into())
+        }
+    }
+}
+
+/// Reads the commission (percent) from a vote account. Both current vote state
+/// layouts (1.14.11 and Current) start with the version tag (u32), node pubkey and
+/// authorized withdrawer, followed by the commission byte, so it is read in place
+/// instead of deserializing the whole vote state.
+pub fn vote_account_commission(vote_info: &AccountInfo) -> Result<u8, ProgramError> {
+    const COMMISSION_OFFSET: usize = 4 + 32 + 32;
+
+    if *vote_info.owner != solana_program::vote::program::id() {
+        msg!("Account {} is not a vote account", vote_info.key);
+        return Err(StakePoolError::InvalidVoteAccount.into());
+    }
+    let data = vote_info.data.borrow();
+    if data.len() <= COMMISSION_OFFSET {
+        msg!("Vote account {} data too short", vote_info.key);
+        return Err(StakePoolError::InvalidVoteAccount.into());
+    }
+    let version = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    if version != 1 && version != 2 {
+        msg!("Unsupported vote state version {}", version);
+        return Err(StakePoolError::InvalidVoteAccount.into());
+    }
+    Ok(data[COMMISSION_OFFSET])
+}
+
+/// A vote account whose last vote is older than this many slots (about ten minutes)
+/// is not a live validator
+pub const MAX_VOTE_AGE_SLOTS: u64 = 1_500;
+
+/// Checks that a vote account belongs to a live validator: it has voted within
+/// MAX_VOTE_AGE_SLOTS of `current_slot`. The vote state can't be deserialized on chain,
+/// so the last vote is read in place: after the commission comes the vote queue's u64
+/// length and its entries, a 1-byte latency followed by the slot (u64) and confirmation
+/// count (u32) in the Current layout, and just the slot and count in 1.14.11.
+pub fn assert_vote_account_live(vote_info: &AccountInfo, current_slot: u64) -> ProgramResult {
+    const VOTES_OFFSET: usize = 4 + 32 + 32 + 1;
+
+    vote_account_commission(vote_info)?;
+    let data = vote_info.data.borrow();
+    let (entry_len, slot_offset) = match data[0] {
+        1 => (12, 0),
+        _ => (13, 1),
+    };
+    let last_voted_slot = data
+        .get(VOTES_OFFSET..VOTES_OFFSET + 8)
+        .map(|len| u64::from_le_bytes(len.try_into().unwrap()) as usize)
+        .filter(|&votes| votes > 0)
+        .and_then(|votes| {
+            let start = (votes - 1).checked_mul(entry_len)?.checked_add(VOTES_OFFSET + 8 + slot_offset)?;
+            data.get(start..start.checked_add(8)?)
+        })
+        .map(|slot| u64::from_le_bytes(slot.try_into().unwrap()));
+    match last_voted_slot {
+        Some(slot) if current_slot.saturating_sub(slot) <= MAX_VOTE_AGE_SLOTS => Ok(()),
+        Some(slot) => {
+            msg!("Vote account {} last voted in slot {}, more than {} slots ago",
+                 vote_info.key, slot, MAX_VOTE_AGE_SLOTS);
+            Err(StakePoolError::InvalidVoteAccount.into())
+        }
+        None => {
+            msg!("Vote account {} has no recent votes", vote_info.key);
+            Err(StakePoolError::InvalidVoteAccount.into())
+        }
+    }
+}
+
+/// Checks that `token_account_info` is the canonical associated token account of
+/// `owner` for `mint`.
+pub fn assert_associated_token_account(
+    owner: &Pubkey,
+    mint: &Pubkey,
+    token_account_info: &AccountInfo,
+) -> ProgramResult {
+    let expected = spl_associated_token_account::get_associated_token_address(owner, mint);
+    if expected != *token_account_info.key {
+        msg!("Token account {} is not the associated token account {}", token_account_info.key, expected);
+        return Err(StakePoolError::NonCanonicalTokenAccount.into());
+    }
+    Ok(())
+}
+
+/// Returns the smallest deposit the pool accepts: the configured `min_stake`, raised
+/// to the stake program's current minimum delegation if that is higher. Invokes the
+/// stake program, so the caller must have it in its account list.
+pub fn effective_min_stake(stake_pool: &StakePool) -> Result<u64, ProgramError> {
+    let minimum_delegation = solana_program::stake::tools::get_minimum_delegation()?;
+    Ok(stake_pool.min_stake.max(minimum_delegation))
+}
+
+/// Returns whether a stake account deactivated in `deactivation_epoch` can have
+/// finished its cooldown by `current_epoch`.
+///
+/// Deactivated stake stays effective for the rest of its deactivation epoch and
+/// cools down at the next epoch boundary, so the earliest withdrawable epoch is
+/// `deactivation_epoch + 1`. Stake still active (`u64::MAX`) is never cooled down.
+/// Stake large enough to be rate-limited by the cluster may need further epochs;
+/// the stake program enforces that on withdraw.
+pub fn cooldown_complete(deactivation_epoch: u64, current_epoch: u64) -> bool {
+    deactivation_epoch != u64::MAX && current_epoch > deactivation_epoch
+}
+
+/// Returns `items[offset..]` as a borsh-encoded `ReturnPage` via return data, taking
+/// at most `limit` items and never exceeding the return-data size cap. Clients keep
+/// requesting from `offset + items.len()` while `has_more` is set.
+pub fn set_paged_return_data<T: BorshSerialize + Clone>(items: &[T], offset: usize, limit: usize) -> ProgramResult {
+    // Vec length prefix + trailing has_more flag
+    const PAGE_OVERHEAD: usize = 4 + 1;
+
+    let mut page_items = Vec::new();
+    let mut page_size = PAGE_OVERHEAD;
+    for item in items.iter().skip(offset).take(limit) {
+        let item_size = item.try_to_vec()?.len();
+        if page_size + item_size > MAX_RETURN_DATA {
+            break;
+        }
+        page_size += item_size;
+        page_items.push(item.clone());
+    }
+    let has_more = offset.saturating_add(page_items.len()) < items.len();
+
+    let page = ReturnPage { items: page_items, has_more };
+    set_return_data(&page.try_to_vec()?);
+    Ok(())
+}
+
+/// Checks that the stake pool account holds the rent-exempt minimum for its data
+/// length. `TopUpPool` restores a pool that fell short.
 pub fn assert_rent_exempt(rent: &Rent, account_info: &AccountInfo) -> ProgramResult {
     if !rent.is_exempt(account_info.lamports(), account_info.data_len()) {
-        Err(ProgramError::AccountNotRentExempt)
+        msg!("Pool account {} is {} lamports short of rent exemption; call TopUpPool",
+             account_info.key,
+             rent.minimum_balance(account_info.data_len()).saturating_sub(account_info.lamports()));
+        Err(StakePoolError::PoolNotRentExempt.into())
     } else {
         Ok(())
     }
 }
-*/
 
 /* // Unused trait and helpers - IsInitialized trait is defined in program_pack
 pub fn assert_uninitialized<T: IsInitialized>(account: &T) -> ProgramResult {